@@ -0,0 +1,22 @@
+use derive_builder::Builder;
+
+use crate::{requests::works::Edition, OpenLibRequest};
+
+/// Looks up the edition with the given ISBN-10 or ISBN-13.
+#[derive(Debug, Builder)]
+#[builder(setter(into))]
+pub struct Isbn {
+    isbn: String,
+}
+
+impl OpenLibRequest for Isbn {
+    type Result = Edition;
+
+    fn path(&self) -> String {
+        format!("/isbn/{}.json", self.isbn)
+    }
+
+    fn query(&self) -> Vec<(&'static str, String)> {
+        vec![]
+    }
+}
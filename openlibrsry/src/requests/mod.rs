@@ -1,2 +1,3 @@
+pub mod isbn;
 pub mod search;
 pub mod works;
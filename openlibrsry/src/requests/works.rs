@@ -43,7 +43,12 @@ pub struct Edition {
     #[serde(default)]
     #[serde_as(deserialize_as = "Vec<serde_with::FromInto<KeyedKey>>")]
     pub authors: Vec<Key>,
+    #[serde(default)]
+    #[serde_as(deserialize_as = "Vec<serde_with::FromInto<KeyedKey>>")]
+    pub works: Vec<Key>,
+    #[serde(default)]
     pub publish_date: String,
+    pub number_of_pages: Option<u16>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,476 @@
+//! Authentication of API requests.
+//!
+//! Every request to `/api/*` except [`login`] has to be authenticated by the [`Authentication`]
+//! middleware, either with the session cookie set by [`login`] or with an API token in the
+//! `Authorization: Bearer <token>` header. Requests with a [`TokenScope::ReadOnly`] token may only
+//! use `GET` and `HEAD`.
+//!
+//! Sessions only live in memory, so everyone has to log in again after a restart.
+
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::EitherBody,
+    cookie::{self, Cookie, SameSite},
+    delete,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    get,
+    http::{header, Method},
+    post, put,
+    web::{self, Data, Json, Path},
+    HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use futures_util::future::LocalBoxFuture;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::{
+    config::config,
+    schema::{ApiToken, TokenScope},
+    storage::Change,
+    users::{Admin, CurrentUser},
+    AppState,
+};
+
+/// Name of the cookie containing the session id
+pub const SESSION_COOKIE: &str = "entrackment_session";
+/// Time after which sessions expire and the user has to log in again
+const SESSION_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Prefix of API tokens to make them recognizable, e.g. by secret scanners
+const TOKEN_PREFIX: &str = "et_";
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+static SESSIONS: Lazy<Mutex<HashMap<String, Session>>> = Lazy::new(Default::default);
+
+struct Session {
+    user: u32,
+    expires: Instant,
+}
+
+/// The identity a request was authenticated with, stored in the request extensions by the
+/// [`Authentication`] middleware.
+#[derive(Debug, Clone, Copy)]
+pub struct Authenticated {
+    pub user: u32,
+    /// Sessions always have the [`TokenScope::ReadWrite`] scope
+    pub scope: TokenScope,
+}
+
+/// Random URL-safe string with 256 bits of entropy.
+pub fn new_secret() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// Hex-encoded SHA-256 hash of an API token. Tokens are random enough that a fast hash is fine.
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Hashes `password` with Argon2 on a blocking thread.
+pub async fn hash_password(password: String) -> Result<String> {
+    web::block(move || {
+        let salt =
+            SaltString::encode_b64(&rand::random::<[u8; 16]>()).map_err(|err| anyhow!(err))?;
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|err| anyhow!(err))?;
+        Ok(hash.to_string())
+    })
+    .await?
+}
+
+/// Checks `password` against the Argon2 `hash` on a blocking thread.
+async fn verify_password(hash: String, password: String) -> bool {
+    web::block(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Checks the session cookie or API token of `req`.
+async fn authenticate(req: &ServiceRequest) -> Result<Authenticated, HttpResponse> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        let sessions = SESSIONS.lock().await;
+        return match sessions.get(cookie.value()) {
+            Some(session) if session.expires > Instant::now() => Ok(Authenticated {
+                user: session.user,
+                scope: TokenScope::ReadWrite,
+            }),
+            _ => Err(HttpResponse::Unauthorized().body("session has expired")),
+        };
+    }
+
+    let Some(authorization) = req.headers().get(header::AUTHORIZATION) else {
+        return Err(HttpResponse::Unauthorized().body("not logged in"));
+    };
+    let Some(token) = authorization
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return Err(HttpResponse::BadRequest().body("expected a bearer token"));
+    };
+    let hash = hash_token(token.trim());
+    let state = req
+        .app_data::<Data<AppState>>()
+        .expect("app state should be registered");
+    let data_lock = state.data.lock().await;
+    match data_lock
+        .api_tokens
+        .values()
+        .find(|token| token.hash == hash)
+    {
+        Some(token) => Ok(Authenticated {
+            user: token.user,
+            scope: token.scope,
+        }),
+        None => Err(HttpResponse::Unauthorized().body("invalid API token")),
+    }
+}
+
+/// Middleware rejecting unauthenticated requests to the API, see the [module docs](self).
+pub struct Authentication;
+
+impl<S, B> Transform<S, ServiceRequest> for Authentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = AuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            // the router matches the percent-decoded path, so that is the one to check
+            let path = req.match_info().as_str();
+            if path.starts_with("/api/") && path != "/api/login" {
+                let auth = match authenticate(&req).await {
+                    Ok(auth) => auth,
+                    Err(resp) => return Ok(req.into_response(resp).map_into_right_body()),
+                };
+                let read_only = matches!(*req.method(), Method::GET | Method::HEAD);
+                if auth.scope == TokenScope::ReadOnly && !read_only {
+                    let resp = HttpResponse::Forbidden().body("the API token is read-only");
+                    return Ok(req.into_response(resp).map_into_right_body());
+                }
+                req.extensions_mut().insert(auth);
+            }
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+/// Ends all sessions of `user`.
+pub async fn end_sessions(user: u32) {
+    SESSIONS
+        .lock()
+        .await
+        .retain(|_, session| session.user != user);
+}
+
+fn session_cookie(id: String) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, id)
+        .path("/")
+        .http_only(true)
+        .secure(config().secure_cookies)
+        .same_site(SameSite::Strict)
+        .max_age(cookie::time::Duration::seconds(
+            SESSION_LIFETIME.as_secs() as i64
+        ))
+        .finish()
+}
+
+#[derive(Deserialize)]
+struct Login {
+    name: String,
+    password: String,
+}
+
+#[post("/api/login")]
+async fn login(state: Data<AppState>, Json(login): Json<Login>) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    let user = data_lock
+        .users
+        .values()
+        .find(|user| user.name == login.name)
+        .cloned();
+    let hash = user
+        .as_ref()
+        .and_then(|user| data_lock.passwords.get(&user.id))
+        .cloned();
+    drop(data_lock);
+    let (Some(user), Some(hash)) = (user, hash) else {
+        return HttpResponse::Unauthorized().body("wrong name or password");
+    };
+    if !verify_password(hash, login.password).await {
+        return HttpResponse::Unauthorized().body("wrong name or password");
+    }
+
+    let id = new_secret();
+    let mut sessions = SESSIONS.lock().await;
+    sessions.retain(|_, session| session.expires > Instant::now());
+    sessions.insert(
+        id.clone(),
+        Session {
+            user: user.id,
+            expires: Instant::now() + SESSION_LIFETIME,
+        },
+    );
+    HttpResponse::Ok().cookie(session_cookie(id)).json(user)
+}
+
+#[post("/api/logout")]
+async fn logout(req: HttpRequest) -> impl Responder {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        SESSIONS.lock().await.remove(cookie.value());
+    }
+    let mut resp = HttpResponse::Ok().finish();
+    // fails only for invalid cookies, and this one is fine
+    let _ = resp.add_removal_cookie(&session_cookie(String::new()));
+    resp
+}
+
+/// Stores the hash of `password` for `user`.
+async fn set_password(state: &AppState, user: u32, password: String) -> HttpResponse {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return HttpResponse::BadRequest().body(format!(
+            "passwords have to be at least {MIN_PASSWORD_LENGTH} characters long"
+        ));
+    }
+    let hash = match hash_password(password).await {
+        Ok(hash) => hash,
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to hash password: {err}"))
+        }
+    };
+    let mut data_lock = state.data.lock().await;
+    if !data_lock.users.contains_key(&user) {
+        return HttpResponse::NotFound().body(format!("user with ID {user} does not exist"));
+    }
+    data_lock.passwords.insert(user, hash);
+    match state.save(&data_lock, Change::Password(user)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[derive(Deserialize)]
+struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+/// Changes the password of the current user, which requires the current password.
+#[put("/api/user/password")]
+async fn change_password(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(change): Json<PasswordChange>,
+) -> impl Responder {
+    let hash = state.data.lock().await.passwords.get(&user.id).cloned();
+    let correct = match hash {
+        Some(hash) => verify_password(hash, change.current_password).await,
+        None => false,
+    };
+    if !correct {
+        return HttpResponse::Forbidden().body("wrong password");
+    }
+    set_password(&state, user.id, change.new_password).await
+}
+
+#[derive(Deserialize)]
+struct PasswordReset {
+    password: String,
+}
+
+/// Sets the password of any user and logs them out everywhere.
+#[put("/api/users/{id}/password")]
+async fn reset_password(
+    state: Data<AppState>,
+    _: Admin,
+    id: Path<u32>,
+    Json(PasswordReset { password }): Json<PasswordReset>,
+) -> impl Responder {
+    let resp = set_password(&state, *id, password).await;
+    if resp.status().is_success() {
+        end_sessions(*id).await;
+    }
+    resp
+}
+
+/// An [`ApiToken`] without its hash.
+#[derive(Serialize)]
+struct TokenInfo<'t> {
+    id: u32,
+    name: &'t str,
+    scope: TokenScope,
+    created: DateTime<Utc>,
+}
+
+impl<'t> From<&'t ApiToken> for TokenInfo<'t> {
+    fn from(token: &'t ApiToken) -> Self {
+        Self {
+            id: token.id,
+            name: &token.name,
+            scope: token.scope,
+            created: token.created,
+        }
+    }
+}
+
+/// Lists the API tokens of the current user.
+#[get("/api/tokens")]
+async fn get_tokens(state: Data<AppState>, user: CurrentUser) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    let mut tokens: Vec<_> = data_lock
+        .api_tokens
+        .values()
+        .filter(|token| token.user == user.id)
+        .map(TokenInfo::from)
+        .collect();
+    tokens.sort_by_key(|token| (token.created, token.id));
+    HttpResponse::Ok().json(tokens)
+}
+
+#[derive(Deserialize)]
+struct NewToken {
+    name: String,
+    scope: TokenScope,
+}
+
+#[derive(Serialize)]
+struct CreatedToken<'t> {
+    #[serde(flatten)]
+    info: TokenInfo<'t>,
+    /// The token itself, which cannot be retrieved again later
+    token: String,
+}
+
+/// Creates an API token for the current user.
+#[post("/api/tokens")]
+async fn post_token(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(new): Json<NewToken>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = loop {
+        let new_id = rand::random::<u32>();
+        if !data_lock.api_tokens.contains_key(&new_id) {
+            break new_id;
+        }
+    };
+    let secret = format!("{TOKEN_PREFIX}{}", new_secret());
+    let token = ApiToken {
+        id,
+        user: user.id,
+        name: new.name,
+        scope: new.scope,
+        hash: hash_token(&secret),
+        created: Utc::now(),
+    };
+    let resp = HttpResponse::Ok().json(CreatedToken {
+        info: TokenInfo::from(&token),
+        token: secret,
+    });
+    data_lock.api_tokens.insert(id, token);
+    match state.save(&data_lock, Change::ApiToken(id)).await {
+        Ok(()) => resp,
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+/// Revokes an API token of the current user.
+#[delete("/api/tokens/{id}")]
+async fn delete_token(state: Data<AppState>, user: CurrentUser, id: Path<u32>) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    if data_lock
+        .api_tokens
+        .get(&id)
+        .is_none_or(|token| token.user != user.id)
+    {
+        return HttpResponse::NotFound().body(format!("API token with ID {id} does not exist"));
+    }
+    data_lock.api_tokens.remove(&id);
+    match state.save(&data_lock, Change::ApiToken(*id)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[actix_web::test]
+    async fn passwords() {
+        let hash = hash_password("correct horse".into()).await.unwrap();
+        assert!(verify_password(hash.clone(), "correct horse".into()).await);
+        assert!(!verify_password(hash, "battery staple".into()).await);
+        assert!(!verify_password("not a hash".into(), "correct horse".into()).await);
+    }
+
+    #[actix_web::test]
+    async fn encoded_paths() {
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .wrap(Authentication)
+                .route("/api/movie", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for uri in ["/api/movie", "/%61pi/movie", "/api/%6Dovie"] {
+            let req = actix_web::test::TestRequest::get().uri(uri).to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 401, "{uri}");
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    cmp,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    get, post,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use anyhow::{bail, Result};
+use chrono::{DateTime, NaiveDateTime, SubsecRound, Utc};
+use serde::Serialize;
+use tokio::fs;
+
+use crate::{schema::AppData, storage::Storage, users::Admin, AppState};
+
+/// Minimum time between two automatic backups
+const BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H-%M-%S%.3f";
+
+pub struct Backups {
+    dir: PathBuf,
+    /// Number of backups to keep, older ones are deleted
+    keep: usize,
+    last_backup: Mutex<Option<Instant>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+    pub name: String,
+    pub created: DateTime<Utc>,
+    pub size: u64,
+}
+
+impl Backups {
+    /// Stores backups in `dir`, keeping the latest `keep` ones. A `keep` of 0 disables automatic
+    /// backups.
+    pub fn new(dir: PathBuf, keep: usize) -> Self {
+        Self {
+            dir,
+            keep,
+            last_backup: Mutex::new(None),
+        }
+    }
+
+    /// Creates a new backup of `data` and deletes the oldest backups exceeding the limit.
+    pub async fn create(&self, storage: &dyn Storage, data: &AppData) -> Result<BackupInfo> {
+        let backup = self.write(storage, data).await?;
+        for old in self.list().await?.iter().skip(self.keep.max(1)) {
+            fs::remove_file(self.dir.join(&old.name)).await?;
+        }
+        Ok(backup)
+    }
+
+    /// Creates a new backup of `data` without deleting any old ones.
+    async fn write(&self, storage: &dyn Storage, data: &AppData) -> Result<BackupInfo> {
+        let created = Utc::now().trunc_subsecs(3);
+        let name = format!(
+            "data-{}.{}",
+            created.format(TIMESTAMP_FORMAT),
+            storage.backup_extension()
+        );
+        let path = self.dir.join(&name);
+        fs::create_dir_all(&self.dir).await?;
+        if fs::try_exists(&path).await? {
+            bail!("backup '{name}' already exists");
+        }
+        storage.backup(data, &path).await?;
+        *self
+            .last_backup
+            .lock()
+            .expect("mutex should not be poisoned") = Some(Instant::now());
+
+        let size = fs::metadata(&path).await?.len();
+        Ok(BackupInfo {
+            name,
+            created,
+            size,
+        })
+    }
+
+    /// Creates a backup if automatic backups are enabled and the last one is older than
+    /// [`BACKUP_INTERVAL`].
+    pub async fn create_if_due(&self, storage: &dyn Storage, data: &AppData) -> Result<()> {
+        let due = self
+            .last_backup
+            .lock()
+            .expect("mutex should not be poisoned")
+            .is_none_or(|last| last.elapsed() >= BACKUP_INTERVAL);
+        if self.keep > 0 && due {
+            self.create(storage, data).await?;
+        }
+        Ok(())
+    }
+
+    /// Lists all backups, newest first.
+    pub async fn list(&self) -> Result<Vec<BackupInfo>> {
+        let mut backups = vec![];
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(backups),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let Some(created) = parse_timestamp(&name) else {
+                continue;
+            };
+            backups.push(BackupInfo {
+                name,
+                created,
+                size: entry.metadata().await?.len(),
+            });
+        }
+        backups.sort_by_key(|backup| cmp::Reverse(backup.created));
+        Ok(backups)
+    }
+
+    /// Returns the path of the backup called `name`, making sure it does not point outside the
+    /// backups directory.
+    pub fn path_of(&self, name: &str) -> Result<PathBuf> {
+        if parse_timestamp(name).is_none() || Path::new(name).file_name() != Some(name.as_ref()) {
+            bail!("invalid backup name '{name}'");
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+fn parse_timestamp(name: &str) -> Option<DateTime<Utc>> {
+    let (stem, _extension) = name.strip_prefix("data-")?.rsplit_once('.')?;
+    NaiveDateTime::parse_from_str(stem, TIMESTAMP_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+#[get("/api/backups")]
+async fn list_backups(state: Data<AppState>, _: Admin) -> impl Responder {
+    match state.backups.list().await {
+        Ok(backups) => HttpResponse::Ok().json(backups),
+        Err(err) => {
+            HttpResponse::InternalServerError().body(format!("Failed to list backups: {err}"))
+        }
+    }
+}
+
+#[post("/api/backups")]
+async fn create_backup(state: Data<AppState>, _: Admin) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    match state.backups.create(&*state.storage, &data_lock).await {
+        Ok(backup) => HttpResponse::Ok().json(backup),
+        Err(err) => {
+            HttpResponse::InternalServerError().body(format!("Failed to create backup: {err}"))
+        }
+    }
+}
+
+#[post("/api/backups/{name}/restore")]
+async fn restore_backup(
+    state: Data<AppState>,
+    _: Admin,
+    name: web::Path<String>,
+) -> impl Responder {
+    let path = match state.backups.path_of(&name) {
+        Ok(path) => path,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+    if !fs::try_exists(&path).await.unwrap_or(false) {
+        return HttpResponse::NotFound().body(format!("backup '{name}' does not exist"));
+    }
+    if !name.ends_with(&format!(".{}", state.storage.backup_extension())) {
+        return HttpResponse::BadRequest().body(format!(
+            "backup '{name}' was not created by the current storage backend"
+        ));
+    }
+
+    let mut data_lock = state.data.lock().await;
+    // keep the current state around in case the restored backup turns out to be the wrong one,
+    // without rotating as that might delete the backup we are about to restore
+    if let Err(err) = state.backups.write(&*state.storage, &data_lock).await {
+        return HttpResponse::InternalServerError().body(format!(
+            "Failed to back up current data before restoring: {err}"
+        ));
+    }
+    match state.storage.restore(&path).await {
+        Ok(data) => {
+            *data_lock = data;
+            state
+                .search
+                .lock()
+                .expect("mutex should not be poisoned")
+                .rebuild(&data_lock);
+            HttpResponse::Ok().finish()
+        }
+        Err(err) => {
+            HttpResponse::InternalServerError().body(format!("Failed to restore backup: {err}"))
+        }
+    }
+}
@@ -0,0 +1,648 @@
+//! Import of Goodreads and StoryGraph CSV exports.
+//!
+//! Importing happens in two steps. First the CSV file is uploaded and every row is matched to an
+//! Open Library work, either through its ISBN or by searching for its title and authors. The
+//! result is kept as a preview, in which ambiguous matches can be resolved by choosing one of the
+//! candidates. Only after confirming the preview any books are created.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    post,
+    web::{self, Data, Json},
+    HttpResponse, Responder,
+};
+use anyhow::{bail, Context, Result};
+use chrono::{Local, NaiveDate};
+use futures_util::{stream, StreamExt};
+use once_cell::sync::Lazy;
+use openlibrsry::{
+    requests::{isbn::IsbnBuilder, search::SearchBuilder},
+    OlId,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    events::{self, EntityChange},
+    schema::{rating_from_stars, Book, Rating, Reading},
+    storage::Change,
+    users::CurrentUser,
+    AppState, OPENLIB,
+};
+
+/// Maximum accepted size of an uploaded CSV file
+const MAX_CSV_SIZE: usize = 16 << 20;
+/// Time after which unconfirmed previews are discarded
+const PREVIEW_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// Maximum number of search results offered for ambiguous matches
+const MAX_CANDIDATES: usize = 5;
+/// Number of rows matched concurrently, since looking up large exports one row at a time takes
+/// longer than clients wait for a response
+const CONCURRENT_LOOKUPS: usize = 10;
+
+static PREVIEWS: Lazy<Mutex<HashMap<u32, Preview>>> = Lazy::new(Default::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ReadStatus {
+    Read,
+    CurrentlyReading,
+    ToRead,
+}
+
+/// A row of either export format.
+#[derive(Debug, Clone, Serialize)]
+struct BookRow {
+    /// Line number in the CSV file
+    line: usize,
+    title: String,
+    authors: Vec<String>,
+    isbn: Option<String>,
+    status: ReadStatus,
+    date_read: Option<NaiveDate>,
+    date_added: Option<NaiveDate>,
+    /// Rating in range `1..=10`
+    rating: Option<u8>,
+    pages: Option<u16>,
+    year: Option<u16>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Candidate {
+    olid: OlId,
+    title: String,
+    authors: Vec<String>,
+    first_publish_year: Option<u16>,
+    pages: Option<u16>,
+    description: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum Match {
+    Matched { candidate: Candidate },
+    Ambiguous { candidates: Vec<Candidate> },
+    Unmatched { reason: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PreviewEntry {
+    row: BookRow,
+    #[serde(flatten)]
+    matching: Match,
+}
+
+struct Preview {
+    /// User who uploaded the file and receives the readings
+    user: u32,
+    created: Instant,
+    entries: Vec<PreviewEntry>,
+}
+
+/// Column accessor by header name.
+struct Columns(HashMap<String, usize>);
+
+impl Columns {
+    fn get<'r>(&self, record: &'r csv::StringRecord, name: &str) -> Option<&'r str> {
+        let value = record.get(*self.0.get(name)?)?.trim();
+        (!value.is_empty()).then_some(value)
+    }
+}
+
+fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y/%m/%d")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%Y-%m-%d"))
+        .ok()
+}
+
+fn parse_status(status: &str) -> ReadStatus {
+    match status {
+        "read" => ReadStatus::Read,
+        "currently-reading" => ReadStatus::CurrentlyReading,
+        _ => ReadStatus::ToRead,
+    }
+}
+
+fn split_authors(authors: Option<&str>) -> impl Iterator<Item = String> + '_ {
+    authors
+        .into_iter()
+        .flat_map(|authors| authors.split(','))
+        .map(str::trim)
+        .filter(|author| !author.is_empty())
+        .map(str::to_owned)
+}
+
+fn parse_csv(csv: &[u8]) -> Result<Vec<BookRow>> {
+    let mut reader = csv::Reader::from_reader(csv);
+    let columns = Columns(
+        reader
+            .headers()?
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| (name.trim().to_owned(), idx))
+            .collect(),
+    );
+    let is_goodreads = columns.0.contains_key("Exclusive Shelf");
+    if !is_goodreads && !columns.0.contains_key("Read Status") {
+        bail!("unknown CSV format, expected a Goodreads or StoryGraph export");
+    }
+
+    let mut rows = vec![];
+    for (idx, record) in reader.records().enumerate() {
+        // line 1 is the header
+        let line = idx + 2;
+        let record = record.with_context(|| format!("invalid CSV row in line {line}"))?;
+        let Some(title) = columns.get(&record, "Title") else {
+            continue;
+        };
+        let row = if is_goodreads {
+            // Goodreads writes ISBNs as `="0123456789"` so spreadsheets treat them as text
+            let isbn = ["ISBN13", "ISBN"].iter().find_map(|column| {
+                let isbn = columns
+                    .get(&record, column)?
+                    .trim_start_matches('=')
+                    .trim_matches('"');
+                (!isbn.is_empty()).then(|| isbn.to_owned())
+            });
+            BookRow {
+                line,
+                title: title.to_owned(),
+                authors: split_authors(columns.get(&record, "Author"))
+                    .chain(split_authors(columns.get(&record, "Additional Authors")))
+                    .collect(),
+                isbn,
+                status: parse_status(columns.get(&record, "Exclusive Shelf").unwrap_or_default()),
+                date_read: columns.get(&record, "Date Read").and_then(parse_date),
+                date_added: columns.get(&record, "Date Added").and_then(parse_date),
+                rating: columns
+                    .get(&record, "My Rating")
+                    .and_then(|rating| rating.parse().ok())
+                    .and_then(rating_from_stars),
+                pages: columns
+                    .get(&record, "Number of Pages")
+                    .and_then(|pages| pages.parse().ok()),
+                year: columns
+                    .get(&record, "Original Publication Year")
+                    .or_else(|| columns.get(&record, "Year Published"))
+                    .and_then(|year| year.parse().ok()),
+            }
+        } else {
+            BookRow {
+                line,
+                title: title.to_owned(),
+                authors: split_authors(columns.get(&record, "Authors")).collect(),
+                isbn: columns
+                    .get(&record, "ISBN/UID")
+                    .filter(|isbn| isbn.chars().all(|c| c.is_ascii_digit() || c == 'X'))
+                    .map(str::to_owned),
+                status: parse_status(columns.get(&record, "Read Status").unwrap_or_default()),
+                date_read: columns.get(&record, "Last Date Read").and_then(parse_date),
+                date_added: columns.get(&record, "Date Added").and_then(parse_date),
+                rating: columns
+                    .get(&record, "Star Rating")
+                    .and_then(|rating| rating.parse().ok())
+                    .and_then(rating_from_stars),
+                pages: None,
+                year: None,
+            }
+        };
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Lowercases a title and removes subtitles and series information like `(Series, #1)`.
+fn normalize_title(title: &str) -> String {
+    let title = match title.rfind(" (") {
+        Some(idx) if title.ends_with(')') => &title[..idx],
+        _ => title,
+    };
+    let title = title.split(':').next().unwrap_or(title);
+    title.trim().to_lowercase()
+}
+
+async fn resolve(row: &BookRow) -> Match {
+    if let Some(isbn) = &row.isbn {
+        let request = IsbnBuilder::default()
+            .isbn(isbn.clone())
+            .build()
+            .expect("required field `isbn` is present");
+        // unknown ISBNs are not an error, the search might still find the book
+        if let Ok(edition) = OPENLIB.execute(request).await {
+            if let Some(work) = edition.works.first() {
+                return Match::Matched {
+                    candidate: Candidate {
+                        olid: work.id,
+                        title: row.title.clone(),
+                        authors: row.authors.clone(),
+                        first_publish_year: row.year,
+                        pages: row.pages.or(edition.number_of_pages),
+                        description: edition.description,
+                    },
+                };
+            }
+        }
+    }
+
+    let title = normalize_title(&row.title);
+    let query = match row.authors.first() {
+        Some(author) => format!("{title} {author}"),
+        None => title.clone(),
+    };
+    let results = match OPENLIB
+        .execute(
+            SearchBuilder::default()
+                .query(query)
+                .limit(MAX_CANDIDATES as u32)
+                .build()
+                .expect("building Search should never fail"),
+        )
+        .await
+    {
+        Ok(results) => results,
+        Err(err) => {
+            return Match::Unmatched {
+                reason: format!("Open Library search failed: {err}"),
+            }
+        }
+    };
+    let candidates: Vec<_> = results
+        .docs
+        .into_iter()
+        .map(|doc| Candidate {
+            olid: doc.key.id,
+            title: doc.title,
+            authors: doc.author_name,
+            first_publish_year: doc.first_publish_year,
+            pages: row.pages,
+            description: None,
+        })
+        .collect();
+
+    let mut exact = candidates.iter().filter(|candidate| {
+        normalize_title(&candidate.title) == title
+            && (row.authors.is_empty()
+                || candidate
+                    .authors
+                    .iter()
+                    .any(|author| row.authors.contains(author)))
+    });
+    match (exact.next(), exact.next()) {
+        (Some(candidate), None) => Match::Matched {
+            candidate: candidate.clone(),
+        },
+        _ if candidates.is_empty() => Match::Unmatched {
+            reason: "no matching work found on Open Library".into(),
+        },
+        _ => Match::Ambiguous { candidates },
+    }
+}
+
+#[derive(Serialize)]
+struct PreviewResponse<'p> {
+    id: u32,
+    entries: &'p [PreviewEntry],
+}
+
+#[post("/api/book/import")]
+async fn preview(user: CurrentUser, payload: web::Payload) -> impl Responder {
+    let body = match payload.to_bytes_limited(MAX_CSV_SIZE).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(err)) => {
+            return HttpResponse::BadRequest().body(format!("Failed to read body: {err}"))
+        }
+        Err(_) => return HttpResponse::PayloadTooLarge().finish(),
+    };
+    let rows = match parse_csv(&body) {
+        Ok(rows) => rows,
+        Err(err) => return HttpResponse::BadRequest().body(format!("{err:#}")),
+    };
+
+    let entries: Vec<_> = stream::iter(rows)
+        .map(|row| async move {
+            let matching = resolve(&row).await;
+            PreviewEntry { row, matching }
+        })
+        .buffered(CONCURRENT_LOOKUPS)
+        .collect()
+        .await;
+
+    let mut previews = PREVIEWS.lock().await;
+    previews.retain(|_, preview| preview.created.elapsed() < PREVIEW_LIFETIME);
+    let id = loop {
+        let new_id = rand::random::<u32>();
+        if !previews.contains_key(&new_id) {
+            break new_id;
+        }
+    };
+    let resp = HttpResponse::Ok().json(PreviewResponse {
+        id,
+        entries: &entries,
+    });
+    previews.insert(
+        id,
+        Preview {
+            user: user.id,
+            created: Instant::now(),
+            entries,
+        },
+    );
+    resp
+}
+
+#[derive(Deserialize)]
+struct Confirmation {
+    /// Chosen works for ambiguous or unmatched rows by line number. `null` imports the row
+    /// without linking it to Open Library. Ambiguous and unmatched rows without a selection are
+    /// skipped.
+    #[serde(default)]
+    selections: HashMap<usize, Option<OlId>>,
+    /// Line numbers of matched rows that should not be imported
+    #[serde(default)]
+    skip: BTreeSet<usize>,
+}
+
+#[derive(Default, Serialize)]
+struct ImportReport {
+    /// IDs of newly created books
+    created: Vec<u32>,
+    /// IDs of existing books with the same work which got a new reading
+    updated: Vec<u32>,
+    /// Line numbers of skipped rows
+    skipped: Vec<usize>,
+}
+
+fn to_reading(row: &BookRow, pages: Option<u16>, user: u32) -> Option<Reading> {
+    let date = row
+        .date_read
+        .or(row.date_added)
+        .unwrap_or_else(|| Local::now().date_naive());
+    let end_page = pages.unwrap_or(0);
+    match row.status {
+        ReadStatus::ToRead => None,
+        ReadStatus::CurrentlyReading => Some(Reading {
+            user,
+            pages_read: BTreeMap::new(),
+            rating: None,
+            isbn: row.isbn.clone(),
+            start_page: 1,
+            end_page,
+        }),
+        // readings of unknown length are stored as a single page so they still count as finished
+        // on the date they were read
+        ReadStatus::Read => Some(Reading {
+            user,
+            pages_read: BTreeMap::from([(date, end_page.max(1))]),
+            rating: row.rating.map(|rating| Rating {
+                user,
+                date,
+                rating,
+                speed: 1.,
+                platform: None,
+                tags: BTreeSet::new(),
+            }),
+            isbn: row.isbn.clone(),
+            start_page: 1,
+            end_page: end_page.max(1),
+        }),
+    }
+}
+
+#[post("/api/book/import/{id}")]
+async fn confirm(
+    state: Data<AppState>,
+    user: CurrentUser,
+    id: web::Path<u32>,
+    Json(Confirmation { selections, skip }): Json<Confirmation>,
+) -> impl Responder {
+    let mut previews = PREVIEWS.lock().await;
+    if previews
+        .get(&id)
+        .is_none_or(|pending| pending.user != user.id)
+    {
+        return HttpResponse::NotFound()
+            .body(format!("import preview with ID {id} does not exist"));
+    }
+    let pending = previews.remove(&id).expect("preview was just looked up");
+    drop(previews);
+
+    let mut report = ImportReport::default();
+    let mut changes = vec![];
+    let mut data_lock = state.data.lock().await;
+    for PreviewEntry { row, matching } in pending.entries {
+        let candidate = match matching {
+            Match::Matched { candidate } if !skip.contains(&row.line) => Some(candidate),
+            Match::Ambiguous { candidates } => match selections.get(&row.line) {
+                Some(Some(olid)) => Some(
+                    candidates
+                        .into_iter()
+                        .find(|candidate| candidate.olid == *olid)
+                        .unwrap_or_else(|| Candidate {
+                            olid: *olid,
+                            title: row.title.clone(),
+                            authors: row.authors.clone(),
+                            first_publish_year: row.year,
+                            pages: row.pages,
+                            description: None,
+                        }),
+                ),
+                Some(None) => None,
+                None => {
+                    report.skipped.push(row.line);
+                    continue;
+                }
+            },
+            Match::Unmatched { .. } => match selections.get(&row.line) {
+                Some(Some(olid)) => Some(Candidate {
+                    olid: *olid,
+                    title: row.title.clone(),
+                    authors: row.authors.clone(),
+                    first_publish_year: row.year,
+                    pages: row.pages,
+                    description: None,
+                }),
+                Some(None) => None,
+                None => {
+                    report.skipped.push(row.line);
+                    continue;
+                }
+            },
+            Match::Matched { .. } => {
+                report.skipped.push(row.line);
+                continue;
+            }
+        };
+        let pages = row
+            .pages
+            .or(candidate.as_ref().and_then(|candidate| candidate.pages));
+        let reading = to_reading(&row, pages, user.id);
+
+        let existing = candidate.as_ref().and_then(|candidate| {
+            data_lock
+                .books
+                .values()
+                .find(|book| book.olid == Some(candidate.olid))
+                .map(|book| book.id)
+        });
+        let book_id = match existing {
+            Some(book_id) => {
+                let book = data_lock
+                    .books
+                    .get_mut(&book_id)
+                    .expect("ID was just looked up");
+                let before = book.clone();
+                book.readings.extend(reading);
+                book.revision += 1;
+                report.updated.push(book_id);
+                changes.push(EntityChange::Book {
+                    book_id,
+                    before: Some(before),
+                    after: Some(book.clone()),
+                });
+                book_id
+            }
+            None => {
+                let book_id = loop {
+                    let new_id = rand::random::<u32>();
+                    if !data_lock.books.contains_key(&new_id) {
+                        break new_id;
+                    }
+                };
+                let year = row
+                    .year
+                    .or(candidate.as_ref().and_then(|c| c.first_publish_year));
+                let book = Book {
+                    id: book_id,
+                    olid: candidate.as_ref().map(|candidate| candidate.olid),
+                    title: row.title,
+                    description: candidate
+                        .as_ref()
+                        .and_then(|candidate| candidate.description.clone())
+                        .unwrap_or_default(),
+                    authors: match &candidate {
+                        Some(candidate) if row.authors.is_empty() => candidate.authors.clone(),
+                        _ => row.authors,
+                    },
+                    readings: reading.into_iter().collect(),
+                    tags: BTreeSet::new(),
+                    release_date: year.and_then(|year| NaiveDate::from_ymd_opt(year as i32, 1, 1)),
+                    score: None,
+                    revision: 0,
+                };
+                changes.push(EntityChange::Book {
+                    book_id,
+                    before: None,
+                    after: Some(book.clone()),
+                });
+                data_lock.books.insert(book_id, book);
+                report.created.push(book_id);
+                book_id
+            }
+        };
+        if let Err(err) = state.save(&data_lock, Change::Book(book_id)).await {
+            // the books saved before the failure were still changed
+            changes.pop();
+            drop(data_lock);
+            for change in changes {
+                events::publish(&state, user.id, change).await;
+            }
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to save new data to disk: {err}"));
+        }
+    }
+    drop(data_lock);
+    for change in changes {
+        events::publish(&state, user.id, change).await;
+    }
+    HttpResponse::Ok().json(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn goodreads() {
+        let csv = "Book Id,Title,Author,Author l-f,Additional Authors,ISBN,ISBN13,My Rating,\
+                   Average Rating,Publisher,Binding,Number of Pages,Year Published,\
+                   Original Publication Year,Date Read,Date Added,Bookshelves,\
+                   Bookshelves with positions,Exclusive Shelf,My Review,Spoiler,Private Notes,\
+                   Read Count,Owned Copies\n\
+                   2767052,\"The Hunger Games (The Hunger Games, #1)\",Suzanne Collins,\
+                   \"Collins, Suzanne\",,\"=\"\"0439023483\"\"\",\"=\"\"9780439023481\"\"\",4,4.33,\
+                   Scholastic Press,Hardcover,374,2008,2008,2024/03/05,2024/02/01,,,read,,,,1,0\n";
+        let rows = parse_csv(csv.as_bytes()).unwrap();
+        let row = &rows[0];
+        assert_eq!(row.line, 2);
+        assert_eq!(normalize_title(&row.title), "the hunger games");
+        assert_eq!(row.authors, ["Suzanne Collins"]);
+        assert_eq!(row.isbn.as_deref(), Some("9780439023481"));
+        assert_eq!(row.status, ReadStatus::Read);
+        assert_eq!(row.date_read, NaiveDate::from_ymd_opt(2024, 3, 5));
+        assert_eq!(row.rating, Some(8));
+        assert_eq!(row.pages, Some(374));
+        assert_eq!(row.year, Some(2008));
+    }
+
+    #[test]
+    fn storygraph() {
+        let csv = "Title,Authors,Contributors,ISBN/UID,Format,Read Status,Date Added,\
+                   Last Date Read,Dates Read,Read Count,Star Rating,Review,Tags,Owned?\n\
+                   Piranesi,Susanna Clarke,,9781635575637,paperback,currently-reading,\
+                   2024/01/10,,,0,,,,No\n\
+                   Project Hail Mary,Andy Weir,,,hardcover,read,2023/05/01,2023/06/02,\
+                   2023/05/20-2023/06/02,1,4.25,,,No\n";
+        let rows = parse_csv(csv.as_bytes()).unwrap();
+        assert_eq!(rows[0].status, ReadStatus::CurrentlyReading);
+        assert_eq!(rows[0].isbn.as_deref(), Some("9781635575637"));
+        assert_eq!(rows[1].isbn, None);
+        assert_eq!(rows[1].rating, Some(9));
+        assert_eq!(rows[1].date_read, NaiveDate::from_ymd_opt(2023, 6, 2));
+    }
+
+    #[test]
+    fn readings() {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap();
+        let mut row = BookRow {
+            line: 2,
+            title: "Erebos".into(),
+            authors: vec![],
+            isbn: None,
+            status: ReadStatus::Read,
+            date_read: Some(date),
+            date_added: NaiveDate::from_ymd_opt(2024, 2, 1),
+            rating: Some(8),
+            pages: None,
+            year: None,
+        };
+
+        let reading = to_reading(&row, Some(480), 1).unwrap();
+        assert_eq!(reading.pages_read, BTreeMap::from([(date, 480)]));
+        assert_eq!(reading.rating.as_ref().map(|rating| rating.rating), Some(8));
+        assert_eq!(reading.finished_on(), Some(date));
+
+        // finished readings without rating and page count still record the date
+        row.rating = None;
+        let reading = to_reading(&row, None, 1).unwrap();
+        assert_eq!(reading.rating, None);
+        assert_eq!(reading.finished_on(), Some(date));
+
+        row.status = ReadStatus::CurrentlyReading;
+        let reading = to_reading(&row, Some(480), 1).unwrap();
+        assert!(reading.pages_read.is_empty());
+        assert_eq!(reading.end_page, 480);
+        assert!(!reading.is_finished());
+
+        row.status = ReadStatus::ToRead;
+        assert_eq!(to_reading(&row, Some(480), 1), None);
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(parse_csv(b"Foo,Bar\n1,2\n").is_err());
+    }
+}
@@ -0,0 +1,590 @@
+//! Changes made through the API, which are sent to the [webhooks](crate::webhooks) and streamed to
+//! connected clients.
+//!
+//! `/api/events` is a stream of Server-Sent Events with one event per change of a movie, tag,
+//! rating, book, reading, series or watchlist entry. Events are named like `movie.updated`, their
+//! data contains the `kind` of the entity, its `id` and its new `value` as seen by the connected
+//! user, which is `null` if it was deleted. The most recent events are kept in memory, so that
+//! clients can resume after the id in the `Last-Event-ID` header when they reconnect. If events
+//! were missed, or after imports of whole snapshots and metadata refreshes, a `reset` event is
+//! sent instead, after which the client has to reload all data.
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    convert::Infallible,
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::{
+    get,
+    rt::time::timeout,
+    web::{Bytes, Data},
+    HttpRequest, HttpResponse, Responder,
+};
+use chrono::Utc;
+use futures_util::stream;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, Mutex};
+
+use crate::{
+    schema::{AppData, Book, Episode, Movie, Rating, Reading, Series, Tag, WatchlistEntry},
+    users::CurrentUser,
+    webhooks, AppState,
+};
+
+/// Number of events which are kept to resume streams
+const KEEP_EVENTS: usize = 1000;
+/// Time without events after which a comment is sent, so that idle connections are not closed
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// Tells the client that it missed events and has to reload all data
+const RESET: &str = "event: reset\ndata: \n\n";
+
+struct Published {
+    id: u64,
+    /// Id of the user who made the change
+    user: u32,
+    /// `None` for a reset
+    change: Option<EntityChange>,
+}
+
+struct EventLog {
+    next_id: u64,
+    /// Events from oldest to newest
+    events: VecDeque<Arc<Published>>,
+    sender: broadcast::Sender<Arc<Published>>,
+}
+
+impl EventLog {
+    fn new(next_id: u64) -> Self {
+        Self {
+            next_id,
+            events: VecDeque::new(),
+            sender: broadcast::channel(KEEP_EVENTS).0,
+        }
+    }
+
+    /// Appends `change` made by `user` or a reset, dropping the oldest event if the log is full.
+    fn push(&mut self, user: u32, change: Option<EntityChange>) -> Arc<Published> {
+        let event = Arc::new(Published {
+            id: self.next_id,
+            user,
+            change,
+        });
+        self.next_id += 1;
+        if self.events.len() == KEEP_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event.clone());
+        event
+    }
+
+    /// The events after `last_id`, or `None` if some of them are not kept anymore or `last_id` is
+    /// not known at all.
+    fn resume(&self, last_id: u64) -> Option<VecDeque<Arc<Published>>> {
+        let first_id = self.events.front().map_or(self.next_id, |event| event.id);
+        if last_id.saturating_add(1) < first_id || last_id >= self.next_id {
+            return None;
+        }
+        let events = self.events.iter().filter(|event| event.id > last_id);
+        Some(events.cloned().collect())
+    }
+}
+
+// the ids keep increasing across restarts, so ids from before a restart are too old to resume from
+// instead of referring to newer events
+static EVENTS: Lazy<Mutex<EventLog>> = Lazy::new(|| {
+    Mutex::new(EventLog::new(
+        u64::try_from(Utc::now().timestamp_micros()).unwrap_or_default(),
+    ))
+});
+
+/// A changed entity with its state before and after the change. `before` is `None` for created and
+/// `after` for deleted entities.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "entity", rename_all = "snake_case")]
+pub enum EntityChange {
+    Movie {
+        tmdb_id: u64,
+        before: Option<Movie>,
+        after: Option<Movie>,
+    },
+    Tag {
+        tag_id: u32,
+        before: Option<Tag>,
+        after: Option<Tag>,
+    },
+    MovieRating {
+        tmdb_id: u64,
+        before: Option<Rating>,
+        after: Option<Rating>,
+    },
+    Book {
+        book_id: u32,
+        before: Option<Book>,
+        after: Option<Book>,
+    },
+    /// A reading including its rating
+    Reading {
+        book_id: u32,
+        /// Index of the reading among the ones of its user
+        index: usize,
+        before: Option<Reading>,
+        after: Option<Reading>,
+    },
+    Series {
+        tmdb_id: u64,
+        before: Option<Series>,
+        after: Option<Series>,
+    },
+    /// An episode whose watch dates changed
+    Episode {
+        tmdb_id: u64,
+        season: u32,
+        episode: u32,
+        before: Option<Episode>,
+        after: Option<Episode>,
+    },
+    EpisodeRating {
+        tmdb_id: u64,
+        season: u32,
+        episode: u32,
+        before: Option<Rating>,
+        after: Option<Rating>,
+    },
+    WatchlistEntry {
+        tmdb_id: u64,
+        before: Option<WatchlistEntry>,
+        after: Option<WatchlistEntry>,
+    },
+}
+
+/// `value` as JSON, `null` if it cannot be serialized.
+fn value(value: impl Serialize) -> Value {
+    serde_json::to_value(value).unwrap_or_default()
+}
+
+impl EntityChange {
+    /// Kind of the changed entity and whether it existed before and after the change.
+    fn parts(&self) -> (&'static str, bool, bool) {
+        match self {
+            EntityChange::Movie { before, after, .. } => {
+                ("movie", before.is_some(), after.is_some())
+            }
+            EntityChange::Tag { before, after, .. } => ("tag", before.is_some(), after.is_some()),
+            EntityChange::MovieRating { before, after, .. } => {
+                ("movie_rating", before.is_some(), after.is_some())
+            }
+            EntityChange::Book { before, after, .. } => ("book", before.is_some(), after.is_some()),
+            EntityChange::Reading { before, after, .. } => {
+                ("reading", before.is_some(), after.is_some())
+            }
+            EntityChange::Series { before, after, .. } => {
+                ("series", before.is_some(), after.is_some())
+            }
+            EntityChange::Episode { before, after, .. } => {
+                ("episode", before.is_some(), after.is_some())
+            }
+            EntityChange::EpisodeRating { before, after, .. } => {
+                ("episode_rating", before.is_some(), after.is_some())
+            }
+            EntityChange::WatchlistEntry { before, after, .. } => {
+                ("watchlist_entry", before.is_some(), after.is_some())
+            }
+        }
+    }
+
+    /// Kind of the changed entity like `movie_rating`.
+    pub fn kind(&self) -> &'static str {
+        self.parts().0
+    }
+
+    /// Name of the event like `movie_rating.created`.
+    pub fn name(&self) -> String {
+        let (kind, before, after) = self.parts();
+        let action = match (before, after) {
+            (false, _) => "created",
+            (true, true) => "updated",
+            (true, false) => "deleted",
+        };
+        format!("{kind}.{action}")
+    }
+
+    /// The change as seen by `user`, who made it and owns the `tags`. Movies, books, series and
+    /// episodes only keep the ratings, readings, watches and tags of that user.
+    pub fn view(&self, user: u32, tags: &BTreeSet<u32>) -> EntityChange {
+        let episode = |episode: &Option<Episode>| {
+            episode.clone().map(|mut episode| {
+                episode.watched.retain(|&id, _| id == user);
+                episode.ratings.retain(|rating| rating.user == user);
+                episode
+            })
+        };
+        match self {
+            EntityChange::Movie {
+                tmdb_id,
+                before,
+                after,
+            } => EntityChange::Movie {
+                tmdb_id: *tmdb_id,
+                before: before.as_ref().map(|movie| movie.view(user, tags)),
+                after: after.as_ref().map(|movie| movie.view(user, tags)),
+            },
+            EntityChange::Book {
+                book_id,
+                before,
+                after,
+            } => EntityChange::Book {
+                book_id: *book_id,
+                before: before.as_ref().map(|book| book.view(user, tags)),
+                after: after.as_ref().map(|book| book.view(user, tags)),
+            },
+            EntityChange::Series {
+                tmdb_id,
+                before,
+                after,
+            } => EntityChange::Series {
+                tmdb_id: *tmdb_id,
+                before: before.as_ref().map(|series| series.view(user, tags)),
+                after: after.as_ref().map(|series| series.view(user, tags)),
+            },
+            EntityChange::Episode {
+                tmdb_id,
+                season,
+                episode: number,
+                before,
+                after,
+            } => EntityChange::Episode {
+                tmdb_id: *tmdb_id,
+                season: *season,
+                episode: *number,
+                before: episode(before),
+                after: episode(after),
+            },
+            // tags, ratings, readings and watchlist entries always belong to the user who changed
+            // them
+            change => change.clone(),
+        }
+    }
+
+    /// Id and new value of the changed entity as seen by `viewer`, or `None` if the change made by
+    /// `author` is none of their business. Tags, ratings, readings and watches are only visible to
+    /// the user they belong to, which is always the one who changed them, and so are watchlist
+    /// entries.
+    fn visible_to(&self, data: &AppData, viewer: u32, author: u32) -> Option<(Value, Value)> {
+        let tags = data.user_tags(viewer);
+        let (id, after) = match self {
+            EntityChange::Movie { tmdb_id, after, .. } => (
+                json!(tmdb_id),
+                value(after.as_ref().map(|movie| movie.view(viewer, &tags))),
+            ),
+            EntityChange::Book { book_id, after, .. } => (
+                json!(book_id),
+                value(after.as_ref().map(|book| book.view(viewer, &tags))),
+            ),
+            EntityChange::Series { tmdb_id, after, .. } => (
+                json!(tmdb_id),
+                value(after.as_ref().map(|series| series.view(viewer, &tags))),
+            ),
+            _ if author != viewer => return None,
+            EntityChange::Tag { tag_id, after, .. } => (json!(tag_id), value(after)),
+            EntityChange::MovieRating {
+                tmdb_id,
+                before,
+                after,
+            } => {
+                let date = after.as_ref().or(before.as_ref()).map(|rating| rating.date);
+                (json!({"tmdb_id": tmdb_id, "date": date}), value(after))
+            }
+            EntityChange::Reading {
+                book_id,
+                index,
+                after,
+                ..
+            } => (json!({"book_id": book_id, "index": index}), value(after)),
+            EntityChange::Episode {
+                tmdb_id,
+                season,
+                episode,
+                after,
+                ..
+            } => {
+                let after = after.clone().map(|mut episode| {
+                    episode.watched.retain(|&id, _| id == viewer);
+                    episode.ratings.retain(|r| r.user == viewer);
+                    episode
+                });
+                (
+                    json!({"tmdb_id": tmdb_id, "season": season, "episode": episode}),
+                    value(&after),
+                )
+            }
+            EntityChange::EpisodeRating {
+                tmdb_id,
+                season,
+                episode,
+                before,
+                after,
+            } => {
+                let date = after.as_ref().or(before.as_ref()).map(|rating| rating.date);
+                (
+                    json!({"tmdb_id": tmdb_id, "season": season, "episode": episode, "date": date}),
+                    value(after),
+                )
+            }
+            EntityChange::WatchlistEntry { tmdb_id, after, .. } => (json!(tmdb_id), value(after)),
+        };
+        Some((id, after))
+    }
+}
+
+/// Appends `change` to the log and sends it to all connected clients.
+async fn push(user: u32, change: Option<EntityChange>) {
+    let mut log = EVENTS.lock().await;
+    let event = log.push(user, change);
+    // fails if no client is connected
+    let _ = log.sender.send(event);
+}
+
+/// Sends `change` made by `user` to the webhooks and to all connected clients. Has to be called
+/// after the change was saved and the data lock was released.
+pub async fn publish(state: &AppState, user: u32, change: EntityChange) {
+    let (webhooks, view) = {
+        let data_lock = state.data.lock().await;
+        let webhooks = webhooks::subscribed_to(&data_lock, &change.name());
+        let view = (!webhooks.is_empty()).then(|| change.view(user, &data_lock.user_tags(user)));
+        (webhooks, view)
+    };
+    if let Some(view) = view {
+        webhooks::emit(webhooks, user, view).await;
+    }
+    push(user, Some(change)).await;
+}
+
+/// Tells all connected clients to reload all data, for changes which are too large to be sent as
+/// single events or are not made by a user.
+pub async fn reset() {
+    push(0, None).await;
+}
+
+#[derive(Serialize)]
+struct EventData {
+    kind: &'static str,
+    id: Value,
+    value: Value,
+}
+
+/// The stream of one connected client
+struct Subscription {
+    state: Data<AppState>,
+    user: u32,
+    /// Missed events to send before the new ones
+    backlog: VecDeque<Arc<Published>>,
+    receiver: broadcast::Receiver<Arc<Published>>,
+    /// Whether a reset has to be sent next
+    reset: bool,
+}
+
+impl Subscription {
+    /// The next message to send, `None` if the stream has ended.
+    async fn next(&mut self) -> Option<String> {
+        loop {
+            if std::mem::take(&mut self.reset) {
+                return Some(RESET.into());
+            }
+            let event = match self.backlog.pop_front() {
+                Some(event) => event,
+                None => match timeout(KEEP_ALIVE, self.receiver.recv()).await {
+                    Err(_) => return Some(": keep-alive\n\n".into()),
+                    Ok(Ok(event)) => event,
+                    Ok(Err(broadcast::error::RecvError::Lagged(_))) => {
+                        self.reset = true;
+                        continue;
+                    }
+                    Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+                },
+            };
+            let Some(change) = &event.change else {
+                return Some(format!("id: {}\n{RESET}", event.id));
+            };
+            let data_lock = self.state.data.lock().await;
+            let Some((id, value)) = change.visible_to(&data_lock, self.user, event.user) else {
+                continue;
+            };
+            drop(data_lock);
+            let data = EventData {
+                kind: change.kind(),
+                id,
+                value,
+            };
+            let Ok(data) = serde_json::to_string(&data) else {
+                continue;
+            };
+            return Some(format!(
+                "id: {}\nevent: {}\ndata: {data}\n\n",
+                event.id,
+                change.name()
+            ));
+        }
+    }
+}
+
+#[get("/api/events")]
+async fn get_events(state: Data<AppState>, user: CurrentUser, req: HttpRequest) -> impl Responder {
+    let last_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse::<u64>().ok());
+    let log = EVENTS.lock().await;
+    let mut subscription = Subscription {
+        state: state.clone(),
+        user: user.id,
+        backlog: VecDeque::new(),
+        receiver: log.sender.subscribe(),
+        reset: false,
+    };
+    if let Some(last_id) = last_id {
+        match log.resume(last_id) {
+            Some(backlog) => subscription.backlog = backlog,
+            None => subscription.reset = true,
+        }
+    }
+    drop(log);
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream::unfold(
+            subscription,
+            |mut subscription| async move {
+                let message = subscription.next().await?;
+                Some((Ok::<_, Infallible>(Bytes::from(message)), subscription))
+            },
+        ))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn tag_change(tag_id: u32) -> EntityChange {
+        EntityChange::Tag {
+            tag_id,
+            before: None,
+            after: Some(Tag::default()),
+        }
+    }
+
+    fn ids(events: &VecDeque<Arc<Published>>) -> Vec<u64> {
+        events.iter().map(|event| event.id).collect()
+    }
+
+    #[test]
+    fn backlog() {
+        let mut log = EventLog::new(100);
+        // nothing happened yet, so only the id before the first one is known
+        assert_eq!(log.resume(99).as_ref().map(ids), Some(vec![]));
+        assert!(log.resume(100).is_none());
+        for id in 0..3 {
+            log.push(1, Some(tag_change(id)));
+        }
+        assert_eq!(log.resume(99).as_ref().map(ids), Some(vec![100, 101, 102]));
+        assert_eq!(log.resume(101).as_ref().map(ids), Some(vec![102]));
+        assert_eq!(log.resume(102).as_ref().map(ids), Some(vec![]));
+    }
+
+    #[test]
+    fn reset() {
+        let mut log = EventLog::new(100);
+        for id in 0..KEEP_EVENTS as u32 + 2 {
+            log.push(1, Some(tag_change(id)));
+        }
+        // events 100 and 101 were dropped
+        assert!(log.resume(99).is_none());
+        assert!(log.resume(100).is_none());
+        assert_eq!(
+            log.resume(101).map(|events| events.len()),
+            Some(KEEP_EVENTS)
+        );
+        // ids from the future, e.g. from before a restart with a wrong clock
+        assert!(log.resume(log.next_id).is_none());
+        assert!(log.resume(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn visibility() {
+        let rating = |user| Rating {
+            user,
+            date: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+            rating: 8,
+            speed: 1.,
+            platform: None,
+            tags: BTreeSet::new(),
+        };
+        let movie = Movie {
+            imdb_id: None,
+            tmdb_id: 603,
+            title: "The Matrix".into(),
+            description: String::new(),
+            ratings: vec![rating(1), rating(2)],
+            tags: BTreeSet::new(),
+            platforms: BTreeSet::new(),
+            availability: Default::default(),
+            poster: None,
+            release_date: NaiveDate::from_ymd_opt(1999, 3, 31).unwrap(),
+            runtime: Duration::from_secs(136 * 60),
+            score: 8.2,
+            revision: 0,
+        };
+        let data = AppData::default();
+
+        let change = EntityChange::Movie {
+            tmdb_id: 603,
+            before: None,
+            after: Some(movie),
+        };
+        let (id, value) = change.visible_to(&data, 2, 1).unwrap();
+        assert_eq!(id, json!(603));
+        assert_eq!(value["ratings"].as_array().unwrap().len(), 1);
+        assert_eq!(value["ratings"][0]["user"], 2);
+        let EntityChange::Movie {
+            after: Some(movie), ..
+        } = change.view(1, &BTreeSet::new())
+        else {
+            panic!("the view of a movie change is a movie change");
+        };
+        assert_eq!(movie.ratings, [rating(1)]);
+
+        let change = EntityChange::MovieRating {
+            tmdb_id: 603,
+            before: Some(rating(1)),
+            after: None,
+        };
+        assert!(change.visible_to(&data, 2, 1).is_none());
+        let (id, value) = change.visible_to(&data, 1, 1).unwrap();
+        assert_eq!(id, json!({"tmdb_id": 603, "date": "2024-01-01"}));
+        assert_eq!(value, Value::Null);
+        assert!(tag_change(1).visible_to(&data, 2, 1).is_none());
+    }
+
+    #[test]
+    fn event_names() {
+        let tag = Tag::default();
+        let change = |before: Option<Tag>, after: Option<Tag>| EntityChange::Tag {
+            tag_id: 1,
+            before,
+            after,
+        };
+        assert_eq!(change(None, Some(tag.clone())).name(), "tag.created");
+        assert_eq!(
+            change(Some(tag.clone()), Some(tag.clone())).name(),
+            "tag.updated"
+        );
+        assert_eq!(change(Some(tag), None).name(), "tag.deleted");
+    }
+}
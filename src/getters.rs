@@ -1,38 +1,376 @@
+use std::collections::{BTreeSet, HashMap};
+
 use actix_web::{
     get,
-    web::{Data, Path},
+    web::{Data, Path, Query},
     HttpResponse, Responder,
 };
+use chrono::{Datelike, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use serde_with::{formats::CommaSeparator, serde_as, StringWithSeparator};
 
-use crate::AppState;
+use crate::{
+    listing::{in_range, Pagination, Quantifier, SortKey, SortOrder, SortValue},
+    revisions,
+    schema::{Book, Episode, Movie, Reading, Series},
+    tmdb::Region,
+    users::CurrentUser,
+    AppState,
+};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MovieSort {
+    #[default]
+    Title,
+    LastWatched,
+    Rating,
+    Score,
+    ReleaseDate,
+    Runtime,
+}
+
+#[serde_as]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct MovieQuery {
+    /// Comma-separated tag ids
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, u32>")]
+    tags: BTreeSet<u32>,
+    tags_mode: Quantifier,
+    /// Comma-separated platform ids, matched against the streaming offers in the region
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, u32>")]
+    platforms: BTreeSet<u32>,
+    platforms_mode: Quantifier,
+    watched: Option<bool>,
+    /// Bounds for the average rating
+    min_rating: Option<f64>,
+    max_rating: Option<f64>,
+    min_year: Option<i32>,
+    max_year: Option<i32>,
+    /// Bounds for the runtime in minutes
+    min_runtime: Option<u64>,
+    max_runtime: Option<u64>,
+    /// Case-insensitive substring of the title
+    title: Option<String>,
+    sort: MovieSort,
+    order: SortOrder,
+}
+
+impl MovieQuery {
+    fn matches(&self, movie: &Movie) -> bool {
+        let rating_filtered = self.min_rating.is_some() || self.max_rating.is_some();
+        self.tags_mode.matches(&movie.tags, &self.tags)
+            && self
+                .platforms_mode
+                .matches(&movie.platforms, &self.platforms)
+            && self
+                .watched
+                .is_none_or(|watched| watched != movie.ratings.is_empty())
+            && match movie.average_rating() {
+                Some(rating) => in_range(rating, self.min_rating, self.max_rating),
+                None => !rating_filtered,
+            }
+            && in_range(movie.release_date.year(), self.min_year, self.max_year)
+            && in_range(
+                movie.runtime.as_secs() / 60,
+                self.min_runtime,
+                self.max_runtime,
+            )
+            && self
+                .title
+                .as_ref()
+                .is_none_or(|title| movie.title.to_lowercase().contains(&title.to_lowercase()))
+    }
+
+    fn key(&self, movie: &Movie) -> SortKey<u64> {
+        let value = match self.sort {
+            MovieSort::Title => SortValue::None,
+            MovieSort::LastWatched => SortValue::Date(movie.last_watched()),
+            MovieSort::Rating => SortValue::Number(movie.average_rating()),
+            MovieSort::Score => SortValue::Number(Some(movie.score)),
+            MovieSort::ReleaseDate => SortValue::Date(Some(movie.release_date)),
+            MovieSort::Runtime => SortValue::Number(Some(movie.runtime.as_secs_f64())),
+        };
+        SortKey {
+            value,
+            title: movie.title.clone(),
+            id: movie.tmdb_id,
+        }
+    }
+}
 
 #[get("/api/movie")]
-async fn get_all_movies(data: Data<AppState>) -> impl Responder {
-    HttpResponse::Ok().json(&data.0.lock().await.movies)
+async fn get_all_movies(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Region(region): Region,
+    Query(query): Query<MovieQuery>,
+    Query(pagination): Query<Pagination>,
+) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    let tags = data_lock.user_tags(user.id);
+    let movies: Vec<_> = data_lock
+        .movies
+        .values()
+        .map(|movie| {
+            let mut movie = movie.view(user.id, &tags);
+            movie.in_region(&region, &data_lock.platforms);
+            movie
+        })
+        .filter(|movie| query.matches(movie))
+        .collect();
+    let page = pagination.paginate(
+        movies,
+        (query.sort, query.order),
+        |movie| query.key(movie),
+        |a, b| a.compare(b, query.order),
+    );
+    match page {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(err) => HttpResponse::BadRequest().body(err),
+    }
 }
 
 #[get("/api/tag")]
-async fn get_all_tags(data: Data<AppState>) -> impl Responder {
-    HttpResponse::Ok().json(&data.0.lock().await.tags)
+async fn get_all_tags(state: Data<AppState>, user: CurrentUser) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    let tags: HashMap<_, _> = data_lock
+        .tags
+        .iter()
+        .filter(|(_, tag)| tag.user == user.id)
+        .collect();
+    HttpResponse::Ok().json(tags)
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BookSort {
+    #[default]
+    Title,
+    Author,
+    LastActivity,
+    Rating,
+}
+
+#[serde_as]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct BookQuery {
+    /// Case-insensitive substring of any author
+    author: Option<String>,
+    /// Comma-separated tag ids
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, u32>")]
+    tags: BTreeSet<u32>,
+    tags_mode: Quantifier,
+    /// Whether the book has a reading which is not finished yet
+    reading: Option<bool>,
+    /// Whether the book has a finished reading
+    finished: Option<bool>,
+    /// Bounds for the average rating
+    min_rating: Option<f64>,
+    max_rating: Option<f64>,
+    min_year: Option<i32>,
+    max_year: Option<i32>,
+    /// Case-insensitive substring of the title
+    title: Option<String>,
+    sort: BookSort,
+    order: SortOrder,
+}
+
+impl BookQuery {
+    fn matches(&self, book: &Book) -> bool {
+        let rating_filtered = self.min_rating.is_some() || self.max_rating.is_some();
+        let year_filtered = self.min_year.is_some() || self.max_year.is_some();
+        self.author.as_ref().is_none_or(|author| {
+            let author = author.to_lowercase();
+            book.authors
+                .iter()
+                .any(|name| name.to_lowercase().contains(&author))
+        }) && self.tags_mode.matches(&book.tags, &self.tags)
+            && self.reading.is_none_or(|reading| {
+                reading == book.readings.iter().any(|reading| !reading.is_finished())
+            })
+            && self
+                .finished
+                .is_none_or(|finished| finished == book.readings.iter().any(Reading::is_finished))
+            && match book.average_rating() {
+                Some(rating) => in_range(rating, self.min_rating, self.max_rating),
+                None => !rating_filtered,
+            }
+            && match book.release_date {
+                Some(date) => in_range(date.year(), self.min_year, self.max_year),
+                None => !year_filtered,
+            }
+            && self
+                .title
+                .as_ref()
+                .is_none_or(|title| book.title.to_lowercase().contains(&title.to_lowercase()))
+    }
+
+    fn key(&self, book: &Book) -> SortKey<u32> {
+        let value = match self.sort {
+            BookSort::Title => SortValue::None,
+            BookSort::Author => SortValue::Text(book.authors.first().cloned()),
+            BookSort::LastActivity => SortValue::Date(book.last_activity()),
+            BookSort::Rating => SortValue::Number(book.average_rating()),
+        };
+        SortKey {
+            value,
+            title: book.title.clone(),
+            id: book.id,
+        }
+    }
 }
 
 #[get("/api/book")]
-async fn get_all_books(data: Data<AppState>) -> impl Responder {
-    HttpResponse::Ok().json(&data.0.lock().await.books)
+async fn get_all_books(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Query(query): Query<BookQuery>,
+    Query(pagination): Query<Pagination>,
+) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    let tags = data_lock.user_tags(user.id);
+    let books: Vec<_> = data_lock
+        .books
+        .values()
+        .map(|book| book.view(user.id, &tags))
+        .filter(|book| query.matches(book))
+        .collect();
+    let page = pagination.paginate(
+        books,
+        (query.sort, query.order),
+        |book| query.key(book),
+        |a, b| a.compare(b, query.order),
+    );
+    match page {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(err) => HttpResponse::BadRequest().body(err),
+    }
+}
+
+#[get("/api/series")]
+async fn get_all_series(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Region(region): Region,
+    Query(pagination): Query<Pagination>,
+) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    let tags = data_lock.user_tags(user.id);
+    let series: Vec<_> = data_lock
+        .series
+        .values()
+        .map(|series| {
+            let mut series = series.view(user.id, &tags);
+            series.in_region(&region, &data_lock.platforms);
+            series
+        })
+        .collect();
+    let key = |series: &Series| SortKey {
+        value: SortValue::None,
+        title: series.title.clone(),
+        id: series.tmdb_id,
+    };
+    match pagination.paginate(series, (), key, |a, b| a.compare(b, SortOrder::Asc)) {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(err) => HttpResponse::BadRequest().body(err),
+    }
+}
+
+#[derive(Serialize)]
+struct NextEpisode<'a> {
+    series_id: u64,
+    series_title: &'a str,
+    season: u32,
+    episode: &'a Episode,
+    /// Whether the episode has already been aired
+    aired: bool,
+    /// Date of the last watched episode of the series
+    last_watched: NaiveDate,
+}
+
+/// Lists the next episode to watch for every series that has been started but not finished,
+/// most recently watched first.
+#[get("/api/series/next")]
+async fn get_next_episodes(state: Data<AppState>, user: CurrentUser) -> impl Responder {
+    let today = Local::now().date_naive();
+    let data_lock = state.data.lock().await;
+    let tags = data_lock.user_tags(user.id);
+    let series: Vec<_> = data_lock
+        .series
+        .values()
+        .map(|series| series.view(user.id, &tags))
+        .collect();
+    let mut next: Vec<_> = series
+        .iter()
+        .filter_map(|series| {
+            let last_watched = series.last_watched()?;
+            let (season, episode) = series.next_episode()?;
+            Some(NextEpisode {
+                series_id: series.tmdb_id,
+                series_title: &series.title,
+                season,
+                episode,
+                aired: episode.air_date.is_some_and(|date| date <= today),
+                last_watched,
+            })
+        })
+        .collect();
+    next.sort_by(|a, b| {
+        b.last_watched
+            .cmp(&a.last_watched)
+            .then(a.series_id.cmp(&b.series_id))
+    });
+    HttpResponse::Ok().json(next)
 }
 
 #[get("/api/movie/{id}")]
-async fn get_movie(data: Data<AppState>, id: Path<u64>) -> impl Responder {
-    match data.0.lock().await.movies.get(&id) {
-        Some(movie) => HttpResponse::Ok().json(movie),
+async fn get_movie(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Region(region): Region,
+    id: Path<u64>,
+) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    match data_lock.movies.get(&id) {
+        Some(movie) => {
+            let mut movie = movie.view(user.id, &data_lock.user_tags(user.id));
+            movie.in_region(&region, &data_lock.platforms);
+            HttpResponse::Ok()
+                .insert_header(revisions::etag(movie.revision))
+                .json(movie)
+        }
         None => HttpResponse::NotFound().finish(),
     }
 }
 
 #[get("/api/book/{id}")]
-async fn get_book(data: Data<AppState>, id: Path<u32>) -> impl Responder {
-    match data.0.lock().await.books.get(&id) {
-        Some(book) => HttpResponse::Ok().json(book),
+async fn get_book(state: Data<AppState>, user: CurrentUser, id: Path<u32>) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    match data_lock.books.get(&id) {
+        Some(book) => HttpResponse::Ok()
+            .insert_header(revisions::etag(book.revision))
+            .json(book.view(user.id, &data_lock.user_tags(user.id))),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/api/series/{id}")]
+async fn get_series(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Region(region): Region,
+    id: Path<u64>,
+) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    match data_lock.series.get(&id) {
+        Some(series) => {
+            let mut series = series.view(user.id, &data_lock.user_tags(user.id));
+            series.in_region(&region, &data_lock.platforms);
+            HttpResponse::Ok().json(series)
+        }
         None => HttpResponse::NotFound().finish(),
     }
 }
@@ -0,0 +1,271 @@
+//! Reading goals and streaks computed from [`Reading::pages_read`](crate::schema::Reading).
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use actix_web::{
+    get, put,
+    web::{Data, Json, Query},
+    HttpResponse, Responder,
+};
+use chrono::{Datelike, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    schema::{AppData, Goals},
+    stats::{self, Streak},
+    storage::Change,
+    users::CurrentUser,
+    AppState,
+};
+
+#[get("/api/goals")]
+async fn get_goals(state: Data<AppState>, user: CurrentUser) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    HttpResponse::Ok().json(data_lock.goals.get(&user.id).cloned().unwrap_or_default())
+}
+
+#[put("/api/goals")]
+async fn put_goals(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(goals): Json<Goals>,
+) -> impl Responder {
+    // too large numbers are parsed as infinity
+    if !goals.pages_per_hour.is_finite() || goals.pages_per_hour <= 0. {
+        return HttpResponse::BadRequest().body("pages_per_hour must be a positive number");
+    }
+    let mut data_lock = state.data.lock().await;
+    data_lock.goals.insert(user.id, goals);
+    match state.save(&data_lock, Change::Goals(user.id)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct YearlyProgress {
+    year: i32,
+    goal: u32,
+    finished: u32,
+    /// Number of books that should be finished by now to reach the goal
+    expected: f64,
+    on_track: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct DailyProgress<T> {
+    goal: u16,
+    done: T,
+    reached: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Streaks {
+    /// Number of consecutive days with pages read, up to today or yesterday
+    current: u64,
+    longest: Option<Streak>,
+    /// Like `current`, but only counting days on which the daily page goal was reached
+    current_goal: Option<u64>,
+    longest_goal: Option<Streak>,
+}
+
+#[derive(Debug, Serialize)]
+struct Progress {
+    date: NaiveDate,
+    yearly_books: Option<YearlyProgress>,
+    daily_pages: Option<DailyProgress<u32>>,
+    /// Estimated from the pages read and the reading pace
+    daily_minutes: Option<DailyProgress<f64>>,
+    streaks: Streaks,
+}
+
+/// Length of the streak ending at `date` or the day before, so that a streak is not broken
+/// before the day is over. Days after `date` are ignored.
+fn current_streak(dates: &BTreeSet<NaiveDate>, date: NaiveDate) -> u64 {
+    stats::streaks(&dates.range(..=date).copied().collect())
+        .pop()
+        .filter(|streak| streak.end == date || streak.end.succ_opt() == Some(date))
+        .map_or(0, |streak| streak.days)
+}
+
+/// Progress of the only user in the [view](AppData::view) `data`.
+fn progress(data: &AppData, date: NaiveDate) -> Progress {
+    let goals = data.goals.values().next().cloned().unwrap_or_default();
+
+    let mut pages_per_day = BTreeMap::<NaiveDate, u32>::new();
+    let mut finished = 0;
+    for reading in data.books.values().flat_map(|book| &book.readings) {
+        for (&day, &pages) in reading.pages_read.range(..=date) {
+            *pages_per_day.entry(day).or_default() += pages as u32;
+        }
+        if reading
+            .finished_on()
+            .is_some_and(|day| day.year() == date.year() && day <= date)
+        {
+            finished += 1;
+        }
+    }
+
+    let yearly_books = goals.yearly_books.get(&date.year()).map(|&goal| {
+        let days_in_year =
+            NaiveDate::from_ymd_opt(date.year(), 12, 31).map_or(365, |last| last.ordinal());
+        let expected = goal as f64 * date.ordinal() as f64 / days_in_year as f64;
+        YearlyProgress {
+            year: date.year(),
+            goal,
+            finished,
+            expected,
+            on_track: finished as f64 >= expected.floor(),
+        }
+    });
+    let pages_today = pages_per_day.get(&date).copied().unwrap_or(0);
+    let daily_pages = goals.daily_pages.map(|goal| DailyProgress {
+        goal,
+        done: pages_today,
+        reached: pages_today >= goal as u32,
+    });
+    let minutes_today = pages_today as f64 / goals.pages_per_hour as f64 * 60.;
+    let daily_minutes = goals.daily_minutes.map(|goal| DailyProgress {
+        goal,
+        done: minutes_today,
+        reached: minutes_today >= goal as f64,
+    });
+
+    let reading_days: BTreeSet<_> = pages_per_day.keys().copied().collect();
+    let goal_days = goals.daily_pages.map(|goal| {
+        pages_per_day
+            .iter()
+            .filter(|(_, &pages)| pages >= goal as u32)
+            .map(|(&day, _)| day)
+            .collect::<BTreeSet<_>>()
+    });
+    Progress {
+        date,
+        yearly_books,
+        daily_pages,
+        daily_minutes,
+        streaks: Streaks {
+            current: current_streak(&reading_days, date),
+            longest: stats::longest_streak(&reading_days),
+            current_goal: goal_days.as_ref().map(|days| current_streak(days, date)),
+            longest_goal: goal_days.as_ref().and_then(stats::longest_streak),
+        },
+    }
+}
+
+#[derive(Deserialize)]
+struct ProgressQuery {
+    /// Day to compute the progress for, defaults to today
+    date: Option<NaiveDate>,
+}
+
+#[get("/api/goals/progress")]
+async fn get_progress(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Query(ProgressQuery { date }): Query<ProgressQuery>,
+) -> impl Responder {
+    let date = date.unwrap_or_else(|| Local::now().date_naive());
+    let data = state.data.lock().await.view(user.id);
+    HttpResponse::Ok().json(progress(&data, date))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::schema::{Book, Reading};
+
+    fn day(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, month, day).unwrap()
+    }
+
+    fn data(pages_read: BTreeMap<NaiveDate, u16>, goals: Option<Goals>) -> AppData {
+        let book = Book {
+            id: 1,
+            olid: None,
+            title: "Erebos".into(),
+            description: String::new(),
+            authors: vec![],
+            readings: vec![Reading {
+                user: 1,
+                pages_read,
+                rating: None,
+                isbn: None,
+                start_page: 1,
+                end_page: 400,
+            }],
+            tags: BTreeSet::new(),
+            release_date: None,
+            score: None,
+            revision: 0,
+        };
+        AppData {
+            books: HashMap::from([(1, book)]),
+            goals: goals.into_iter().map(|goals| (1, goals)).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn streaks() {
+        let dates = BTreeSet::from([day(3, 1), day(3, 2), day(3, 4), day(3, 5)]);
+        assert_eq!(current_streak(&dates, day(3, 5)), 2);
+        // the streak is not broken before the day is over
+        assert_eq!(current_streak(&dates, day(3, 6)), 2);
+        assert_eq!(current_streak(&dates, day(3, 7)), 0);
+        // later days do not count for earlier dates
+        assert_eq!(current_streak(&dates, day(3, 3)), 2);
+        assert_eq!(current_streak(&dates, day(3, 4)), 1);
+        // across the leap day
+        let dates = BTreeSet::from([day(2, 28), day(2, 29), day(3, 1)]);
+        assert_eq!(current_streak(&dates, day(3, 1)), 3);
+        assert_eq!(current_streak(&BTreeSet::new(), day(3, 1)), 0);
+    }
+
+    #[test]
+    fn no_goals() {
+        let progress = progress(&data(BTreeMap::from([(day(3, 4), 20)]), None), day(3, 5));
+        assert!(progress.yearly_books.is_none());
+        assert!(progress.daily_pages.is_none());
+        assert!(progress.daily_minutes.is_none());
+        assert_eq!(progress.streaks.current, 1);
+        assert!(progress.streaks.current_goal.is_none());
+        assert!(progress.streaks.longest_goal.is_none());
+
+        let empty = super::progress(&AppData::default(), day(3, 5));
+        assert_eq!(empty.streaks.current, 0);
+        assert!(empty.streaks.longest.is_none());
+    }
+
+    #[test]
+    fn goals() {
+        let goals = Goals {
+            yearly_books: BTreeMap::from([(2024, 366)]),
+            daily_pages: Some(20),
+            daily_minutes: Some(30),
+            pages_per_hour: 60.,
+        };
+        let pages_read = BTreeMap::from([(day(2, 27), 30), (day(2, 28), 10), (day(2, 29), 25)]);
+        let progress = progress(&data(pages_read, Some(goals)), day(2, 29));
+
+        // 2024 is a leap year, so one book per day means 60 books by the 29th of February
+        let yearly = progress.yearly_books.unwrap();
+        assert_eq!(yearly.expected, 60.);
+        assert_eq!(yearly.finished, 0);
+        assert!(!yearly.on_track);
+        let pages = progress.daily_pages.unwrap();
+        assert_eq!(pages.done, 25);
+        assert!(pages.reached);
+        let minutes = progress.daily_minutes.unwrap();
+        assert_eq!(minutes.done, 25.);
+        assert!(!minutes.reached);
+        assert_eq!(progress.streaks.current, 3);
+        assert_eq!(progress.streaks.current_goal, Some(1));
+        assert_eq!(
+            progress.streaks.longest_goal.map(|streak| streak.days),
+            Some(1)
+        );
+    }
+}
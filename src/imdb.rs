@@ -0,0 +1,327 @@
+//! Import of the "Your Ratings" CSV export from IMDb.
+//!
+//! Rating histories can contain thousands of titles, each of which needs multiple TMDB requests.
+//! The import therefore runs in the background in rate-limited batches, and its progress can be
+//! polled from a status resource.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+use actix_web::{
+    get, post,
+    rt::{
+        self,
+        time::{sleep, Instant},
+    },
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::future;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    config::config,
+    events::{self, EntityChange},
+    schema::Rating,
+    storage::Change,
+    tmdb,
+    users::CurrentUser,
+    AppState,
+};
+
+/// Maximum accepted size of an uploaded CSV file
+const MAX_CSV_SIZE: usize = 16 << 20;
+/// Number of titles looked up concurrently
+const BATCH_SIZE: usize = 10;
+/// Minimum time between the start of two batches. Every title takes up to three TMDB requests,
+/// which keeps the import well below TMDB's limit of about 50 requests per second.
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of finished imports whose status is kept around
+const KEEP_FINISHED: usize = 10;
+
+static IMPORTS: Lazy<Mutex<HashMap<u32, ImportStatus>>> = Lazy::new(Default::default);
+
+#[derive(Debug, Deserialize)]
+struct ImdbRow {
+    /// IMDb id like `tt0133093`
+    #[serde(rename = "Const")]
+    id: String,
+    /// Rating in range `1..=10`
+    #[serde(rename = "Your Rating")]
+    rating: u8,
+    #[serde(rename = "Date Rated")]
+    date: NaiveDate,
+    #[serde(rename = "Title", default)]
+    title: String,
+    #[serde(rename = "Title Type", default)]
+    title_type: String,
+}
+
+fn parse_csv(csv: &[u8]) -> csv::Result<Vec<csv::Result<ImdbRow>>> {
+    let mut reader = csv::Reader::from_reader(csv);
+    // make sure the headers are valid before reading any rows
+    reader.headers()?;
+    Ok(reader.into_deserialize().collect())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ImportState {
+    Running,
+    Finished,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct UnmatchedRow {
+    /// Line number in the CSV file
+    line: usize,
+    imdb_id: Option<String>,
+    title: Option<String>,
+    reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ImportStatus {
+    id: u32,
+    /// User who started the import and receives the ratings
+    user: u32,
+    state: ImportState,
+    started: DateTime<Utc>,
+    finished: Option<DateTime<Utc>>,
+    /// Number of rows in the CSV file
+    total: usize,
+    /// Number of rows already handled
+    processed: usize,
+    ratings_added: usize,
+    movies_created: Vec<u64>,
+    /// Rows for which a rating at the same date already exists
+    duplicates: usize,
+    unmatched: Vec<UnmatchedRow>,
+    /// Reason why the import was aborted
+    error: Option<String>,
+}
+
+/// Applies `f` to the status of the import with the given id.
+async fn update(id: u32, f: impl FnOnce(&mut ImportStatus)) {
+    if let Some(status) = IMPORTS.lock().await.get_mut(&id) {
+        f(status);
+    }
+}
+
+/// Imports the `rows` in a separate task, so that import `id` is marked as failed instead of
+/// staying in the running state forever if a batch aborts with a panic.
+async fn run(state: Data<AppState>, id: u32, rows: Vec<(usize, ImdbRow)>) {
+    if let Err(err) = rt::spawn(import_rows(state, id, rows)).await {
+        update(id, |status| {
+            status.state = ImportState::Failed;
+            status.finished = Some(Utc::now());
+            status.error = Some(format!("Import aborted: {err}"));
+        })
+        .await;
+    }
+}
+
+/// Imports all `rows` in batches, updating the status of import `id` after every batch.
+async fn import_rows(state: Data<AppState>, id: u32, rows: Vec<(usize, ImdbRow)>) {
+    for batch in rows.chunks(BATCH_SIZE) {
+        let batch_start = Instant::now();
+        let movies = future::join_all(
+            batch
+                .iter()
+                .map(|(_, row)| tmdb::movie_by_id(&state, &row.id, &config().region)),
+        )
+        .await;
+
+        let mut data_lock = state.data.lock().await;
+        let mut imports = IMPORTS.lock().await;
+        let Some(status) = imports.get_mut(&id) else {
+            return;
+        };
+        let user = status.user;
+        let mut changes = vec![];
+        for ((line, row), movie) in batch.iter().zip(movies) {
+            status.processed += 1;
+            let movie = match movie {
+                Ok(movie) => movie,
+                Err(err) => {
+                    status.unmatched.push(UnmatchedRow {
+                        line: *line,
+                        imdb_id: Some(row.id.clone()),
+                        title: Some(row.title.clone()),
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            let tmdb_id = movie.tmdb_id;
+            let created = !data_lock.movies.contains_key(&tmdb_id);
+            let movie = data_lock.movies.entry(tmdb_id).or_insert_with(|| {
+                status.movies_created.push(tmdb_id);
+                movie
+            });
+            let rating = Rating {
+                user,
+                date: row.date,
+                rating: row.rating,
+                speed: 1.,
+                platform: None,
+                tags: BTreeSet::new(),
+            };
+            let added = movie.add_rating(rating.clone()).is_ok();
+            if added {
+                movie.revision += 1;
+                status.ratings_added += 1;
+            } else {
+                status.duplicates += 1;
+            }
+            let change = match (created, added) {
+                (true, _) => Some(EntityChange::Movie {
+                    tmdb_id,
+                    before: None,
+                    after: Some(movie.clone()),
+                }),
+                (false, true) => Some(EntityChange::MovieRating {
+                    tmdb_id,
+                    before: None,
+                    after: Some(rating),
+                }),
+                (false, false) => None,
+            };
+            if let Err(err) = state.save(&data_lock, Change::Movie(tmdb_id)).await {
+                status.state = ImportState::Failed;
+                status.finished = Some(Utc::now());
+                status.error = Some(format!("Failed to save new data to disk: {err}"));
+                return;
+            }
+            changes.extend(change);
+        }
+        drop(imports);
+        drop(data_lock);
+        for change in changes {
+            events::publish(&state, user, change).await;
+        }
+
+        sleep(BATCH_INTERVAL.saturating_sub(batch_start.elapsed())).await;
+    }
+
+    update(id, |status| {
+        status.state = ImportState::Finished;
+        status.finished = Some(Utc::now());
+    })
+    .await;
+}
+
+#[post("/api/imdb/import")]
+async fn import(state: Data<AppState>, user: CurrentUser, payload: web::Payload) -> impl Responder {
+    let body = match payload.to_bytes_limited(MAX_CSV_SIZE).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(err)) => {
+            return HttpResponse::BadRequest().body(format!("Failed to read body: {err}"))
+        }
+        Err(_) => return HttpResponse::PayloadTooLarge().finish(),
+    };
+    let rows = match parse_csv(&body) {
+        Ok(rows) => rows,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid CSV file: {err}")),
+    };
+
+    let mut unmatched = vec![];
+    let mut valid_rows = vec![];
+    for (idx, row) in rows.into_iter().enumerate() {
+        // line 1 is the header
+        let line = idx + 2;
+        match row {
+            // series and episodes are never returned as movies by TMDB
+            Ok(row) if row.title_type.contains("Series") || row.title_type.contains("Episode") => {
+                unmatched.push(UnmatchedRow {
+                    line,
+                    imdb_id: Some(row.id),
+                    title: Some(row.title),
+                    reason: format!("unsupported title type '{}'", row.title_type),
+                })
+            }
+            Ok(row) => valid_rows.push((line, row)),
+            Err(err) => unmatched.push(UnmatchedRow {
+                line,
+                imdb_id: None,
+                title: None,
+                reason: format!("invalid row: {err}"),
+            }),
+        }
+    }
+
+    let mut imports = IMPORTS.lock().await;
+    let mut finished: Vec<_> = imports
+        .values()
+        .filter_map(|status| Some((status.finished?, status.id)))
+        .collect();
+    finished.sort_unstable();
+    for (_, old_id) in finished.iter().rev().skip(KEEP_FINISHED) {
+        imports.remove(old_id);
+    }
+    let id = loop {
+        let new_id = rand::random::<u32>();
+        if !imports.contains_key(&new_id) {
+            break new_id;
+        }
+    };
+    let status = ImportStatus {
+        id,
+        user: user.id,
+        state: ImportState::Running,
+        started: Utc::now(),
+        finished: None,
+        total: valid_rows.len() + unmatched.len(),
+        processed: unmatched.len(),
+        ratings_added: 0,
+        movies_created: vec![],
+        duplicates: 0,
+        unmatched,
+        error: None,
+    };
+    imports.insert(id, status.clone());
+    drop(imports);
+
+    rt::spawn(run(state, id, valid_rows));
+    HttpResponse::Accepted()
+        .insert_header(("Location", format!("/api/imdb/import/{id}")))
+        .json(status)
+}
+
+#[get("/api/imdb/import/{id}")]
+async fn import_status(user: CurrentUser, id: web::Path<u32>) -> impl Responder {
+    match IMPORTS.lock().await.get(&id) {
+        Some(status) if status.user == user.id => HttpResponse::Ok().json(status),
+        _ => HttpResponse::NotFound().body(format!("import with ID {id} does not exist")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ratings_csv() {
+        let csv = "Const,Your Rating,Date Rated,Title,Original Title,URL,Title Type,IMDb Rating,\
+                   Runtime (mins),Year,Genres,Num Votes,Release Date,Directors\n\
+                   tt0133093,9,2023-04-01,The Matrix,The Matrix,\
+                   https://www.imdb.com/title/tt0133093/,Movie,8.7,136,1999,\"Action, Sci-Fi\",\
+                   2000000,1999-03-24,\"Lana Wachowski, Lilly Wachowski\"\n\
+                   tt0903747,10,2022-01-02,Breaking Bad,Breaking Bad,\
+                   https://www.imdb.com/title/tt0903747/,TV Series,9.5,49,2008,Drama,2000000,\
+                   2008-01-20,\n";
+        let rows = parse_csv(csv.as_bytes()).unwrap();
+        let rows = rows.into_iter().collect::<csv::Result<Vec<_>>>().unwrap();
+        assert_eq!(rows[0].id, "tt0133093");
+        assert_eq!(rows[0].rating, 9);
+        assert_eq!(rows[0].date, NaiveDate::from_ymd_opt(2023, 4, 1).unwrap());
+        assert_eq!(rows[1].title_type, "TV Series");
+    }
+}
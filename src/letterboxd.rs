@@ -0,0 +1,319 @@
+//! Import and export of Letterboxd CSV files.
+//!
+//! Both the `diary.csv` and the `ratings.csv` from a Letterboxd data export can be imported.
+//! The export uses the format accepted by Letterboxd's own importer.
+
+use std::collections::{BTreeSet, HashMap};
+
+use actix_web::{
+    get, post,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use anyhow::Result;
+use chrono::{Datelike, NaiveDate};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::config,
+    events::{self, EntityChange},
+    schema::{rating_from_stars, AppData, MovieStub, Rating},
+    storage::Change,
+    tmdb,
+    users::CurrentUser,
+    AppState,
+};
+
+/// Maximum accepted size of an uploaded CSV file
+const MAX_CSV_SIZE: usize = 16 << 20;
+
+#[derive(Debug, Deserialize)]
+struct LetterboxdRow {
+    /// Date of the diary entry or rating
+    #[serde(rename = "Date")]
+    date: NaiveDate,
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Year")]
+    year: Option<u16>,
+    /// Half-star rating in range `0.5..=5.0`
+    #[serde(rename = "Rating", default)]
+    rating: Option<f32>,
+    /// Only present in `diary.csv`
+    #[serde(rename = "Watched Date", default)]
+    watched_date: Option<NaiveDate>,
+}
+
+fn parse_csv(csv: &[u8]) -> csv::Result<Vec<csv::Result<LetterboxdRow>>> {
+    let mut reader = csv::Reader::from_reader(csv);
+    // make sure the headers are valid before reading any rows
+    reader.headers()?;
+    Ok(reader.into_deserialize().collect())
+}
+
+#[derive(Debug, Serialize)]
+struct UnmatchedRow {
+    /// Line number in the CSV file
+    line: usize,
+    name: Option<String>,
+    year: Option<u16>,
+    reason: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ImportReport {
+    ratings_added: usize,
+    movies_created: Vec<u64>,
+    /// Rows for which a rating at the same date already exists
+    duplicates: usize,
+    unmatched: Vec<UnmatchedRow>,
+}
+
+/// Picks the only search result with exactly the given title and release year. Guessing would
+/// silently add ratings to the wrong movie, so anything else is reported as unmatched.
+fn pick_match(results: &[MovieStub], name: &str, year: Option<u16>) -> Result<u64, String> {
+    let exact: Vec<_> = results
+        .iter()
+        .filter(|stub| {
+            year.is_none_or(|year| stub.release_date.year() == year as i32)
+                && stub.title.eq_ignore_ascii_case(name)
+        })
+        .collect();
+    match exact[..] {
+        [stub] => Ok(stub.tmdb_id),
+        [] => Err("no movie with this title and year found on TMDB".into()),
+        _ => Err(format!(
+            "{} movies with this title and year found on TMDB",
+            exact.len()
+        )),
+    }
+}
+
+/// Finds the TMDB id of the movie with the given title and release year, or returns why there is
+/// no single match.
+async fn find_tmdb_id(name: &str, year: Option<u16>) -> Result<u64, String> {
+    let results = tmdb::search_movies(name.to_owned(), year)
+        .await
+        .map_err(|err| format!("TMDB search failed: {err}"))?;
+    pick_match(&results, name, year)
+}
+
+#[post("/api/letterboxd/import")]
+async fn import(state: Data<AppState>, user: CurrentUser, payload: web::Payload) -> impl Responder {
+    let body = match payload.to_bytes_limited(MAX_CSV_SIZE).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(err)) => {
+            return HttpResponse::BadRequest().body(format!("Failed to read body: {err}"))
+        }
+        Err(_) => return HttpResponse::PayloadTooLarge().finish(),
+    };
+    let rows = match parse_csv(&body) {
+        Ok(rows) => rows,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid CSV file: {err}")),
+    };
+
+    let mut report = ImportReport::default();
+    // the same movie usually appears multiple times in a diary
+    let mut matches = HashMap::<(String, Option<u16>), Result<u64, String>>::new();
+    for (idx, row) in rows.into_iter().enumerate() {
+        // line 1 is the header
+        let line = idx + 2;
+        let row = match row {
+            Ok(row) => row,
+            Err(err) => {
+                report.unmatched.push(UnmatchedRow {
+                    line,
+                    name: None,
+                    year: None,
+                    reason: format!("invalid row: {err}"),
+                });
+                continue;
+            }
+        };
+        let unmatched = |reason: String| UnmatchedRow {
+            line,
+            name: Some(row.name.clone()),
+            year: row.year,
+            reason,
+        };
+
+        let Some(rating) = row.rating.and_then(rating_from_stars) else {
+            report.unmatched.push(unmatched("row has no rating".into()));
+            continue;
+        };
+        let key = (row.name.clone(), row.year);
+        let tmdb_id = match matches.get(&key) {
+            Some(tmdb_id) => tmdb_id.clone(),
+            None => {
+                let tmdb_id = find_tmdb_id(&row.name, row.year).await;
+                matches.insert(key, tmdb_id.clone());
+                tmdb_id
+            }
+        };
+        let tmdb_id = match tmdb_id {
+            Ok(tmdb_id) => tmdb_id,
+            Err(reason) => {
+                report.unmatched.push(unmatched(reason));
+                continue;
+            }
+        };
+        let movie = match tmdb::movie_by_id(&state, &tmdb_id.to_string(), &config().region).await {
+            Ok(movie) => movie,
+            Err(err) => {
+                report.unmatched.push(unmatched(err.to_string()));
+                continue;
+            }
+        };
+
+        let mut data_lock = state.data.lock().await;
+        let created = !data_lock.movies.contains_key(&tmdb_id);
+        let movie = data_lock.movies.entry(tmdb_id).or_insert_with(|| {
+            report.movies_created.push(tmdb_id);
+            movie
+        });
+        let rating = Rating {
+            user: user.id,
+            date: row.watched_date.unwrap_or(row.date),
+            rating,
+            speed: 1.,
+            platform: None,
+            tags: BTreeSet::new(),
+        };
+        let added = movie.add_rating(rating.clone()).is_ok();
+        if added {
+            movie.revision += 1;
+            report.ratings_added += 1;
+        } else {
+            report.duplicates += 1;
+        }
+        let change = match (created, added) {
+            (true, _) => Some(EntityChange::Movie {
+                tmdb_id,
+                before: None,
+                after: Some(movie.clone()),
+            }),
+            (false, true) => Some(EntityChange::MovieRating {
+                tmdb_id,
+                before: None,
+                after: Some(rating),
+            }),
+            (false, false) => None,
+        };
+        if let Err(err) = state.save(&data_lock, Change::Movie(tmdb_id)).await {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to save new data to disk: {err}"));
+        }
+        drop(data_lock);
+        if let Some(change) = change {
+            events::publish(&state, user.id, change).await;
+        }
+    }
+    HttpResponse::Ok().json(report)
+}
+
+/// Writes all ratings in the format of Letterboxd's CSV importer.
+fn write_csv(data: &AppData) -> Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record([
+        "tmdbID",
+        "imdbID",
+        "Title",
+        "Year",
+        "Rating10",
+        "WatchedDate",
+        "Rewatch",
+        "Tags",
+    ])?;
+    for movie in data.movies.values() {
+        // ratings are sorted newest first
+        let first_watch = movie.ratings.last().map(|rating| rating.date);
+        for rating in &movie.ratings {
+            let tags = movie
+                .tags
+                .union(&rating.tags)
+                .filter_map(|id| data.tags.get(id))
+                .map(|tag| &tag.name)
+                .join(", ");
+            writer.write_record([
+                movie.tmdb_id.to_string(),
+                movie
+                    .imdb_id
+                    .map(|id| format!("tt{id:07}"))
+                    .unwrap_or_default(),
+                movie.title.clone(),
+                movie.release_date.year().to_string(),
+                rating.rating.to_string(),
+                rating.date.format("%Y-%m-%d").to_string(),
+                (Some(rating.date) != first_watch).to_string(),
+                tags,
+            ])?;
+        }
+    }
+    Ok(writer.into_inner()?)
+}
+
+#[get("/api/letterboxd/export")]
+async fn export(state: Data<AppState>, user: CurrentUser) -> impl Responder {
+    match write_csv(&state.data.lock().await.view(user.id)) {
+        Ok(csv) => HttpResponse::Ok()
+            .content_type("text/csv")
+            .insert_header((
+                "Content-Disposition",
+                "attachment; filename=\"letterboxd-import.csv\"",
+            ))
+            .body(csv),
+        Err(err) => {
+            HttpResponse::InternalServerError().body(format!("Failed to write CSV file: {err}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches() {
+        let stub = |tmdb_id, title: &str, year| MovieStub {
+            tmdb_id,
+            title: title.into(),
+            description: String::new(),
+            release_date: NaiveDate::from_ymd_opt(year, 1, 1).unwrap(),
+            poster: None,
+        };
+        let results = [
+            stub(1, "Dune", 2021),
+            stub(2, "Dune", 1984),
+            stub(3, "Dune: Part Two", 2024),
+        ];
+        assert_eq!(pick_match(&results, "dune", Some(1984)), Ok(2));
+        assert!(pick_match(&results, "Dune", None).is_err());
+        assert!(pick_match(&results, "Dune", Some(2000)).is_err());
+        assert!(pick_match(&results[2..], "Dune", Some(2024)).is_err());
+    }
+
+    #[test]
+    fn diary() {
+        let csv = "Date,Name,Year,Letterboxd URI,Rating,Rewatch,Tags,Watched Date\n\
+            2024-02-01,The Matrix,1999,https://boxd.it/abc,4.5,Yes,,2024-01-31\n\
+            2024-02-02,Dune,2021,https://boxd.it/def,,,,2024-02-02\n";
+        let rows = parse_csv(csv.as_bytes()).unwrap();
+        let rows = rows.into_iter().collect::<csv::Result<Vec<_>>>().unwrap();
+        assert_eq!(rows[0].name, "The Matrix");
+        assert_eq!(rows[0].year, Some(1999));
+        assert_eq!(rows[0].rating, Some(4.5));
+        assert_eq!(rows[0].watched_date, NaiveDate::from_ymd_opt(2024, 1, 31));
+        assert_eq!(rows[1].rating, None);
+    }
+
+    #[test]
+    fn ratings_csv() {
+        let csv = "Date,Name,Year,Letterboxd URI,Rating\n\
+            2023-05-06,Arrival,2016,https://boxd.it/ghi,5\n";
+        let rows = parse_csv(csv.as_bytes()).unwrap();
+        let row = rows.into_iter().next().unwrap().unwrap();
+        assert_eq!(row.date, NaiveDate::from_ymd_opt(2023, 5, 6).unwrap());
+        assert_eq!(row.watched_date, None);
+    }
+}
@@ -0,0 +1,315 @@
+//! Query parameters and helpers shared by the endpoints listing all entities of a kind.
+
+use std::{cmp::Ordering, collections::BTreeSet};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::NaiveDate;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+/// How a set of ids in a filter is matched against the ids of an entity.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quantifier {
+    /// The entity has all of the ids
+    #[default]
+    All,
+    /// The entity has at least one of the ids
+    Any,
+    /// The entity has none of the ids
+    None,
+}
+
+impl Quantifier {
+    /// Returns whether `values` matches `filter`. An empty filter matches everything.
+    pub fn matches<T: Ord>(self, values: &BTreeSet<T>, filter: &BTreeSet<T>) -> bool {
+        if filter.is_empty() {
+            return true;
+        }
+        match self {
+            Quantifier::All => filter.is_subset(values),
+            Quantifier::Any => !filter.is_disjoint(values),
+            Quantifier::None => filter.is_disjoint(values),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn apply(self, ordering: Ordering) -> Ordering {
+        match self {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+}
+
+/// Returns whether `value` lies within the optional bounds.
+pub fn in_range<T: PartialOrd>(value: T, min: Option<T>, max: Option<T>) -> bool {
+    min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+}
+
+/// Value of the field a list is sorted by, before ties are broken by title and id.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum SortValue {
+    /// Sorted by title only
+    None,
+    Date(Option<NaiveDate>),
+    Number(Option<f64>),
+    Text(Option<String>),
+}
+
+/// Position of an entity in a list sorted by [`SortValue`], title and id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SortKey<I> {
+    pub value: SortValue,
+    pub title: String,
+    pub id: I,
+}
+
+impl<I: Ord> SortKey<I> {
+    pub fn compare(&self, other: &Self, order: SortOrder) -> Ordering {
+        let ordering = self
+            .value
+            .partial_cmp(&other.value)
+            .unwrap_or(Ordering::Equal);
+        // ties are sorted by title and id so pages stay stable
+        order.apply(
+            ordering
+                .then_with(|| self.title.cmp(&other.title))
+                .then_with(|| self.id.cmp(&other.id)),
+        )
+    }
+}
+
+/// Selects a page from the results.
+///
+/// `cursor` encodes the sort key of the last item of the previous page and takes precedence over
+/// `offset`. Unlike offsets, cursors stay valid when items are added or removed, including the
+/// last item of the previous page itself. They are only valid for the sort they were created
+/// with.
+#[derive(Debug, Default, Deserialize)]
+pub struct Pagination {
+    #[serde(default)]
+    pub offset: usize,
+    pub cursor: Option<String>,
+    /// Maximum number of items per page, all remaining items if not set
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    /// Number of items matching the filters across all pages
+    pub total: usize,
+    /// Index of the first item of this page
+    pub offset: usize,
+    /// Cursor for the next page, `null` on the last page
+    pub next_cursor: Option<String>,
+    pub items: Vec<T>,
+}
+
+/// Contents of a cursor
+#[derive(Serialize, Deserialize)]
+struct Cursor<K> {
+    /// The sort the cursor was created with
+    sort: Value,
+    /// Key of the last item of the previous page
+    after: K,
+}
+
+impl Pagination {
+    /// Sorts `items` by the keys returned by `key` using `compare` and returns the requested
+    /// page. `sort` describes how `key` and `compare` sort, for example the sort field and order
+    /// from the query. It is stored in cursors together with the keys, so that cursors cannot be
+    /// used with a different sort.
+    pub fn paginate<T, K: Serialize + DeserializeOwned>(
+        &self,
+        items: Vec<T>,
+        sort: impl Serialize,
+        key: impl Fn(&T) -> K,
+        compare: impl Fn(&K, &K) -> Ordering,
+    ) -> Result<Page<T>, String> {
+        if self.limit == Some(0) {
+            return Err("limit must be at least 1".into());
+        }
+        let sort = serde_json::to_value(sort).map_err(|err| format!("invalid sort: {err}"))?;
+        let mut items: Vec<_> = items.into_iter().map(|item| (key(&item), item)).collect();
+        items.sort_by(|(a, _), (b, _)| compare(a, b));
+
+        let total = items.len();
+        let offset = match &self.cursor {
+            Some(cursor) => {
+                let cursor = decode_cursor::<K>(cursor)
+                    .ok_or_else(|| format!("invalid cursor '{cursor}'"))?;
+                if cursor.sort != sort {
+                    return Err("the cursor was created for a different sort".into());
+                }
+                let after = cursor.after;
+                items.partition_point(|(key, _)| compare(key, &after) != Ordering::Greater)
+            }
+            None => self.offset.min(total),
+        };
+        let end = self
+            .limit
+            .map_or(total, |limit| offset.saturating_add(limit).min(total));
+        let next_cursor = match end < total && end > 0 {
+            true => Some(encode_cursor(&Cursor {
+                sort,
+                after: &items[end - 1].0,
+            })?),
+            false => None,
+        };
+        Ok(Page {
+            total,
+            offset,
+            next_cursor,
+            items: items
+                .into_iter()
+                .skip(offset)
+                .take(end - offset)
+                .map(|(_, item)| item)
+                .collect(),
+        })
+    }
+}
+
+fn encode_cursor(key: &impl Serialize) -> Result<String, String> {
+    let json = serde_json::to_vec(key).map_err(|err| format!("failed to create cursor: {err}"))?;
+    Ok(URL_SAFE_NO_PAD.encode(json))
+}
+
+fn decode_cursor<K: DeserializeOwned>(cursor: &str) -> Option<Cursor<K>> {
+    let json = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn page(items: &[u32], pagination: &Pagination) -> Page<u32> {
+        pagination
+            .paginate(items.to_vec(), (), |item| *item, Ord::cmp)
+            .unwrap()
+    }
+
+    #[test]
+    fn pages() {
+        let items: Vec<u32> = (1..=5).collect();
+        let pagination = Pagination {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = page(&items, &pagination);
+        assert_eq!(first.items, [1, 2]);
+        assert!(first.next_cursor.is_some());
+
+        let pagination = Pagination {
+            cursor: first.next_cursor,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let second = page(&items, &pagination);
+        assert_eq!(second.offset, 2);
+        assert_eq!(second.items, [3, 4]);
+
+        let pagination = Pagination {
+            offset: 4,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let last = page(&items, &pagination);
+        assert_eq!(last.items, [5]);
+        assert_eq!(last.next_cursor, None);
+        assert_eq!(last.total, 5);
+    }
+
+    #[test]
+    fn removed_cursor_items() {
+        let pagination = Pagination {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = page(&[5, 1, 3, 2, 4], &pagination);
+        assert_eq!(first.items, [1, 2]);
+
+        // the last item of the first page is gone by the time the second one is requested
+        let pagination = Pagination {
+            cursor: first.next_cursor,
+            limit: Some(2),
+            ..Default::default()
+        };
+        let second = page(&[1, 3, 4, 5], &pagination);
+        assert_eq!(second.offset, 1);
+        assert_eq!(second.items, [3, 4]);
+
+        let pagination = Pagination {
+            cursor: Some("garbage".into()),
+            ..Default::default()
+        };
+        assert!(pagination
+            .paginate(vec![1], (), |item| *item, Ord::cmp)
+            .is_err());
+        let pagination = Pagination {
+            limit: Some(0),
+            ..Default::default()
+        };
+        assert!(pagination
+            .paginate(vec![1], (), |item| *item, Ord::cmp)
+            .is_err());
+    }
+
+    #[test]
+    fn cursor_sort() {
+        let pagination = Pagination {
+            limit: Some(1),
+            ..Default::default()
+        };
+        let sorted_by = |pagination: &Pagination, sort| {
+            pagination.paginate(vec![1, 2, 3], sort, |item| *item, Ord::cmp)
+        };
+        let first = sorted_by(&pagination, ("rating", SortOrder::Desc)).unwrap();
+        let pagination = Pagination {
+            cursor: first.next_cursor,
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            sorted_by(&pagination, ("rating", SortOrder::Desc))
+                .unwrap()
+                .items,
+            [2]
+        );
+        assert!(sorted_by(&pagination, ("score", SortOrder::Desc)).is_err());
+        assert!(sorted_by(&pagination, ("rating", SortOrder::Asc)).is_err());
+    }
+
+    #[test]
+    fn sort_keys() {
+        let key = |value, title: &str, id| SortKey {
+            value: SortValue::Number(value),
+            title: title.into(),
+            id,
+        };
+        let a = key(Some(2.), "B", 1);
+        assert_eq!(
+            a.compare(&key(None, "A", 1), SortOrder::Asc),
+            Ordering::Greater
+        );
+        assert_eq!(
+            a.compare(&key(Some(2.), "C", 1), SortOrder::Asc),
+            Ordering::Less
+        );
+        assert_eq!(
+            a.compare(&key(Some(2.), "B", 0), SortOrder::Desc),
+            Ordering::Less
+        );
+    }
+}
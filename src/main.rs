@@ -1,23 +1,43 @@
-use std::path::Path;
-
 use actix_files::Files;
 use actix_web::{web::Data, App, HttpServer};
-use anyhow::Result;
+use anyhow::{Context, Result};
+use backups::Backups;
 use once_cell::sync::Lazy;
+use rand::Rng;
 use reqwest::Client;
-use schema::AppData;
+use schema::{default_platforms, AppData, User};
+use search::SearchIndex;
+use storage::{Change, Storage};
 use tokio::{fs, sync::Mutex};
 
+mod auth;
+mod backups;
+mod book_import;
+mod config;
+mod events;
 mod getters;
+mod goals;
+mod imdb;
+mod letterboxd;
+mod listing;
+mod migrations;
+mod notify;
 mod openlib;
+mod platforms;
 mod posters;
+mod refresh;
+mod review;
+mod revisions;
 mod schema;
+mod search;
 mod setters;
+mod snapshot;
+mod stats;
+mod storage;
 mod tmdb;
-
-pub const DATA_FILE: &str = "data.json";
-pub const POSTERS_DIR: &str = "posters";
-pub const COVERS_DIR: &str = "covers";
+mod users;
+mod watchlist;
+mod webhooks;
 
 pub static CLIENT: Lazy<Client> = Lazy::new(Client::new);
 pub static TMDB: Lazy<tmdb_api::client::ReqwestClient> = Lazy::new(|| {
@@ -28,39 +48,141 @@ pub static TMDB: Lazy<tmdb_api::client::ReqwestClient> = Lazy::new(|| {
 });
 pub static OPENLIB: Lazy<openlibrsry::Client> = Lazy::new(openlibrsry::Client::new);
 
-pub struct AppState(pub Mutex<AppData>);
+pub struct AppState {
+    pub data: Mutex<AppData>,
+    pub storage: Box<dyn Storage>,
+    pub backups: Backups,
+    pub search: std::sync::Mutex<SearchIndex>,
+}
 
-pub async fn save_to_disk(data: &AppData) -> Result<()> {
-    fs::write(DATA_FILE, serde_json::to_string_pretty(data)?).await?;
-    Ok(())
+impl AppState {
+    /// Persists `change` to the storage, updates the search index and creates a backup if one is
+    /// due.
+    pub async fn save(&self, data: &AppData, change: Change) -> Result<()> {
+        self.search
+            .lock()
+            .expect("mutex should not be poisoned")
+            .update(data, &change);
+        self.storage.save(data, change).await?;
+        if let Err(err) = self.backups.create_if_due(&*self.storage, data).await {
+            eprintln!("failed to create backup: {err:#}");
+        }
+        Ok(())
+    }
 }
 
 #[actix_web::main]
 async fn main() -> Result<()> {
-    let saved_data = fs::read_to_string(DATA_FILE)
+    let config = config::init()?;
+    fs::create_dir_all(&config.data_dir)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to create data directory '{}'",
+                config.data_dir.display()
+            )
+        })?;
+    let storage = storage::open().await?;
+    let mut data = storage.load().await?;
+    // a new installation, SQLite databases already get the default platforms from migrations
+    if data.users.is_empty() {
+        let admin = User {
+            id: 1,
+            name: "admin".into(),
+            admin: true,
+            region: None,
+        };
+        data.users.insert(admin.id, admin);
+        if data.platforms.is_empty() {
+            data.platforms = default_platforms();
+        }
+        storage.save(&data, Change::All).await?;
+    }
+    let admins_without_password = data
+        .users
+        .values()
+        .filter(|user| user.admin)
+        .all(|user| !data.passwords.contains_key(&user.id));
+    if admins_without_password {
+        let admin = data
+            .users
+            .values()
+            .find(|user| user.admin)
+            .expect("there is always an admin")
+            .clone();
+        let password: String = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        data.passwords
+            .insert(admin.id, auth::hash_password(password.clone()).await?);
+        storage.save(&data, Change::Password(admin.id)).await?;
+        println!(
+            "set the password of '{}' to '{password}', change it after logging in",
+            admin.name
+        );
+    }
+    let backups = Backups::new(config.backups_dir(), config.backups);
+    backups.create_if_due(&*storage, &data).await?;
+    for dir in [config.posters_dir(), config.covers_dir()] {
+        fs::create_dir_all(dir.join("small")).await?;
+        fs::create_dir_all(dir.join("big")).await?;
+    }
+    webhooks::resume(&data)
         .await
-        .unwrap_or_else(|_| String::from("{}"));
-    let data = serde_json::from_str(&saved_data)?;
-    fs::create_dir_all(Path::new(POSTERS_DIR).join("small")).await?;
-    fs::create_dir_all(Path::new(POSTERS_DIR).join("big")).await?;
-    fs::create_dir_all(Path::new(COVERS_DIR).join("small")).await?;
-    fs::create_dir_all(Path::new(COVERS_DIR).join("big")).await?;
+        .context("failed to load the webhook deliveries")?;
 
-    let state = Data::new(AppState(Mutex::new(data)));
+    let state = Data::new(AppState {
+        search: std::sync::Mutex::new(SearchIndex::new(&data)),
+        data: Mutex::new(data),
+        storage,
+        backups,
+    });
+    if config.refresh_interval_hours > 0 {
+        actix_web::rt::spawn(refresh::run_periodically(state.clone()));
+    }
     HttpServer::new(move || {
         App::new()
+            .wrap(auth::Authentication)
+            .service(auth::login)
+            .service(auth::logout)
+            .service(auth::change_password)
+            .service(auth::reset_password)
+            .service(auth::get_tokens)
+            .service(auth::post_token)
+            .service(auth::delete_token)
+            .service(users::get_current_user)
+            .service(users::put_region)
+            .service(users::get_users)
+            .service(users::post_user)
+            .service(users::patch_user)
+            .service(users::delete_user)
+            .service(backups::list_backups)
+            .service(backups::create_backup)
+            .service(backups::restore_backup)
             .service(getters::get_all_movies)
             .service(getters::get_all_tags)
             .service(getters::get_all_books)
             .service(getters::get_movie)
             .service(getters::get_book)
+            .service(getters::get_all_series)
+            // must be registered before `get_series` which would also match `next` as an id
+            .service(getters::get_next_episodes)
+            .service(getters::get_series)
             .service(setters::clear_cache)
+            .service(refresh::get_runs)
+            .service(refresh::post_run)
             .service(setters::post_movie)
             .service(setters::patch_movie)
             .service(setters::delete_movie)
             .service(setters::post_tag)
             .service(setters::patch_tag)
             .service(setters::delete_tag)
+            .service(platforms::get_platforms)
+            .service(platforms::post_platform)
+            .service(platforms::patch_platform)
+            .service(platforms::delete_platform)
             .service(setters::movie_put_rating)
             .service(setters::movie_patch_rating)
             .service(setters::movie_delete_rating)
@@ -72,18 +194,64 @@ async fn main() -> Result<()> {
             .service(setters::book_reading_set_for_date)
             .service(setters::book_reading_set_rating)
             .service(setters::book_reading_delete_rating)
+            .service(setters::post_series)
+            .service(setters::patch_series)
+            .service(setters::delete_series)
+            .service(setters::episode_put_watched)
+            .service(setters::episode_delete_watched)
+            .service(setters::episode_put_rating)
+            .service(setters::episode_delete_rating)
+            .service(events::get_events)
+            .service(webhooks::get_webhooks)
+            .service(webhooks::get_deliveries)
+            .service(webhooks::post_webhook)
+            .service(webhooks::patch_webhook)
+            .service(webhooks::delete_webhook)
+            .service(watchlist::get_watchlist)
+            .service(watchlist::post_watchlist)
+            .service(watchlist::patch_watchlist)
+            .service(watchlist::delete_watchlist)
+            .service(goals::get_goals)
+            .service(goals::put_goals)
+            .service(goals::get_progress)
+            .service(notify::get_notifications)
+            .service(notify::put_notifications)
+            .service(notify::test_notifications)
+            .service(notify::get_availability_history)
             .service(posters::get_poster_small)
             .service(posters::get_poster_big)
             .service(posters::get_cover_small)
             .service(posters::get_cover_big)
+            .service(posters::upload_cover_big)
+            .service(book_import::preview)
+            .service(book_import::confirm)
+            .service(imdb::import)
+            .service(imdb::import_status)
+            .service(letterboxd::import)
+            .service(letterboxd::export)
+            .service(snapshot::export)
+            .service(snapshot::import)
+            .service(review::get_review)
+            .service(review::get_review_html)
+            .service(search::search)
+            .service(stats::movies_watched)
+            .service(stats::hours_watched)
+            .service(stats::rating_distribution)
+            .service(stats::platform_share)
+            .service(stats::top_tags)
+            .service(stats::pages_read)
+            .service(stats::books_finished)
             .service(tmdb::search)
             .service(tmdb::by_id)
+            .service(tmdb::search_tv)
+            .service(tmdb::tv_by_id)
             .service(openlib::search)
             .service(openlib::editions)
-            .service(Files::new("/", "./web/dist").index_file("index.html"))
+            .service(Files::new("/", &config.static_dir).index_file("index.html"))
             .app_data(state.clone())
     })
-    .bind(("0.0.0.0", 19283))?
+    .bind((config.address, config.port))
+    .with_context(|| format!("failed to listen on {}:{}", config.address, config.port))?
     .run()
     .await?;
     Ok(())
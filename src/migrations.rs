@@ -0,0 +1,455 @@
+//! Upgrades serialized [`AppData`](crate::schema::AppData) written by older versions to the
+//! current format.
+//!
+//! Every data file carries a `schema_version` field (files without one are version 0). When a
+//! file is loaded, all migrations between its version and [`SCHEMA_VERSION`] are applied in
+//! order on the raw JSON before it is deserialized.
+//!
+//! To change the shape of the stored data, bump [`SCHEMA_VERSION`], append a migration to
+//! [`MIGRATIONS`] and add the expected output as a new fixture in `fixtures/migrations/`.
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+
+/// Version of the data format written by this version of entrackment
+pub const SCHEMA_VERSION: u64 = 12;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<()>;
+
+/// `MIGRATIONS[n]` upgrades data from version `n` to version `n + 1`
+const MIGRATIONS: &[Migration] = &[
+    v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7, v7_to_v8, v8_to_v9,
+    v9_to_v10, v10_to_v11, v11_to_v12,
+];
+
+/// Upgrades `value` to [`SCHEMA_VERSION`] in place.
+pub fn migrate(value: &mut Value) -> Result<()> {
+    let Some(object) = value.as_object_mut() else {
+        bail!("expected data to be a JSON object");
+    };
+    let version = match object.get("schema_version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .with_context(|| format!("invalid schema version {version}"))?,
+    };
+    if version > SCHEMA_VERSION {
+        bail!(
+            "data has schema version {version}, but this version of entrackment only supports \
+             up to version {SCHEMA_VERSION}"
+        );
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(object).with_context(|| {
+            format!("failed to migrate data from version {from} to {}", from + 1)
+        })?;
+        object.insert("schema_version".into(), json!(from + 1));
+    }
+    Ok(())
+}
+
+/// Parses and migrates data of any known schema version.
+///
+/// `T` is usually [`AppData`](crate::schema::AppData), but may also be any other struct
+/// consisting of a subset of its fields.
+pub fn from_str<T: DeserializeOwned>(json: &str) -> Result<T> {
+    let mut value = serde_json::from_str(json)?;
+    migrate(&mut value)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Serializes `data` including the current `schema_version`.
+///
+/// `data` must serialize to a JSON object.
+pub fn to_string_pretty(data: &impl Serialize) -> Result<String> {
+    let mut value = serde_json::to_value(data)?;
+    let Some(object) = value.as_object_mut() else {
+        bail!("expected data to be serialized as a JSON object");
+    };
+    object.insert("schema_version".into(), json!(SCHEMA_VERSION));
+    Ok(serde_json::to_string_pretty(&value)?)
+}
+
+/// Calls `f` for all ratings of movies and book readings.
+fn for_each_rating(data: &mut Map<String, Value>, mut f: impl FnMut(&mut Map<String, Value>)) {
+    let movie_ratings = data
+        .get_mut("movies")
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flat_map(|movies| movies.values_mut())
+        .filter_map(|movie| movie.get_mut("ratings")?.as_array_mut())
+        .flatten();
+    movie_ratings
+        .filter_map(Value::as_object_mut)
+        .for_each(&mut f);
+
+    let reading_ratings = data
+        .get_mut("books")
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flat_map(|books| books.values_mut())
+        .filter_map(|book| book.get_mut("readings")?.as_array_mut())
+        .flatten()
+        .filter_map(|reading| reading.get_mut("rating"));
+    reading_ratings.filter_map(Value::as_object_mut).for_each(f);
+}
+
+/// Version 0 relied on serde defaults for missing collections and rating fields. Version 1
+/// always writes them explicitly.
+fn v0_to_v1(data: &mut Map<String, Value>) -> Result<()> {
+    for key in ["movies", "tags", "tmdb_cache", "books"] {
+        data.entry(key).or_insert_with(|| json!({}));
+    }
+    for_each_rating(data, |rating| {
+        rating.entry("speed").or_insert(json!(1.0));
+        rating.entry("tags").or_insert(json!([]));
+    });
+    Ok(())
+}
+
+/// Version 2 added reading goals.
+fn v1_to_v2(data: &mut Map<String, Value>) -> Result<()> {
+    data.insert(
+        "goals".into(),
+        json!({
+            "yearly_books": {},
+            "daily_pages": null,
+            "daily_minutes": null,
+            "pages_per_hour": 30.0,
+        }),
+    );
+    Ok(())
+}
+
+/// Version 3 added the watchlist.
+fn v2_to_v3(data: &mut Map<String, Value>) -> Result<()> {
+    data.insert("watchlist".into(), json!({}));
+    Ok(())
+}
+
+/// Version 4 added TV series and their TMDB cache.
+fn v3_to_v4(data: &mut Map<String, Value>) -> Result<()> {
+    data.insert("series".into(), json!({}));
+    data.insert("tmdb_tv_cache".into(), json!({}));
+    Ok(())
+}
+
+/// Returns the values of the object at `key` in `data`.
+fn values_mut<'a>(
+    data: &'a mut Map<String, Value>,
+    key: &str,
+) -> impl Iterator<Item = &'a mut Map<String, Value>> {
+    data.get_mut(key)
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flat_map(|object| object.values_mut())
+        .filter_map(Value::as_object_mut)
+}
+
+/// Version 5 added users. Ratings, readings, tags, the watchlist and the goals now belong to a
+/// user, everything existing is assigned to an initial admin with the id 1.
+fn v4_to_v5(data: &mut Map<String, Value>) -> Result<()> {
+    data.insert(
+        "users".into(),
+        json!({ "1": { "id": 1, "name": "admin", "admin": true } }),
+    );
+    for_each_rating(data, |rating| {
+        rating.insert("user".into(), json!(1));
+    });
+    let readings = values_mut(data, "books")
+        .filter_map(|book| book.get_mut("readings")?.as_array_mut())
+        .flatten()
+        .filter_map(Value::as_object_mut);
+    for reading in readings {
+        reading.insert("user".into(), json!(1));
+    }
+    for tag in values_mut(data, "tags") {
+        tag.insert("user".into(), json!(1));
+    }
+    if let Some(goals) = data.remove("goals") {
+        data.insert("goals".into(), json!({ "1": goals }));
+    }
+    if let Some(watchlist) = data.remove("watchlist") {
+        data.insert("watchlists".into(), json!({ "1": watchlist }));
+    }
+
+    for key in ["series", "tmdb_tv_cache"] {
+        let episodes = values_mut(data, key)
+            .filter_map(|series| series.get_mut("seasons")?.as_array_mut())
+            .flatten()
+            .filter_map(|season| season.get_mut("episodes")?.as_array_mut())
+            .flatten()
+            .filter_map(Value::as_object_mut);
+        for episode in episodes {
+            let watched = match episode.remove("watched") {
+                Some(Value::Array(dates)) if !dates.is_empty() => json!({ "1": dates }),
+                _ => json!({}),
+            };
+            episode.insert("watched".into(), watched);
+            let ratings = episode
+                .get_mut("ratings")
+                .and_then(Value::as_array_mut)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_object_mut);
+            for rating in ratings {
+                rating.insert("user".into(), json!(1));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Version 6 added passwords and API tokens. Existing users have no password yet.
+fn v5_to_v6(data: &mut Map<String, Value>) -> Result<()> {
+    data.insert("passwords".into(), json!({}));
+    data.insert("api_tokens".into(), json!({}));
+    Ok(())
+}
+
+/// Version 7 added the region of users and the offers of movies and series in every tier and in
+/// multiple regions. The old offers do not say which region they are from, so they are dropped
+/// and fetched again by the next refresh.
+fn v6_to_v7(data: &mut Map<String, Value>) -> Result<()> {
+    for user in values_mut(data, "users") {
+        user.insert("region".into(), Value::Null);
+    }
+    for key in ["movies", "tmdb_cache", "series", "tmdb_tv_cache"] {
+        for entry in values_mut(data, key) {
+            entry.insert("availability".into(), json!({}));
+        }
+    }
+    Ok(())
+}
+
+/// A formerly built-in platform: its name, new id, colour, TMDB provider ids and the TMDB provider
+/// which offers in older data are attributed to
+type BuiltinPlatform = (
+    &'static str,
+    u32,
+    [u8; 3],
+    &'static [u64],
+    Option<(u64, &'static str)>,
+);
+
+const BUILTIN_PLATFORMS: [BuiltinPlatform; 12] = [
+    (
+        "Disney+",
+        1,
+        [17, 60, 207],
+        &[337],
+        Some((337, "Disney Plus")),
+    ),
+    ("Jellyfin", 2, [170, 92, 195], &[], None),
+    (
+        "Netflix",
+        3,
+        [229, 9, 20],
+        &[8, 175, 1796],
+        Some((8, "Netflix")),
+    ),
+    (
+        "Prime Video",
+        4,
+        [0, 168, 225],
+        &[9, 10, 119, 2100],
+        Some((9, "Amazon Prime Video")),
+    ),
+    (
+        "YouTube",
+        5,
+        [255, 0, 0],
+        &[188, 192, 235],
+        Some((192, "YouTube")),
+    ),
+    ("DVD", 6, [110, 110, 110], &[], None),
+    ("BluRay", 7, [0, 144, 206], &[], None),
+    ("Cinema", 8, [180, 30, 60], &[], None),
+    ("TV", 9, [90, 90, 90], &[], None),
+    ("Airplane", 10, [70, 130, 180], &[], None),
+    ("Apple TV", 11, [0, 0, 0], &[2, 350], Some((2, "Apple TV"))),
+    ("Stan", 12, [0, 114, 206], &[21], Some((21, "Stan"))),
+];
+
+/// Version 8 replaced the built-in platforms with platforms stored like tags. Ratings and movies
+/// refer to them by id and offers refer to TMDB providers.
+fn v7_to_v8(data: &mut Map<String, Value>) -> Result<()> {
+    let builtin = |name: &Value| {
+        BUILTIN_PLATFORMS
+            .iter()
+            .find(|platform| Some(platform.0) == name.as_str())
+    };
+    let platforms: Map<_, _> = BUILTIN_PLATFORMS
+        .iter()
+        .map(|&(name, id, color, providers, _)| {
+            let platform = json!({
+                "id": id,
+                "name": name,
+                "color": color,
+                "icon": null,
+                "tmdb_providers": providers,
+            });
+            (id.to_string(), platform)
+        })
+        .collect();
+    data.insert("platforms".into(), Value::Object(platforms));
+
+    let platform_id = |rating: &mut Map<String, Value>| {
+        if let Some(platform) = rating.get_mut("platform") {
+            *platform = builtin(platform).map_or(Value::Null, |platform| json!(platform.1));
+        }
+    };
+    for_each_rating(data, platform_id);
+    for key in ["series", "tmdb_tv_cache"] {
+        let ratings = values_mut(data, key)
+            .filter_map(|series| series.get_mut("seasons")?.as_array_mut())
+            .flatten()
+            .filter_map(|season| season.get_mut("episodes")?.as_array_mut())
+            .flatten()
+            .filter_map(|episode| episode.get_mut("ratings")?.as_array_mut())
+            .flatten()
+            .filter_map(Value::as_object_mut);
+        ratings.for_each(platform_id);
+    }
+
+    for key in ["movies", "tmdb_cache", "series", "tmdb_tv_cache"] {
+        for entry in values_mut(data, key) {
+            if let Some(Value::Array(names)) = entry.get_mut("platforms") {
+                *names = names
+                    .iter()
+                    .filter_map(|name| Some(json!(builtin(name)?.1)))
+                    .collect();
+            }
+            let tiers = entry
+                .get_mut("availability")
+                .and_then(Value::as_object_mut)
+                .into_iter()
+                .flat_map(|regions| regions.values_mut())
+                .filter_map(Value::as_object_mut)
+                .flat_map(|tiers| tiers.values_mut())
+                .filter_map(Value::as_array_mut);
+            for providers in tiers {
+                *providers = providers
+                    .iter()
+                    .filter_map(|provider| {
+                        // providers without a platform already have the new format
+                        if provider.is_object() {
+                            return Some(provider.clone());
+                        }
+                        let (tmdb_id, name) = builtin(provider)?.4?;
+                        Some(json!({ "tmdb_id": tmdb_id, "name": name }))
+                    })
+                    .collect();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Version 9 added the time when TMDB cache entries were fetched. Existing entries are wrapped and
+/// count as expired.
+fn v8_to_v9(data: &mut Map<String, Value>) -> Result<()> {
+    for key in ["tmdb_cache", "tmdb_tv_cache"] {
+        let entries = data
+            .get_mut(key)
+            .and_then(Value::as_object_mut)
+            .into_iter()
+            .flat_map(|object| object.values_mut());
+        for entry in entries {
+            *entry = json!({ "value": entry.take(), "fetched": "1970-01-01T00:00:00Z" });
+        }
+    }
+    Ok(())
+}
+
+/// Version 10 added the availability history of movies and the notification settings of users.
+fn v9_to_v10(data: &mut Map<String, Value>) -> Result<()> {
+    data.insert("availability_history".into(), json!({}));
+    data.insert("notifications".into(), json!({}));
+    Ok(())
+}
+
+/// Version 11 added outgoing webhooks.
+fn v10_to_v11(data: &mut Map<String, Value>) -> Result<()> {
+    data.insert("webhooks".into(), json!({}));
+    Ok(())
+}
+
+/// Version 12 added revisions to movies, tags and books, which start at 0.
+fn v11_to_v12(data: &mut Map<String, Value>) -> Result<()> {
+    for key in ["movies", "tags", "books"] {
+        for entry in values_mut(data, key) {
+            entry.insert("revision".into(), json!(0));
+        }
+    }
+    let cached =
+        values_mut(data, "tmdb_cache").filter_map(|entry| entry.get_mut("value")?.as_object_mut());
+    for movie in cached {
+        movie.insert("revision".into(), json!(0));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::schema::AppData;
+
+    fn fixture(version: u64) -> Value {
+        let path = format!(
+            "{}/fixtures/migrations/v{version}.json",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let json = std::fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("could not read fixture '{path}': {err}"));
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn every_version_has_a_migration() {
+        assert_eq!(MIGRATIONS.len() as u64, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn single_steps() {
+        for from in 0..SCHEMA_VERSION {
+            let mut value = fixture(from);
+            let object = value.as_object_mut().unwrap();
+            // the version number is updated by `migrate`, not the single steps
+            object.remove("schema_version");
+            MIGRATIONS[from as usize](object).unwrap();
+            let mut expected = fixture(from + 1);
+            expected.as_object_mut().unwrap().remove("schema_version");
+            assert_eq!(
+                value,
+                expected,
+                "migration from version {from} to {}",
+                from + 1
+            );
+        }
+    }
+
+    #[test]
+    fn all_versions_load() {
+        for version in 0..=SCHEMA_VERSION {
+            let mut value = fixture(version);
+            migrate(&mut value).unwrap();
+            assert_eq!(value, fixture(SCHEMA_VERSION));
+            serde_json::from_value::<AppData>(value).unwrap();
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data: AppData = from_str(&fixture(SCHEMA_VERSION).to_string()).unwrap();
+        let json: Value = serde_json::from_str(&to_string_pretty(&data).unwrap()).unwrap();
+        assert_eq!(json, fixture(SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut value = json!({ "schema_version": SCHEMA_VERSION + 1 });
+        assert!(migrate(&mut value).is_err());
+    }
+}
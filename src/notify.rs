@@ -0,0 +1,627 @@
+//! Notifications about movies on the watchlist becoming available on or leaving a platform.
+//!
+//! When the [background refresh](crate::refresh) finds changed offers of a tracked movie, the
+//! platforms it was added to or removed from are appended to its availability history. Users who
+//! have the movie on their watchlist and subscribe to the platform are then notified through all of
+//! their [`Channel`]s if the change happened in their region.
+//!
+//! Webhooks and ntfy servers on this host or in private networks are rejected, unless
+//! `private_notification_urls` is enabled in the [config](crate::config).
+
+use std::{collections::BTreeMap, net::IpAddr, time::Duration};
+
+use actix_web::{
+    get, post, put,
+    web::{Data, Json, Path},
+    HttpResponse, Responder,
+};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use once_cell::sync::Lazy;
+use reqwest::{redirect, Client, Url};
+use serde::Serialize;
+use tokio::net::lookup_host;
+
+use crate::{
+    config::config,
+    schema::{
+        provider_platforms, AppData, Availability, AvailabilityChange, AvailabilityEvent, Channel,
+        Notifications, Platform,
+    },
+    storage::Change,
+    users::CurrentUser,
+    AppState,
+};
+
+/// Time after which sending a notification is aborted
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Client for webhooks and ntfy servers. It does not follow redirects, which could lead to URLs
+/// that are not allowed.
+static CLIENT: Lazy<Client> = Lazy::new(|| {
+    Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(redirect::Policy::none())
+        .build()
+        .expect("the TLS backend can be initialized")
+});
+
+/// Changes of the platforms with subscriptions between the `old` and `new` offers. Regions which
+/// are missing in either of them are skipped, because their offers were not known before or are
+/// not fetched anymore.
+pub fn availability_events(
+    old: &BTreeMap<String, Availability>,
+    new: &BTreeMap<String, Availability>,
+    platforms: &BTreeMap<u32, Platform>,
+) -> Vec<AvailabilityEvent> {
+    let time = Utc::now();
+    let mut events = vec![];
+    for (region, new_offers) in new {
+        let Some(old_offers) = old.get(region) else {
+            continue;
+        };
+        let old_platforms = provider_platforms(&old_offers.flatrate, platforms);
+        let new_platforms = provider_platforms(&new_offers.flatrate, platforms);
+        let event = |platform: &u32, change| AvailabilityEvent {
+            time,
+            region: region.clone(),
+            platform: *platform,
+            change,
+        };
+        events.extend(
+            new_platforms
+                .difference(&old_platforms)
+                .map(|platform| event(platform, AvailabilityChange::Added)),
+        );
+        events.extend(
+            old_platforms
+                .difference(&new_platforms)
+                .map(|platform| event(platform, AvailabilityChange::Removed)),
+        );
+    }
+    events
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notification {
+    Availability {
+        tmdb_id: u64,
+        title: String,
+        region: String,
+        platform: u32,
+        platform_name: String,
+        change: AvailabilityChange,
+        time: DateTime<Utc>,
+    },
+    /// Sent on request to check that the channels work
+    Test,
+}
+
+impl Notification {
+    /// Human readable text of the notification.
+    fn message(&self) -> String {
+        match self {
+            Notification::Availability {
+                title,
+                region,
+                platform_name,
+                change: AvailabilityChange::Added,
+                ..
+            } => format!("{title} is now available on {platform_name} in {region}"),
+            Notification::Availability {
+                title,
+                region,
+                platform_name,
+                change: AvailabilityChange::Removed,
+                ..
+            } => format!("{title} is no longer available on {platform_name} in {region}"),
+            Notification::Test => "Notifications from entrackment work".into(),
+        }
+    }
+}
+
+/// Notifications about the `events` of the movie with the TMDB id `id`, together with the channels
+/// they are sent to.
+pub fn notifications(
+    data: &AppData,
+    id: u64,
+    events: &[AvailabilityEvent],
+) -> Vec<(Vec<Channel>, Notification)> {
+    let Some(movie) = data.movies.get(&id) else {
+        return vec![];
+    };
+    let mut notifications = vec![];
+    for (user, settings) in &data.notifications {
+        if !data
+            .watchlists
+            .get(user)
+            .is_some_and(|watchlist| watchlist.contains_key(&id))
+        {
+            continue;
+        }
+        let region = data
+            .users
+            .get(user)
+            .and_then(|user| user.region.as_deref())
+            .unwrap_or(&config().region);
+        let events = events
+            .iter()
+            .filter(|event| event.region == region && settings.platforms.contains(&event.platform));
+        for event in events {
+            let platform_name = data
+                .platforms
+                .get(&event.platform)
+                .map_or_else(|| "Unknown".into(), |platform| platform.name.clone());
+            let notification = Notification::Availability {
+                tmdb_id: id,
+                title: movie.title.clone(),
+                region: event.region.clone(),
+                platform: event.platform,
+                platform_name,
+                change: event.change,
+                time: event.time,
+            };
+            notifications.push((settings.channels.clone(), notification));
+        }
+    }
+    notifications
+}
+
+/// Body of webhook requests
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    #[serde(flatten)]
+    notification: &'a Notification,
+    message: String,
+}
+
+/// Whether `ip` belongs to this host or a private network.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // shared address space of carrier-grade NATs
+                || (first == 100 && second & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // unique local addresses
+                    || first & 0xfe00 == 0xfc00
+                    // link-local addresses
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Checks that `url` is an HTTP URL, which neither points to this host nor to a private network
+/// unless `allow_private` is set.
+async fn check_url(url: &str, allow_private: bool) -> Result<()> {
+    let url = Url::parse(url)?;
+    if !["http", "https"].contains(&url.scheme()) {
+        bail!("unsupported scheme '{}'", url.scheme());
+    }
+    if allow_private {
+        return Ok(());
+    }
+    let Some(host) = url.host_str() else {
+        bail!("URL has no host");
+    };
+    let addresses: Vec<IpAddr> = match host.trim_matches(['[', ']']).parse() {
+        Ok(ip) => vec![ip],
+        Err(_) => {
+            let port = url.port_or_known_default().unwrap_or(80);
+            lookup_host((host, port))
+                .await?
+                .map(|addr| addr.ip())
+                .collect()
+        }
+    };
+    if let Some(ip) = addresses.into_iter().find(|&ip| is_private(ip)) {
+        bail!("{ip} is on this host or in a private network");
+    }
+    Ok(())
+}
+
+/// Sends `notification` through `channel`. Webhooks and ntfy servers are checked again, because
+/// the addresses of their hosts might have changed, unless `allow_private` is set.
+async fn send(channel: &Channel, notification: &Notification, allow_private: bool) -> Result<()> {
+    let message = notification.message();
+    match channel {
+        Channel::Webhook { url } => {
+            check_url(url, allow_private).await?;
+            let payload = WebhookPayload {
+                notification,
+                message,
+            };
+            CLIENT
+                .post(url)
+                .json(&payload)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        Channel::Ntfy { url } => {
+            check_url(url, allow_private).await?;
+            CLIENT
+                .post(url)
+                .header("Title", "entrackment")
+                .body(message)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        Channel::Email { address } => {
+            let config = config();
+            let (Some(smtp_url), Some(from)) = (&config.smtp_url, &config.smtp_from) else {
+                bail!("no SMTP server configured");
+            };
+            send_email(smtp_url, from, address, message).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends `message` from `from` to `address` through the SMTP server at `smtp_url`.
+async fn send_email(smtp_url: &str, from: &str, address: &str, message: String) -> Result<()> {
+    let email = Message::builder()
+        .from(from.parse()?)
+        .to(address.parse()?)
+        .subject(&message)
+        .body(message)?;
+    AsyncSmtpTransport::<Tokio1Executor>::from_url(smtp_url)?
+        .timeout(Some(REQUEST_TIMEOUT))
+        .build()
+        .send(email)
+        .await?;
+    Ok(())
+}
+
+/// Sends all `notifications` through their channels, see [`send`]. Failures are only logged,
+/// because nobody would be waiting for them.
+pub async fn deliver(notifications: Vec<(Vec<Channel>, Notification)>, allow_private: bool) {
+    for (channels, notification) in notifications {
+        for channel in &channels {
+            if let Err(err) = send(channel, &notification, allow_private).await {
+                eprintln!("failed to send notification to {channel:?}: {err:#}");
+            }
+        }
+    }
+}
+
+/// Checks that the URLs of all webhooks and ntfy servers are allowed, see [`check_url`].
+async fn validate_urls(notifications: &Notifications, allow_private: bool) -> Result<(), String> {
+    for channel in &notifications.channels {
+        if let Channel::Webhook { url } | Channel::Ntfy { url } = channel {
+            if let Err(err) = check_url(url, allow_private).await {
+                return Err(format!("invalid URL '{url}': {err:#}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that all platforms exist and that the email channels can be used.
+fn validate(data: &AppData, notifications: &Notifications) -> Result<(), String> {
+    if let Some(id) = notifications
+        .platforms
+        .iter()
+        .find(|id| !data.platforms.contains_key(id))
+    {
+        return Err(format!("platform with ID {id} does not exist"));
+    }
+    for channel in &notifications.channels {
+        match channel {
+            Channel::Webhook { .. } | Channel::Ntfy { .. } => {}
+            Channel::Email { address } => {
+                if address.parse::<lettre::Address>().is_err() {
+                    return Err(format!("invalid email address '{address}'"));
+                }
+                if config().smtp_url.is_none() {
+                    return Err("email notifications require an SMTP server".into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[get("/api/notifications")]
+async fn get_notifications(state: Data<AppState>, user: CurrentUser) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    HttpResponse::Ok().json(
+        data_lock
+            .notifications
+            .get(&user.id)
+            .cloned()
+            .unwrap_or_default(),
+    )
+}
+
+#[put("/api/notifications")]
+async fn put_notifications(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(notifications): Json<Notifications>,
+) -> impl Responder {
+    // resolving hosts may take a while, so it is done before locking the data
+    if let Err(err) = validate_urls(&notifications, config().private_notification_urls).await {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let mut data_lock = state.data.lock().await;
+    if let Err(err) = validate(&data_lock, &notifications) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    data_lock.notifications.insert(user.id, notifications);
+    match state.save(&data_lock, Change::Notifications(user.id)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[derive(Serialize)]
+struct TestResult {
+    channel: Channel,
+    /// Why sending the notification failed, `null` if it was sent
+    error: Option<String>,
+}
+
+/// Sends a test notification through all channels of the current user.
+#[post("/api/notifications/test")]
+async fn test_notifications(state: Data<AppState>, user: CurrentUser) -> impl Responder {
+    let channels = state
+        .data
+        .lock()
+        .await
+        .notifications
+        .get(&user.id)
+        .map(|notifications| notifications.channels.clone())
+        .unwrap_or_default();
+    let mut results = vec![];
+    for channel in channels {
+        let error = send(
+            &channel,
+            &Notification::Test,
+            config().private_notification_urls,
+        )
+        .await
+        .err();
+        results.push(TestResult {
+            channel,
+            error: error.map(|err| format!("{err:#}")),
+        });
+    }
+    HttpResponse::Ok().json(results)
+}
+
+/// The changes of the platforms on which a tracked movie is available, from oldest to newest.
+#[get("/api/movie/{id}/availability")]
+async fn get_availability_history(
+    state: Data<AppState>,
+    _: CurrentUser,
+    id: Path<u64>,
+) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    if !data_lock.movies.contains_key(&id) {
+        return HttpResponse::NotFound().body(format!("movie with ID {id} does not exist"));
+    }
+    let history = data_lock.availability_history.get(&id);
+    HttpResponse::Ok().json(history.cloned().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+    use crate::schema::{default_platforms, Provider};
+
+    /// Starts an HTTP server on a free local port which answers a single request with `200 OK`.
+    /// Returns its URL and a handle resolving to the received request.
+    fn http_stub() -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/topic", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+            reader
+                .into_inner()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            request
+        });
+        (url, handle)
+    }
+
+    /// Starts an SMTP server on a free local port which accepts a single email. Returns its URL and
+    /// a handle resolving to the commands and the data it received.
+    fn smtp_stub() -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("smtp://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"220 stub ESMTP\r\n").unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+                let reply: &[u8] = if in_data {
+                    if line != ".\r\n" {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    stream.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                stream.write_all(reply).unwrap();
+            }
+            received
+        });
+        (url, handle)
+    }
+
+    fn availability_notification() -> Notification {
+        Notification::Availability {
+            tmdb_id: 603,
+            title: "The Matrix".into(),
+            region: "DE".into(),
+            platform: 3,
+            platform_name: "Netflix".into(),
+            change: AvailabilityChange::Added,
+            time: DateTime::from_timestamp(0, 0).unwrap(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn webhook() {
+        let (url, request) = http_stub();
+        send(
+            &Channel::Webhook { url },
+            &availability_notification(),
+            true,
+        )
+        .await
+        .unwrap();
+        let request = request.join().unwrap();
+        assert!(request.starts_with("POST /topic HTTP/1.1\r\n"));
+        assert!(request.contains("content-type: application/json\r\n"));
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["type"], "availability");
+        assert_eq!(body["tmdb_id"], 603);
+        assert_eq!(body["change"], "added");
+        assert_eq!(
+            body["message"],
+            "The Matrix is now available on Netflix in DE"
+        );
+    }
+
+    #[actix_web::test]
+    async fn deliver_to_every_channel() {
+        let (webhook_url, webhook_request) = http_stub();
+        let (ntfy_url, ntfy_request) = http_stub();
+        let channels = vec![
+            Channel::Webhook { url: webhook_url },
+            Channel::Ntfy { url: ntfy_url },
+        ];
+        deliver(vec![(channels, Notification::Test)], true).await;
+        assert!(webhook_request.join().unwrap().contains(r#""type":"test""#));
+        let request = ntfy_request.join().unwrap();
+        assert!(request.starts_with("POST /topic HTTP/1.1\r\n"));
+        assert!(request.contains("title: entrackment\r\n"));
+        assert!(request.ends_with("\r\n\r\nNotifications from entrackment work"));
+    }
+
+    #[actix_web::test]
+    async fn private_urls() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "https://10.1.2.3/",
+            "http://192.168.178.1/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:127.0.0.1]/",
+        ] {
+            assert!(check_url(url, false).await.is_err(), "{url}");
+            assert!(check_url(url, true).await.is_ok(), "{url}");
+        }
+        assert!(check_url("https://93.184.215.14/hook", false).await.is_ok());
+        assert!(check_url("http://[2606:4700::1111]/", false).await.is_ok());
+        assert!(check_url("ftp://93.184.215.14/", true).await.is_err());
+        assert!(check_url("not a url", true).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn email() {
+        let (url, received) = smtp_stub();
+        let message = availability_notification().message();
+        send_email(&url, "entrackment@example.com", "user@example.com", message)
+            .await
+            .unwrap();
+        let received = received.join().unwrap();
+        assert!(received.contains("MAIL FROM:<entrackment@example.com>"));
+        assert!(received.contains("RCPT TO:<user@example.com>"));
+        assert!(received.contains("Subject: The Matrix is now available on Netflix in DE\r\n"));
+        assert!(received.contains("\r\n\r\nThe Matrix is now available on Netflix in DE\r\n"));
+    }
+
+    #[test]
+    fn events() {
+        let offers = |providers: &[u64]| Availability {
+            flatrate: providers
+                .iter()
+                .map(|&tmdb_id| Provider {
+                    tmdb_id,
+                    name: String::new(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        // Netflix is replaced by Disney+ in Germany, Austria was not fetched before
+        let old = BTreeMap::from([("DE".to_owned(), offers(&[8]))]);
+        let new = BTreeMap::from([
+            ("DE".to_owned(), offers(&[337])),
+            ("AT".to_owned(), offers(&[8])),
+        ]);
+        let events = availability_events(&old, &new, &default_platforms());
+        let events: Vec<_> = events
+            .iter()
+            .map(|event| (event.region.as_str(), event.platform, event.change))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("DE", 1, AvailabilityChange::Added),
+                ("DE", 3, AvailabilityChange::Removed)
+            ]
+        );
+    }
+}
@@ -7,7 +7,7 @@ use openlibrsry::{
     OlId,
 };
 
-use crate::OPENLIB;
+use crate::{users::CurrentUser, OPENLIB};
 
 #[derive(serde::Deserialize)]
 struct SearchQuery {
@@ -15,7 +15,10 @@ struct SearchQuery {
 }
 
 #[get("/api/openlib/search")]
-async fn search(Query(SearchQuery { title }): Query<SearchQuery>) -> impl Responder {
+async fn search(
+    _: CurrentUser,
+    Query(SearchQuery { title }): Query<SearchQuery>,
+) -> impl Responder {
     if title.trim_end().is_empty() {
         return HttpResponse::Ok().json(Vec::<Document>::new());
     }
@@ -41,7 +44,10 @@ struct EditionsQuery {
 }
 
 #[get("/api/openlib/editions")]
-async fn editions(Query(EditionsQuery { work }): Query<EditionsQuery>) -> impl Responder {
+async fn editions(
+    _: CurrentUser,
+    Query(EditionsQuery { work }): Query<EditionsQuery>,
+) -> impl Responder {
     let editions = match OPENLIB
         .execute(
             WorksEditionsBuilder::default()
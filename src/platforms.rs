@@ -0,0 +1,133 @@
+//! Platforms on which movies and series are watched, see [`Platform`].
+//!
+//! Platforms are shared by all users, so only admins may change them.
+
+use actix_web::{
+    delete, get, patch, post,
+    web::{Data, Json, Path},
+    HttpResponse, Responder,
+};
+
+use crate::{
+    schema::{AppData, Platform},
+    storage::Change,
+    users::{Admin, CurrentUser},
+    AppState,
+};
+
+#[get("/api/platform")]
+async fn get_platforms(state: Data<AppState>, _: CurrentUser) -> impl Responder {
+    HttpResponse::Ok().json(&state.data.lock().await.platforms)
+}
+
+/// Checks that no other platform than the one with `id` has the same name or one of the same TMDB
+/// providers, because offers could not be attributed to a single platform otherwise.
+fn check_conflicts(data: &AppData, platform: &Platform, id: u32) -> Result<(), HttpResponse> {
+    for other in data.platforms.values().filter(|other| other.id != id) {
+        if other.name == platform.name {
+            return Err(HttpResponse::Conflict().body("a platform with that name already exists"));
+        }
+        if let Some(provider) = other
+            .tmdb_providers
+            .intersection(&platform.tmdb_providers)
+            .next()
+        {
+            return Err(HttpResponse::Conflict().body(format!(
+                "TMDB provider {provider} already belongs to '{}'",
+                other.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[post("/api/platform")]
+async fn post_platform(
+    state: Data<AppState>,
+    _: Admin,
+    Json(platform): Json<Platform>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = loop {
+        let new_id = rand::random::<u32>();
+        if !data_lock.platforms.contains_key(&new_id) {
+            break new_id;
+        }
+    };
+    if let Err(resp) = check_conflicts(&data_lock, &platform, id) {
+        return resp;
+    }
+    let new_platform = Platform { id, ..platform };
+    let resp = HttpResponse::Ok().json(&new_platform);
+    data_lock.platforms.insert(id, new_platform);
+    match state.save(&data_lock, Change::Platform(id)).await {
+        Ok(()) => resp,
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[patch("/api/platform")]
+async fn patch_platform(
+    state: Data<AppState>,
+    _: Admin,
+    Json(platform): Json<Platform>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = platform.id;
+    if !data_lock.platforms.contains_key(&id) {
+        return HttpResponse::NotFound().body(format!("platform with ID {id} does not exist"));
+    }
+    if let Err(resp) = check_conflicts(&data_lock, &platform, id) {
+        return resp;
+    }
+    data_lock.platforms.insert(id, platform);
+    match state.save(&data_lock, Change::Platform(id)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+/// Deletes a platform and removes it from all movies, series and subscriptions. Platforms which
+/// are used by any rating can't be deleted.
+#[delete("/api/platform/{id}")]
+async fn delete_platform(state: Data<AppState>, _: Admin, id: Path<u32>) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    if !data_lock.platforms.contains_key(&id) {
+        return HttpResponse::NotFound().body(format!("platform with ID {id} does not exist"));
+    }
+    let movie_ratings = data_lock.movies.values().flat_map(|movie| &movie.ratings);
+    let reading_ratings = data_lock
+        .books
+        .values()
+        .flat_map(|book| &book.readings)
+        .filter_map(|reading| reading.rating.as_ref());
+    let episode_ratings = data_lock
+        .series
+        .values()
+        .flat_map(|series| &series.seasons)
+        .flat_map(|season| &season.episodes)
+        .flat_map(|episode| &episode.ratings);
+    if movie_ratings
+        .chain(reading_ratings)
+        .chain(episode_ratings)
+        .any(|rating| rating.platform == Some(*id))
+    {
+        return HttpResponse::Conflict().body("the platform is still used by ratings");
+    }
+    data_lock.platforms.remove(&id);
+    for movie in data_lock.movies.values_mut() {
+        if movie.platforms.remove(&id) {
+            movie.revision += 1;
+        }
+    }
+    for series in data_lock.series.values_mut() {
+        series.platforms.remove(&id);
+    }
+    for notifications in data_lock.notifications.values_mut() {
+        notifications.platforms.remove(&id);
+    }
+    match state.save(&data_lock, Change::All).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
@@ -13,16 +13,16 @@ use actix_web::{
 use openlibrsry::OlId;
 use tokio::fs::{self, File};
 
-use crate::{CLIENT, COVERS_DIR, POSTERS_DIR};
+use crate::{config::config, users::CurrentUser, CLIENT};
 
 async fn get_image(
-    base_path: &str,
+    base_path: &Path,
     kind: &str,
     name: &str,
     dir: &str,
     download_url: impl FnOnce() -> Result<String, HttpResponse>,
 ) -> Either<HttpResponse, io::Result<NamedFile>> {
-    let path = Path::new(base_path).join(dir).join(name);
+    let path = base_path.join(dir).join(name);
     match File::open(&path).await {
         Ok(file) => {
             println!("using saved {kind}");
@@ -70,7 +70,7 @@ async fn get_image(
 
 async fn get_poster(name: web::Path<String>, dir: &str, size: &str) -> impl Responder {
     let name = name.into_inner();
-    get_image(POSTERS_DIR, "poster", &name, dir, || {
+    get_image(&config().posters_dir(), "poster", &name, dir, || {
         Ok(format!("https://image.tmdb.org/t/p/{size}/{name}"))
     })
     .await
@@ -93,15 +93,21 @@ async fn get_cover(
     olid: Option<OlId>,
 ) -> impl Responder {
     let id = id.into_inner();
-    get_image(COVERS_DIR, "cover", &format!("{id}.jpg"), dir, || {
-        olid.map(|olid| {
-            format!("https://covers.openlibrary.org/b/olid/{olid}-{size}.jpg?default=false")
-        })
-        .ok_or_else(|| {
-            HttpResponse::ServiceUnavailable()
-                .body("cannot auto-download cover without OpenLibrary ID")
-        })
-    })
+    get_image(
+        &config().covers_dir(),
+        "cover",
+        &format!("{id}.jpg"),
+        dir,
+        || {
+            olid.map(|olid| {
+                format!("https://covers.openlibrary.org/b/olid/{olid}-{size}.jpg?default=false")
+            })
+            .ok_or_else(|| {
+                HttpResponse::ServiceUnavailable()
+                    .body("cannot auto-download cover without OpenLibrary ID")
+            })
+        },
+    )
     .await
 }
 
@@ -134,12 +140,13 @@ struct FileUpload {
 
 #[post("/api/covers/big/{id}")]
 async fn upload_cover_big(
+    _: CurrentUser,
     id: web::Path<u64>,
     MultipartForm(FileUpload { file }): MultipartForm<FileUpload>,
 ) -> impl Responder {
     if let Err(err) = fs::copy(
         file.file.path(),
-        Path::new(COVERS_DIR).join("big").join(id.to_string()),
+        config().covers_dir().join("big").join(id.to_string()),
     )
     .await
     {
@@ -0,0 +1,424 @@
+//! Background refresh of the TMDB metadata of tracked movies.
+//!
+//! Every `refresh_interval_hours` the movies whose TMDB cache entry is missing or expired are
+//! fetched again in rate-limited batches. Only the fields that come from TMDB are updated, the
+//! ratings and tags are never touched. What changed in the last runs is kept in memory and can be
+//! listed from `/api/refresh`. Changed offers are also recorded in the availability history and
+//! sent to the subscribed users, see [`notify`].
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use actix_web::{
+    get, post,
+    rt::{
+        self,
+        time::{interval, sleep, Instant},
+    },
+    web::{Data, Query},
+    HttpResponse, Responder,
+};
+use chrono::{DateTime, Utc};
+use futures_util::future;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+use crate::{
+    config::config,
+    events, notify,
+    schema::{Cached, Movie, Platform},
+    storage::Change,
+    tmdb,
+    users::{Admin, CurrentUser},
+    AppState,
+};
+
+/// Number of movies fetched concurrently
+const BATCH_SIZE: usize = 10;
+/// Minimum time between the start of two batches. Every movie takes two TMDB requests, which keeps
+/// the refresh well below TMDB's limit of about 50 requests per second.
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of runs whose changes are kept around
+const KEEP_RUNS: usize = 10;
+
+/// Runs from newest to oldest
+static RUNS: Lazy<Mutex<VecDeque<RefreshRun>>> = Lazy::new(Default::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RunState {
+    Running,
+    Finished,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FieldChange {
+    old: Value,
+    new: Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct MovieChanges {
+    tmdb_id: u64,
+    title: String,
+    /// map of field name to its old and new value
+    fields: BTreeMap<&'static str, FieldChange>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FailedRefresh {
+    tmdb_id: u64,
+    title: String,
+    reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RefreshRun {
+    state: RunState,
+    started: DateTime<Utc>,
+    finished: Option<DateTime<Utc>>,
+    /// Number of movies to refresh
+    total: usize,
+    /// Number of movies already handled
+    processed: usize,
+    /// Movies of which at least one field changed
+    changes: Vec<MovieChanges>,
+    failed: Vec<FailedRefresh>,
+    /// Reason why the run was aborted
+    error: Option<String>,
+}
+
+/// Sets `old` to `new` and records the change under `name` if they differ.
+fn update_field<T: PartialEq + Serialize>(
+    changes: &mut BTreeMap<&'static str, FieldChange>,
+    name: &'static str,
+    old: &mut T,
+    new: T,
+) {
+    if *old != new {
+        changes.insert(
+            name,
+            FieldChange {
+                old: serde_json::to_value(&*old).unwrap_or_default(),
+                new: serde_json::to_value(&new).unwrap_or_default(),
+            },
+        );
+        *old = new;
+    }
+}
+
+/// Replaces the TMDB metadata of `movie` with the one of `new` and returns what changed. The
+/// platforms are updated to the offers in `region`. Ratings, tags and platforms without TMDB
+/// providers are kept.
+fn update_metadata(
+    movie: &mut Movie,
+    new: Movie,
+    region: &str,
+    platforms: &BTreeMap<u32, Platform>,
+) -> BTreeMap<&'static str, FieldChange> {
+    let Movie {
+        imdb_id,
+        title,
+        description,
+        availability,
+        poster,
+        release_date,
+        runtime,
+        score,
+        ..
+    } = new;
+    let mut changes = BTreeMap::new();
+    update_field(&mut changes, "imdb_id", &mut movie.imdb_id, imdb_id);
+    update_field(&mut changes, "title", &mut movie.title, title);
+    update_field(
+        &mut changes,
+        "description",
+        &mut movie.description,
+        description,
+    );
+    update_field(&mut changes, "poster", &mut movie.poster, poster);
+    update_field(
+        &mut changes,
+        "release_date",
+        &mut movie.release_date,
+        release_date,
+    );
+    update_field(&mut changes, "runtime", &mut movie.runtime, runtime);
+    update_field(&mut changes, "score", &mut movie.score, score);
+    update_field(
+        &mut changes,
+        "availability",
+        &mut movie.availability,
+        availability,
+    );
+
+    let old_platforms = movie.platforms.clone();
+    movie.in_region(region, platforms);
+    let new_platforms = std::mem::replace(&mut movie.platforms, old_platforms);
+    update_field(
+        &mut changes,
+        "platforms",
+        &mut movie.platforms,
+        new_platforms,
+    );
+    changes
+}
+
+/// Adds a new run for `total` movies unless one is still running.
+async fn start_run(total: usize) -> Option<RefreshRun> {
+    let mut runs = RUNS.lock().await;
+    if runs
+        .front()
+        .is_some_and(|run| run.state == RunState::Running)
+    {
+        return None;
+    }
+    runs.truncate(KEEP_RUNS - 1);
+    let run = RefreshRun {
+        state: RunState::Running,
+        started: Utc::now(),
+        finished: None,
+        total,
+        processed: 0,
+        changes: vec![],
+        failed: vec![],
+        error: None,
+    };
+    runs.push_front(run.clone());
+    Some(run)
+}
+
+/// TMDB ids of all tracked movies, or only of those whose cache entry is missing or expired unless
+/// `all` is set.
+async fn due_movies(state: &AppState, all: bool) -> Vec<u64> {
+    let data_lock = state.data.lock().await;
+    data_lock
+        .movies
+        .keys()
+        .filter(|id| {
+            all || data_lock
+                .tmdb_cache
+                .get(&id.to_string())
+                .is_none_or(tmdb::is_expired)
+        })
+        .copied()
+        .collect()
+}
+
+/// Refreshes the `movies` in a separate task, so that the newest run is marked as failed instead
+/// of staying in the running state forever if a batch aborts with a panic. Connected clients are
+/// told to reload everything afterwards if any movie changed.
+async fn run(state: Data<AppState>, movies: Vec<u64>) {
+    let result = rt::spawn(refresh_movies(state, movies)).await;
+    let mut runs = RUNS.lock().await;
+    let Some(run) = runs.front_mut() else {
+        return;
+    };
+    if let Err(err) = result {
+        run.state = RunState::Failed;
+        run.finished = Some(Utc::now());
+        run.error = Some(format!("Refresh aborted: {err}"));
+    }
+    let changed = !run.changes.is_empty();
+    drop(runs);
+    if changed {
+        events::reset().await;
+    }
+}
+
+/// Fetches the `movies` in batches and updates them, recording the progress in the newest run.
+async fn refresh_movies(state: Data<AppState>, movies: Vec<u64>) {
+    for batch in movies.chunks(BATCH_SIZE) {
+        let batch_start = Instant::now();
+        let regions = tmdb::regions(&*state.data.lock().await, &config().region);
+        let keys: Vec<_> = batch.iter().map(u64::to_string).collect();
+        let fetched =
+            future::join_all(keys.iter().map(|key| tmdb::fetch_movie(key, &regions))).await;
+
+        let mut data_lock = state.data.lock().await;
+        let data = &mut *data_lock;
+        let mut runs = RUNS.lock().await;
+        let Some(run) = runs.front_mut() else {
+            return;
+        };
+        let mut notifications = vec![];
+        for (&id, new) in batch.iter().zip(fetched) {
+            run.processed += 1;
+            // the movie might have been deleted in the meantime
+            let Some(movie) = data.movies.get_mut(&id) else {
+                continue;
+            };
+            let mut new = match new {
+                Ok(new) => new,
+                Err(err) => {
+                    run.failed.push(FailedRefresh {
+                        tmdb_id: id,
+                        title: movie.title.clone(),
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+            new.in_region(&config().region, &data.platforms);
+
+            let old_availability = movie.availability.clone();
+            let fields = update_metadata(movie, new.clone(), &config().region, &data.platforms);
+            data.tmdb_cache.insert(id.to_string(), Cached::new(new));
+            let mut changes = vec![Change::CacheEntry(id.to_string())];
+            if !fields.is_empty() {
+                movie.revision += 1;
+                run.changes.push(MovieChanges {
+                    tmdb_id: id,
+                    title: data.movies[&id].title.clone(),
+                    fields,
+                });
+                changes.push(Change::Movie(id));
+            }
+            let events = notify::availability_events(
+                &old_availability,
+                &data.movies[&id].availability,
+                &data.platforms,
+            );
+            if !events.is_empty() {
+                notifications.extend(notify::notifications(data, id, &events));
+                data.availability_history
+                    .entry(id)
+                    .or_default()
+                    .extend(events);
+                changes.push(Change::AvailabilityHistory(id));
+            }
+            for change in changes {
+                if let Err(err) = state.save(data, change).await {
+                    run.state = RunState::Failed;
+                    run.finished = Some(Utc::now());
+                    run.error = Some(format!("Failed to save new data to disk: {err}"));
+                    return;
+                }
+            }
+        }
+        drop(runs);
+        drop(data_lock);
+        rt::spawn(notify::deliver(
+            notifications,
+            config().private_notification_urls,
+        ));
+
+        sleep(BATCH_INTERVAL.saturating_sub(batch_start.elapsed())).await;
+    }
+
+    if let Some(run) = RUNS.lock().await.front_mut() {
+        run.state = RunState::Finished;
+        run.finished = Some(Utc::now());
+    }
+}
+
+/// Refreshes all movies whose cache entry is missing or expired every `refresh_interval_hours`,
+/// starting right away. Runs forever and must only be called if the interval is not 0.
+pub async fn run_periodically(state: Data<AppState>) {
+    let hours = config().refresh_interval_hours;
+    let mut interval = interval(Duration::from_secs(hours.saturating_mul(60 * 60)));
+    loop {
+        interval.tick().await;
+        let movies = due_movies(&state, false).await;
+        if movies.is_empty() || start_run(movies.len()).await.is_none() {
+            continue;
+        }
+        run(state.clone(), movies).await;
+    }
+}
+
+#[get("/api/refresh")]
+async fn get_runs(_: CurrentUser) -> impl Responder {
+    HttpResponse::Ok().json(&*RUNS.lock().await)
+}
+
+#[derive(Deserialize)]
+struct RefreshQuery {
+    /// Also refresh movies whose cache entry has not expired yet
+    #[serde(default)]
+    all: bool,
+}
+
+#[post("/api/refresh")]
+async fn post_run(
+    state: Data<AppState>,
+    _: Admin,
+    Query(RefreshQuery { all }): Query<RefreshQuery>,
+) -> impl Responder {
+    let movies = due_movies(&state, all).await;
+    let Some(run) = start_run(movies.len()).await else {
+        return HttpResponse::Conflict().body("a refresh is already running");
+    };
+    rt::spawn(self::run(state, movies));
+    HttpResponse::Accepted().json(run)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::schema::{default_platforms, Availability, Provider, Rating};
+
+    #[test]
+    fn keeps_ratings_and_tags() {
+        let old = Movie {
+            imdb_id: Some(133093),
+            tmdb_id: 603,
+            title: "The Matrix".into(),
+            description: String::new(),
+            ratings: vec![Rating {
+                user: 1,
+                date: NaiveDate::from_ymd_opt(2023, 4, 1).unwrap(),
+                rating: 9,
+                speed: 1.,
+                platform: Some(3),
+                tags: BTreeSet::new(),
+            }],
+            tags: [1].into(),
+            // watched on DVD, formerly on Netflix
+            platforms: [3, 6].into(),
+            availability: BTreeMap::new(),
+            poster: None,
+            release_date: NaiveDate::from_ymd_opt(1999, 3, 31).unwrap(),
+            runtime: Duration::from_secs(136 * 60),
+            score: 8.2,
+            revision: 0,
+        };
+        let availability = Availability {
+            flatrate: [Provider {
+                tmdb_id: 337,
+                name: "Disney Plus".into(),
+            }]
+            .into(),
+            ..Default::default()
+        };
+        let new = Movie {
+            ratings: vec![],
+            tags: BTreeSet::new(),
+            platforms: BTreeSet::new(),
+            availability: [("DE".to_owned(), availability)].into(),
+            score: 8.3,
+            ..old.clone()
+        };
+
+        let mut movie = old.clone();
+        let changes = update_metadata(&mut movie, new, "DE", &default_platforms());
+        assert_eq!(
+            changes.keys().copied().collect::<Vec<_>>(),
+            ["availability", "platforms", "score"]
+        );
+        assert_eq!(changes["score"].new, 8.3);
+        assert_eq!(movie.ratings, old.ratings);
+        assert_eq!(movie.tags, old.tags);
+        assert_eq!(movie.platforms, [1, 6].into());
+        assert!(update_metadata(&mut movie.clone(), movie, "DE", &default_platforms()).is_empty());
+    }
+}
@@ -0,0 +1,603 @@
+//! Yearly "wrapped"-style summary of the watch and reading history.
+//!
+//! Besides the JSON summary, the review can be rendered as a self-contained HTML page which
+//! embeds posters and covers from the local image caches, so it can be shared as a single file.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use actix_web::{
+    get,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Datelike, Local, NaiveDate};
+use serde::Serialize;
+use tokio::fs;
+
+use crate::{
+    config::config,
+    schema::AppData,
+    stats::{self, DateRange, PlatformShare, Streak, TagUsage},
+    users::CurrentUser,
+    AppState,
+};
+
+/// Number of entries in each of the lists of the review
+const TOP_COUNT: usize = 5;
+
+/// Where to look for the image of a title in the local caches.
+#[derive(Debug, Clone)]
+enum Image {
+    /// TMDB poster path
+    Poster(Option<String>),
+    /// Book id
+    Cover(u32),
+}
+
+/// Extensions of the images which are embedded into the page
+const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+/// Returns `name` as a path if it is the plain file name of an image. Poster paths can be set by
+/// any user, so anything else could point outside of the image cache.
+fn image_file_name(name: &str) -> Option<&Path> {
+    let path = Path::new(name);
+    let mut components = path.components();
+    let (Some(Component::Normal(_)), None) = (components.next(), components.next()) else {
+        return None;
+    };
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    IMAGE_EXTENSIONS
+        .contains(&extension.as_str())
+        .then_some(path)
+}
+
+/// Canonicalizes `path` if it exists and is still inside of `dir` afterwards, which is not the
+/// case for symbolic links pointing elsewhere.
+async fn canonicalize_within(dir: &Path, path: &Path) -> Option<PathBuf> {
+    let dir = fs::canonicalize(dir).await.ok()?;
+    let path = fs::canonicalize(path).await.ok()?;
+    path.starts_with(dir).then_some(path)
+}
+
+impl Image {
+    /// Returns the path of the cached image, preferring the bigger one.
+    async fn path(&self) -> Option<PathBuf> {
+        let config = config();
+        self.find(&config.posters_dir(), &config.covers_dir()).await
+    }
+
+    /// Looks for the image in the given poster and cover caches.
+    async fn find(&self, posters_dir: &Path, covers_dir: &Path) -> Option<PathBuf> {
+        let (dir, names) = match self {
+            Image::Poster(poster) => {
+                let name = image_file_name(poster.as_deref()?.trim_start_matches('/'))?;
+                (posters_dir, vec![name.to_owned()])
+            }
+            // uploaded covers are stored without extension
+            Image::Cover(id) => (
+                covers_dir,
+                vec![
+                    PathBuf::from(format!("{id}.jpg")),
+                    PathBuf::from(id.to_string()),
+                ],
+            ),
+        };
+        for size in ["big", "small"] {
+            for name in &names {
+                if let Some(path) = canonicalize_within(dir, &dir.join(size).join(name)).await {
+                    return Some(path);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RatedTitle {
+    /// TMDB id for movies, book id for books
+    id: u64,
+    title: String,
+    /// Average of all ratings within the year
+    rating: f64,
+    #[serde(skip)]
+    image: Image,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Rewatch {
+    tmdb_id: u64,
+    title: String,
+    /// Number of watches within the year
+    count: usize,
+    #[serde(skip)]
+    image: Image,
+}
+
+#[derive(Debug, Serialize)]
+struct MovieReview {
+    watches: usize,
+    /// Number of different movies watched
+    movies: usize,
+    /// Time spent watching, shortened by faster playback
+    hours: f64,
+    runtime_hours: f64,
+    highest_rated: Vec<RatedTitle>,
+    lowest_rated: Vec<RatedTitle>,
+    rewatches: Vec<Rewatch>,
+    longest_streak: Option<Streak>,
+}
+
+#[derive(Debug, Serialize)]
+struct BookReview {
+    /// Number of readings started within the year
+    started: usize,
+    /// Number of readings finished within the year
+    finished: usize,
+    pages: u32,
+    /// Number of days with at least one page read
+    reading_days: usize,
+    /// Pages per day of the year, up to today for the current year
+    pages_per_day: f64,
+    pages_per_reading_day: f64,
+    /// Average number of days from the first to the last day of finished readings
+    days_per_book: Option<f64>,
+    highest_rated: Vec<RatedTitle>,
+    lowest_rated: Vec<RatedTitle>,
+    longest_streak: Option<Streak>,
+}
+
+#[derive(Debug, Serialize)]
+struct Review {
+    year: i32,
+    movies: MovieReview,
+    books: BookReview,
+    tags: Vec<TagUsage>,
+    platforms: Vec<PlatformShare>,
+}
+
+/// Splits `titles` into the best and worst rated ones, without overlap.
+fn top_and_bottom(mut titles: Vec<RatedTitle>) -> (Vec<RatedTitle>, Vec<RatedTitle>) {
+    titles.sort_by(|a, b| {
+        b.rating
+            .total_cmp(&a.rating)
+            .then_with(|| a.title.cmp(&b.title))
+    });
+    let highest: Vec<_> = titles.iter().take(TOP_COUNT).cloned().collect();
+    let lowest = titles
+        .into_iter()
+        .skip(highest.len())
+        .rev()
+        .take(TOP_COUNT)
+        .collect();
+    (highest, lowest)
+}
+
+fn review(data: &AppData, year: i32) -> Option<Review> {
+    let range = DateRange {
+        from: NaiveDate::from_ymd_opt(year, 1, 1),
+        to: NaiveDate::from_ymd_opt(year, 12, 31),
+    };
+    let first_day = range.from?;
+    let today = Local::now().date_naive();
+    let days_in_year = if year == today.year() {
+        today.ordinal()
+    } else if year > today.year() {
+        0
+    } else {
+        range.to?.ordinal()
+    };
+
+    // movies
+    let mut movie_ratings = BTreeMap::<u64, Vec<u8>>::new();
+    let mut watch_days = BTreeSet::new();
+    let mut hours = 0.;
+    let mut runtime_hours = 0.;
+    let mut watches = 0;
+    for (movie, rating) in stats::watches(data, range) {
+        movie_ratings
+            .entry(movie.tmdb_id)
+            .or_default()
+            .push(rating.rating);
+        watch_days.insert(rating.date);
+        hours += stats::watch_hours(movie, rating);
+        runtime_hours += movie.runtime.as_secs_f64() / 3600.;
+        watches += 1;
+    }
+    let rated_movies = movie_ratings
+        .iter()
+        .map(|(id, ratings)| {
+            let movie = &data.movies[id];
+            RatedTitle {
+                id: *id,
+                title: movie.title.clone(),
+                rating: ratings.iter().map(|&r| r as f64).sum::<f64>() / ratings.len() as f64,
+                image: Image::Poster(movie.poster.clone()),
+            }
+        })
+        .collect();
+    let (highest_rated, lowest_rated) = top_and_bottom(rated_movies);
+    let mut rewatches: Vec<_> = movie_ratings
+        .iter()
+        .filter(|(_, ratings)| ratings.len() > 1)
+        .map(|(id, ratings)| {
+            let movie = &data.movies[id];
+            Rewatch {
+                tmdb_id: *id,
+                title: movie.title.clone(),
+                count: ratings.len(),
+                image: Image::Poster(movie.poster.clone()),
+            }
+        })
+        .collect();
+    rewatches.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.title.cmp(&b.title)));
+    let movies = MovieReview {
+        watches,
+        movies: movie_ratings.len(),
+        hours,
+        runtime_hours,
+        highest_rated,
+        lowest_rated,
+        rewatches,
+        longest_streak: stats::longest_streak(&watch_days),
+    };
+
+    // books
+    let mut reading_days = BTreeMap::<NaiveDate, u32>::new();
+    let mut rated_books = vec![];
+    let mut started = 0;
+    let mut finished = 0;
+    let mut durations = vec![];
+    for book in data.books.values() {
+        let mut ratings = vec![];
+        for reading in &book.readings {
+            for (date, pages) in &reading.pages_read {
+                if range.contains(*date) {
+                    *reading_days.entry(*date).or_default() += *pages as u32;
+                }
+            }
+            let first = reading.pages_read.keys().next().copied();
+            if first.is_some_and(|date| range.contains(date)) {
+                started += 1;
+            }
+            if let Some(end) = reading.finished_on().filter(|date| range.contains(*date)) {
+                finished += 1;
+                if let Some(first) = first {
+                    durations.push((end - first).num_days() as f64 + 1.);
+                }
+            }
+            if let Some(rating) = &reading.rating {
+                if range.contains(rating.date) {
+                    ratings.push(rating.rating as f64);
+                }
+            }
+        }
+        if !ratings.is_empty() {
+            rated_books.push(RatedTitle {
+                id: book.id as u64,
+                title: book.title.clone(),
+                rating: ratings.iter().sum::<f64>() / ratings.len() as f64,
+                image: Image::Cover(book.id),
+            });
+        }
+    }
+    let pages: u32 = reading_days.values().sum();
+    let (highest_rated, lowest_rated) = top_and_bottom(rated_books);
+    let books = BookReview {
+        started,
+        finished,
+        pages,
+        reading_days: reading_days.len(),
+        pages_per_day: match days_in_year {
+            0 => 0.,
+            days => pages as f64 / days as f64,
+        },
+        pages_per_reading_day: match reading_days.len() {
+            0 => 0.,
+            days => pages as f64 / days as f64,
+        },
+        days_per_book: (!durations.is_empty())
+            .then(|| durations.iter().sum::<f64>() / durations.len() as f64),
+        highest_rated,
+        lowest_rated,
+        longest_streak: stats::longest_streak(&reading_days.into_keys().collect()),
+    };
+
+    let mut tags = stats::tag_usage(data, range);
+    tags.truncate(TOP_COUNT);
+    let mut platforms = stats::platform_shares(data, range);
+    platforms.truncate(TOP_COUNT);
+    Some(Review {
+        year: first_day.year(),
+        movies,
+        books,
+        tags,
+        platforms,
+    })
+}
+
+#[get("/api/review/{year}")]
+async fn get_review(
+    state: Data<AppState>,
+    user: CurrentUser,
+    year: web::Path<i32>,
+) -> impl Responder {
+    match review(&state.data.lock().await.view(user.id), *year) {
+        Some(review) => HttpResponse::Ok().json(review),
+        None => HttpResponse::BadRequest().body(format!("invalid year {year}")),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reads an image into a `data:` URL so it can be embedded into the page.
+async fn data_url(path: &Path) -> Option<String> {
+    let mime = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        _ => "image/jpeg",
+    };
+    let bytes = fs::read(path).await.ok()?;
+    Some(format!("data:{mime};base64,{}", STANDARD.encode(bytes)))
+}
+
+async fn write_titles<'t>(
+    html: &mut String,
+    heading: &str,
+    titles: impl IntoIterator<Item = (&'t str, String, &'t Image)>,
+) {
+    let mut items = String::new();
+    for (title, detail, image) in titles {
+        let image = match image.path().await {
+            Some(path) => data_url(&path).await,
+            None => None,
+        };
+        let image = match image {
+            Some(url) => format!(r#"<img src="{url}" alt="">"#),
+            None => r#"<div class="placeholder"></div>"#.to_owned(),
+        };
+        let _ = write!(
+            items,
+            r#"<li>{image}<span class="title">{}</span><span class="detail">{}</span></li>"#,
+            escape(title),
+            escape(&detail),
+        );
+    }
+    if !items.is_empty() {
+        let _ = write!(
+            html,
+            r#"<section><h2>{}</h2><ul class="titles">{items}</ul></section>"#,
+            escape(heading)
+        );
+    }
+}
+
+fn tile(value: impl std::fmt::Display, label: &str) -> String {
+    format!(
+        r#"<div class="tile"><span class="value">{}</span><span class="label">{}</span></div>"#,
+        escape(&value.to_string()),
+        escape(label)
+    )
+}
+
+fn streak_text(streak: &Option<Streak>) -> String {
+    match streak {
+        Some(streak) => format!(
+            "{} days ({} – {})",
+            streak.days,
+            streak.start.format("%b %-d"),
+            streak.end.format("%b %-d")
+        ),
+        None => "–".to_owned(),
+    }
+}
+
+async fn render(review: &Review) -> String {
+    let Review {
+        year,
+        movies,
+        books,
+        tags,
+        platforms,
+    } = review;
+    let mut html = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{year} in Review</title>
+<style>
+body {{ font-family: system-ui, sans-serif; background: #14141c; color: #eee; margin: 0; padding: 2rem; }}
+main {{ max-width: 60rem; margin: auto; }}
+h1 {{ font-size: 3rem; margin-bottom: 0; }}
+h2 {{ color: #b39ddb; }}
+.tiles {{ display: flex; flex-wrap: wrap; gap: 1rem; }}
+.tile {{ background: #242432; border-radius: .75rem; padding: 1rem 1.5rem; display: flex; flex-direction: column; min-width: 9rem; }}
+.tile .value {{ font-size: 1.75rem; font-weight: bold; }}
+.tile .label {{ opacity: .7; }}
+.titles {{ list-style: none; padding: 0; display: flex; flex-wrap: wrap; gap: 1rem; }}
+.titles li {{ width: 8rem; display: flex; flex-direction: column; gap: .25rem; }}
+.titles img, .placeholder {{ width: 8rem; height: 12rem; object-fit: cover; border-radius: .5rem; background: #242432; }}
+.title {{ font-weight: bold; }}
+.detail {{ opacity: .7; }}
+</style>
+</head>
+<body>
+<main>
+<h1>{year} in Review</h1>
+<section><h2>Movies</h2><div class="tiles">"#
+    );
+    html += &tile(movies.watches, "watches");
+    html += &tile(movies.movies, "different movies");
+    html += &tile(format!("{:.0}", movies.hours), "hours watched");
+    html += &tile(streak_text(&movies.longest_streak), "longest streak");
+    html += "</div></section>";
+    let rating = |title: &RatedTitle| format!("{:.1} / 10", title.rating);
+    write_titles(
+        &mut html,
+        "Favourite movies",
+        movies
+            .highest_rated
+            .iter()
+            .map(|t| (t.title.as_str(), rating(t), &t.image)),
+    )
+    .await;
+    write_titles(
+        &mut html,
+        "Least favourite movies",
+        movies
+            .lowest_rated
+            .iter()
+            .map(|t| (t.title.as_str(), rating(t), &t.image)),
+    )
+    .await;
+    write_titles(
+        &mut html,
+        "Rewatches",
+        movies
+            .rewatches
+            .iter()
+            .map(|r| (r.title.as_str(), format!("{} times", r.count), &r.image)),
+    )
+    .await;
+
+    html += r#"<section><h2>Books</h2><div class="tiles">"#;
+    html += &tile(books.finished, "books finished");
+    html += &tile(books.pages, "pages read");
+    html += &tile(format!("{:.1}", books.pages_per_day), "pages per day");
+    html += &tile(streak_text(&books.longest_streak), "longest streak");
+    html += "</div></section>";
+    write_titles(
+        &mut html,
+        "Favourite books",
+        books
+            .highest_rated
+            .iter()
+            .map(|t| (t.title.as_str(), rating(t), &t.image)),
+    )
+    .await;
+    write_titles(
+        &mut html,
+        "Least favourite books",
+        books
+            .lowest_rated
+            .iter()
+            .map(|t| (t.title.as_str(), rating(t), &t.image)),
+    )
+    .await;
+
+    if !tags.is_empty() {
+        html += r#"<section><h2>Favourite tags</h2><div class="tiles">"#;
+        for tag in tags {
+            html += &tile(tag.watches + tag.readings, &tag.name);
+        }
+        html += "</div></section>";
+    }
+    if !platforms.is_empty() {
+        html += r#"<section><h2>Platforms</h2><div class="tiles">"#;
+        for platform in platforms {
+            let name = platform.name.as_deref().unwrap_or("Unknown");
+            html += &tile(format!("{:.0}%", platform.share * 100.), name);
+        }
+        html += "</div></section>";
+    }
+    html += "</main>\n</body>\n</html>\n";
+    html
+}
+
+#[get("/api/review/{year}/html")]
+async fn get_review_html(
+    state: Data<AppState>,
+    user: CurrentUser,
+    year: web::Path<i32>,
+) -> impl Responder {
+    // don't hold the lock while reading the images
+    let review = review(&state.data.lock().await.view(user.id), *year);
+    match review {
+        Some(review) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .insert_header((
+                "Content-Disposition",
+                format!("inline; filename=\"review-{year}.html\""),
+            ))
+            .body(render(&review).await),
+        None => HttpResponse::BadRequest().body(format!("invalid year {year}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn image_file_names() {
+        assert!(image_file_name("poster.jpg").is_some());
+        assert!(image_file_name("poster.PNG").is_some());
+        assert!(image_file_name("poster").is_none());
+        assert!(image_file_name("data.json").is_none());
+        assert!(image_file_name("../poster.jpg").is_none());
+        assert!(image_file_name("big/poster.jpg").is_none());
+        assert!(image_file_name("/etc/poster.jpg").is_none());
+    }
+
+    #[actix_web::test]
+    async fn images_stay_in_cache() {
+        let dir = std::env::temp_dir().join(format!("entrackment-review-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let posters = dir.join("posters");
+        let covers = dir.join("covers");
+        std::fs::create_dir_all(posters.join("big")).unwrap();
+        std::fs::create_dir_all(covers.join("small")).unwrap();
+        std::fs::write(dir.join("data.json"), "{}").unwrap();
+        std::fs::write(dir.join("secret.jpg"), "").unwrap();
+        std::fs::write(posters.join("big").join("poster.jpg"), "").unwrap();
+        std::fs::write(covers.join("small").join("7"), "").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.jpg"), posters.join("big").join("link.jpg"))
+            .unwrap();
+
+        let find = |image: Image| {
+            let (posters, covers) = (posters.clone(), covers.clone());
+            async move { image.find(&posters, &covers).await }
+        };
+        let poster = |path: &str| Image::Poster(Some(path.into()));
+        assert_eq!(
+            find(poster("/poster.jpg")).await,
+            Some(
+                posters
+                    .join("big")
+                    .join("poster.jpg")
+                    .canonicalize()
+                    .unwrap()
+            )
+        );
+        assert_eq!(find(poster("../../data.json")).await, None);
+        assert_eq!(find(poster("/../../secret.jpg")).await, None);
+        assert_eq!(find(poster("/link.jpg")).await, None);
+        assert_eq!(
+            find(Image::Cover(7)).await,
+            Some(covers.join("small").join("7").canonicalize().unwrap())
+        );
+        assert_eq!(find(Image::Cover(8)).await, None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+//! Optimistic concurrency control for movies, tags and books.
+//!
+//! Every change increments the `revision` of the entity, which is sent as a strong `ETag` when a
+//! single entity is requested. Requests replacing a whole entity have to send the revision they
+//! are based on in the `If-Match` header. If the entity was changed in the meantime, they fail with
+//! `412 Precondition Failed` and the current version in the body instead of overwriting it.
+
+use actix_web::{
+    http::header::{self, ETag, EntityTag, Header, IfMatch},
+    HttpRequest, HttpResponse,
+};
+use serde::Serialize;
+
+/// The `ETag` header of an entity at `revision`.
+pub fn etag(revision: u64) -> ETag {
+    ETag(EntityTag::new_strong(revision.to_string()))
+}
+
+/// Checks that the `If-Match` header of `req` matches `revision`. Fails with `428 Precondition
+/// Required` if there is none and with `412 Precondition Failed` and `current` as body if the
+/// entity has been changed since.
+pub fn check(
+    req: &HttpRequest,
+    revision: u64,
+    current: &impl Serialize,
+) -> Result<(), HttpResponse> {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return Err(HttpResponse::PreconditionRequired()
+            .body("the If-Match header with the revision to replace is required"));
+    }
+    let ETag(tag) = etag(revision);
+    let matches = match IfMatch::parse(req) {
+        Ok(IfMatch::Any) => true,
+        Ok(IfMatch::Items(items)) => items.iter().any(|item| item.strong_eq(&tag)),
+        Err(_) => return Err(HttpResponse::BadRequest().body("invalid If-Match header")),
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(HttpResponse::PreconditionFailed()
+            .insert_header(etag(revision))
+            .json(current))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{http::StatusCode, test::TestRequest};
+
+    use super::*;
+
+    #[test]
+    fn if_match() {
+        let check = |if_match: Option<&str>| {
+            let mut req = TestRequest::default();
+            if let Some(if_match) = if_match {
+                req = req.insert_header((header::IF_MATCH, if_match));
+            }
+            check(&req.to_http_request(), 3, &()).map_err(|resp| resp.status())
+        };
+        assert_eq!(check(Some("\"3\"")), Ok(()));
+        assert_eq!(check(Some("\"2\", \"3\"")), Ok(()));
+        assert_eq!(check(Some("*")), Ok(()));
+        assert_eq!(check(Some("\"2\"")), Err(StatusCode::PRECONDITION_FAILED));
+        // weak tags never match
+        assert_eq!(check(Some("W/\"3\"")), Err(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(check(None), Err(StatusCode::PRECONDITION_REQUIRED));
+    }
+}
@@ -1,27 +1,339 @@
 use std::{
     borrow::Cow,
+    cmp,
     collections::{BTreeMap, BTreeSet, HashMap},
     time::Duration,
 };
 
-use chrono::NaiveDate;
+use chrono::{DateTime, NaiveDate, Utc};
 use openlibrsry::OlId;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+/// The complete state of the application.
+///
+/// Movies, series and books are shared between all users, while ratings, readings, tags,
+/// watchlists and goals belong to a single user. Use [`AppData::view`] to get the data as seen by
+/// one user.
+///
+/// The serialized format is versioned, see [`crate::migrations`] before changing anything here or
+/// in any of the nested types.
+#[derive(Default, Serialize, Deserialize)]
 pub struct AppData {
+    /// map of user id to User structs
+    pub users: BTreeMap<u32, User>,
     /// map of TMDB id to Movie structs
-    #[serde(default)]
     pub movies: BTreeMap<u64, Movie>,
     /// map of tag id to tag structs
-    #[serde(default)]
     pub tags: HashMap<u32, Tag>,
+    /// map of platform id to the platforms shared by all users
+    pub platforms: BTreeMap<u32, Platform>,
     /// map of TMDB or IMDb id to raw movies
-    #[serde(default)]
-    pub tmdb_cache: HashMap<String, Movie>,
+    pub tmdb_cache: HashMap<String, Cached<Movie>>,
     /// map of book id to Book structs
-    #[serde(default)]
     pub books: HashMap<u32, Book>,
+    /// map of user id to the reading goals of that user
+    pub goals: HashMap<u32, Goals>,
+    /// map of user id to the watchlist of that user, which maps TMDB ids to watchlist entries
+    pub watchlists: HashMap<u32, BTreeMap<u64, WatchlistEntry>>,
+    /// map of TMDB id to Series structs
+    pub series: BTreeMap<u64, Series>,
+    /// map of TMDB id to raw series
+    pub tmdb_tv_cache: HashMap<u64, Cached<Series>>,
+    /// map of user id to the Argon2 hash of their password in PHC string format
+    pub passwords: HashMap<u32, String>,
+    /// map of token id to the API tokens of all users
+    pub api_tokens: HashMap<u32, ApiToken>,
+    /// map of TMDB id to the changes of the platforms a tracked movie is available on, from oldest
+    /// to newest
+    pub availability_history: BTreeMap<u64, Vec<AvailabilityEvent>>,
+    /// map of user id to the notification settings of that user
+    pub notifications: HashMap<u32, Notifications>,
+    /// map of webhook id to the outgoing webhooks
+    pub webhooks: HashMap<u32, Webhook>,
+}
+
+impl AppData {
+    /// Ids of all tags owned by `user`.
+    pub fn user_tags(&self, user: u32) -> BTreeSet<u32> {
+        self.tags
+            .values()
+            .filter(|tag| tag.user == user)
+            .map(|tag| tag.id)
+            .collect()
+    }
+
+    /// Copy of the data as seen by `user`. It contains all movies, series and books, but only the
+    /// ratings, readings, tags, watchlist, goals and notification settings of that user. The TMDB
+    /// caches, passwords, API tokens and webhooks are left empty.
+    pub fn view(&self, user: u32) -> AppData {
+        let tags = self.user_tags(user);
+        AppData {
+            users: self
+                .users
+                .get(&user)
+                .map(|u| (user, u.clone()))
+                .into_iter()
+                .collect(),
+            movies: self
+                .movies
+                .iter()
+                .map(|(&id, movie)| (id, movie.view(user, &tags)))
+                .collect(),
+            tags: self
+                .tags
+                .iter()
+                .filter(|(id, _)| tags.contains(id))
+                .map(|(&id, tag)| (id, tag.clone()))
+                .collect(),
+            platforms: self.platforms.clone(),
+            tmdb_cache: HashMap::new(),
+            books: self
+                .books
+                .iter()
+                .map(|(&id, book)| (id, book.view(user, &tags)))
+                .collect(),
+            goals: self
+                .goals
+                .get(&user)
+                .map(|goals| (user, goals.clone()))
+                .into_iter()
+                .collect(),
+            watchlists: self
+                .watchlists
+                .get(&user)
+                .map(|watchlist| (user, watchlist.clone()))
+                .into_iter()
+                .collect(),
+            series: self
+                .series
+                .iter()
+                .map(|(&id, series)| (id, series.view(user, &tags)))
+                .collect(),
+            tmdb_tv_cache: HashMap::new(),
+            passwords: HashMap::new(),
+            api_tokens: HashMap::new(),
+            availability_history: self.availability_history.clone(),
+            notifications: self
+                .notifications
+                .get(&user)
+                .map(|notifications| (user, notifications.clone()))
+                .into_iter()
+                .collect(),
+            webhooks: HashMap::new(),
+        }
+    }
+
+    /// Removes `user` together with everything that belongs to them.
+    pub fn remove_user(&mut self, user: u32) {
+        let tags = self.user_tags(user);
+        self.users.remove(&user);
+        self.tags.retain(|id, _| !tags.contains(id));
+        self.goals.remove(&user);
+        self.notifications.remove(&user);
+        self.watchlists.remove(&user);
+        self.passwords.remove(&user);
+        self.api_tokens.retain(|_, token| token.user != user);
+        for movie in self.movies.values_mut() {
+            movie.ratings.retain(|rating| rating.user != user);
+            movie.tags.retain(|id| !tags.contains(id));
+        }
+        for book in self.books.values_mut() {
+            book.readings.retain(|reading| reading.user != user);
+            book.tags.retain(|id| !tags.contains(id));
+        }
+        for series in self.series.values_mut() {
+            series.tags.retain(|id| !tags.contains(id));
+            for episode in series.seasons.iter_mut().flat_map(|s| &mut s.episodes) {
+                episode.watched.remove(&user);
+                episode.ratings.retain(|rating| rating.user != user);
+            }
+        }
+    }
+}
+
+/// An entry of the TMDB caches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cached<T> {
+    pub value: T,
+    /// When the value was fetched from TMDB
+    pub fetched: DateTime<Utc>,
+}
+
+impl<T> Cached<T> {
+    /// Entry for a `value` that was just fetched.
+    pub fn new(value: T) -> Self {
+        Self {
+            value,
+            fetched: Utc::now(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub id: u32,
+    /// Unique name of the user
+    pub name: String,
+    /// Whether the user may manage other users and the whole collection
+    pub admin: bool,
+    /// ISO 3166-1 country code of the region whose streaming offers are shown to the user,
+    /// defaults to the one of the server
+    pub region: Option<String>,
+}
+
+/// A long-lived token for scripts to access the API on behalf of a user.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: u32,
+    /// Id of the user on whose behalf the token acts
+    pub user: u32,
+    /// Description of what the token is used for
+    pub name: String,
+    pub scope: TokenScope,
+    /// Hex-encoded SHA-256 hash of the token, the token itself is only shown once
+    pub hash: String,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    strum::EnumString,
+    strum::IntoStaticStr,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum TokenScope {
+    /// Only requests which do not modify anything
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Goals {
+    /// map of year to the number of books to finish in that year
+    pub yearly_books: BTreeMap<i32, u32>,
+    /// Pages to read every day
+    pub daily_pages: Option<u16>,
+    /// Minutes to read every day, estimated from the pages read using `pages_per_hour`
+    pub daily_minutes: Option<u16>,
+    /// Estimated reading pace
+    pub pages_per_hour: f32,
+}
+
+impl Default for Goals {
+    fn default() -> Self {
+        Self {
+            yearly_books: BTreeMap::new(),
+            daily_pages: None,
+            daily_minutes: None,
+            pages_per_hour: 30.,
+        }
+    }
+}
+
+/// An outgoing webhook which is called for changes of movies, tags, books and series, see
+/// [`crate::webhooks`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: u32,
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature of the payloads
+    pub secret: String,
+    /// Names of the events to send like `movie.created`, all events are sent if this is empty
+    #[serde(default)]
+    pub events: BTreeSet<String>,
+}
+
+/// Where a user is notified when movies on their watchlist become available on or leave one of the
+/// platforms they subscribe to in their region.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Notifications {
+    /// Ids of the subscribed platforms
+    pub platforms: BTreeSet<u32>,
+    pub channels: Vec<Channel>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Channel {
+    /// Sends notifications as JSON in POST requests to `url`
+    Webhook { url: String },
+    /// Publishes notifications as plain text messages to the ntfy topic at `url`, for example
+    /// `https://ntfy.sh/my-topic`
+    Ntfy { url: String },
+    /// Sends notifications by email through the SMTP server of the configuration
+    Email { address: String },
+}
+
+/// A movie became available on or left a platform in a region.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AvailabilityEvent {
+    pub time: DateTime<Utc>,
+    pub region: String,
+    /// Id of the platform
+    pub platform: u32,
+    pub change: AvailabilityChange,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    strum::EnumString,
+    strum::IntoStaticStr,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum AvailabilityChange {
+    /// The movie became available on the platform
+    Added,
+    /// The movie is not available on the platform anymore
+    Removed,
+}
+
+/// A movie that should be watched at some point. The movie itself is stored in
+/// [`AppData::movies`] under the same id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchlistEntry {
+    pub tmdb_id: u64,
+    pub priority: Priority,
+    pub added: NaiveDate,
+    pub note: Option<String>,
+    /// Name of the person who suggested the movie
+    pub suggested_by: Option<String>,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+    Serialize,
+    Deserialize,
+    strum::EnumString,
+    strum::IntoStaticStr,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -32,11 +344,104 @@ pub struct Movie {
     pub description: String,
     pub ratings: Vec<Rating>,
     pub tags: BTreeSet<u32>,
-    pub platforms: BTreeSet<Platform>,
+    /// Ids of the platforms on which the movie can be watched. Platforms with TMDB providers are
+    /// the ones included in a subscription in the region set with [`Movie::in_region`].
+    pub platforms: BTreeSet<u32>,
+    /// map of region to the offers in that region, only contains the regions of the server and of
+    /// its users
+    #[serde(default)]
+    pub availability: BTreeMap<String, Availability>,
     pub poster: Option<String>,
     pub release_date: NaiveDate,
     pub runtime: Duration,
     pub score: f64,
+    /// Incremented with every change, set by the server. Sent as the `ETag` and expected in the
+    /// `If-Match` header when the movie is replaced.
+    #[serde(default)]
+    pub revision: u64,
+}
+
+/// Inserts `rating` while keeping the `ratings` sorted from newest to oldest. Gives the rating back
+/// if the same user already has one for the same date.
+fn insert_rating(ratings: &mut Vec<Rating>, rating: Rating) -> Result<(), Rating> {
+    if ratings
+        .iter()
+        .any(|r| r.date == rating.date && r.user == rating.user)
+    {
+        return Err(rating);
+    }
+    let index = ratings.partition_point(|r| r.date > rating.date);
+    ratings.insert(index, rating);
+    Ok(())
+}
+
+/// Ratings of `user` from `ratings`.
+fn user_ratings(ratings: &[Rating], user: u32) -> Vec<Rating> {
+    ratings.iter().filter(|r| r.user == user).cloned().collect()
+}
+
+/// Puts the ratings of all users other than `user` from `old` into `new`.
+fn merge_ratings(new: &mut Vec<Rating>, old: &[Rating], user: u32) {
+    new.extend(old.iter().filter(|r| r.user != user).cloned());
+    new.sort_by_key(|r| cmp::Reverse(r.date));
+}
+
+impl Movie {
+    /// Inserts `rating` while keeping the ratings sorted from newest to oldest. Gives the rating
+    /// back if the same user already has one for the same date.
+    pub fn add_rating(&mut self, rating: Rating) -> Result<(), Rating> {
+        insert_rating(&mut self.ratings, rating)
+    }
+
+    /// Assigns all ratings to `user` and removes all tags not in `tags`, which are the tags of
+    /// that user.
+    pub fn claim(&mut self, user: u32, tags: &BTreeSet<u32>) {
+        self.ratings.iter_mut().for_each(|r| r.user = user);
+        self.tags.retain(|id| tags.contains(id));
+    }
+
+    /// The movie as seen by `user`, who owns the `tags`.
+    pub fn view(&self, user: u32, tags: &BTreeSet<u32>) -> Movie {
+        Movie {
+            ratings: user_ratings(&self.ratings, user),
+            tags: self.tags.intersection(tags).copied().collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Replaces the metadata and the ratings and tags of `user` with the ones of `new`, keeping
+    /// those of all other users. The availability is kept if `new` has none and the revision is
+    /// incremented.
+    pub fn update_for_user(&mut self, mut new: Movie, user: u32, tags: &BTreeSet<u32>) {
+        new.claim(user, tags);
+        new.revision = self.revision + 1;
+        merge_ratings(&mut new.ratings, &self.ratings, user);
+        new.tags.extend(self.tags.difference(tags));
+        if new.availability.is_empty() {
+            new.availability = std::mem::take(&mut self.availability);
+        }
+        *self = new;
+    }
+
+    /// Sets the platforms with TMDB providers to the ones included in a subscription in `region`,
+    /// keeping all others.
+    pub fn in_region(&mut self, region: &str, platforms: &BTreeMap<u32, Platform>) {
+        set_flatrate_platforms(&mut self.platforms, &self.availability, region, platforms);
+    }
+
+    /// Average of all ratings, `None` if the movie has not been watched yet.
+    pub fn average_rating(&self) -> Option<f64> {
+        if self.ratings.is_empty() {
+            return None;
+        }
+        let sum: f64 = self.ratings.iter().map(|r| r.rating as f64).sum();
+        Some(sum / self.ratings.len() as f64)
+    }
+
+    pub fn last_watched(&self) -> Option<NaiveDate> {
+        // ratings are sorted newest first
+        self.ratings.first().map(|r| r.date)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -48,14 +453,204 @@ pub struct MovieStub {
     pub poster: Option<String>,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Series {
+    pub tmdb_id: u64,
+    pub title: String,
+    pub description: String,
+    pub tags: BTreeSet<u32>,
+    /// Ids of the platforms on which the series can be watched. Platforms with TMDB providers are
+    /// the ones included in a subscription in the region set with [`Series::in_region`].
+    pub platforms: BTreeSet<u32>,
+    /// map of region to the offers in that region, only contains the regions of the server and of
+    /// its users
+    #[serde(default)]
+    pub availability: BTreeMap<String, Availability>,
+    pub poster: Option<String>,
+    pub first_air_date: Option<NaiveDate>,
+    pub score: f64,
+    /// Seasons sorted by number. Season 0 contains specials, if there are any.
+    pub seasons: Vec<Season>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Season {
+    pub number: u32,
+    pub title: String,
+    pub description: String,
+    pub poster: Option<String>,
+    pub air_date: Option<NaiveDate>,
+    /// Episodes sorted by number
+    pub episodes: Vec<Episode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Episode {
+    pub number: u32,
+    pub title: String,
+    pub description: String,
+    pub air_date: Option<NaiveDate>,
+    pub runtime: Duration,
+    /// map of user id to the dates on which that user watched the episode without rating it
+    pub watched: BTreeMap<u32, BTreeSet<NaiveDate>>,
+    pub ratings: Vec<Rating>,
+}
+
+impl Series {
+    /// Sets the platforms with TMDB providers to the ones included in a subscription in `region`,
+    /// keeping all others.
+    pub fn in_region(&mut self, region: &str, platforms: &BTreeMap<u32, Platform>) {
+        set_flatrate_platforms(&mut self.platforms, &self.availability, region, platforms);
+    }
+
+    /// Assigns all watches and ratings to `user` and removes all tags not in `tags`, which are
+    /// the tags of that user.
+    pub fn claim(&mut self, user: u32, tags: &BTreeSet<u32>) {
+        self.tags.retain(|id| tags.contains(id));
+        for episode in self.seasons.iter_mut().flat_map(|s| &mut s.episodes) {
+            let watched: BTreeSet<_> = std::mem::take(&mut episode.watched)
+                .into_values()
+                .flatten()
+                .collect();
+            if !watched.is_empty() {
+                episode.watched.insert(user, watched);
+            }
+            episode.ratings.iter_mut().for_each(|r| r.user = user);
+        }
+    }
+
+    /// The series as seen by `user`, who owns the `tags`.
+    pub fn view(&self, user: u32, tags: &BTreeSet<u32>) -> Series {
+        let mut series = Series {
+            tags: self.tags.intersection(tags).copied().collect(),
+            ..self.clone()
+        };
+        for episode in series.seasons.iter_mut().flat_map(|s| &mut s.episodes) {
+            episode.watched.retain(|&id, _| id == user);
+            episode.ratings.retain(|r| r.user == user);
+        }
+        series
+    }
+
+    /// Replaces the metadata and the watches, ratings and tags of `user` with the ones of `new`,
+    /// keeping those of all other users. The availability is kept if `new` has none.
+    pub fn update_for_user(&mut self, mut new: Series, user: u32, tags: &BTreeSet<u32>) {
+        new.claim(user, tags);
+        new.tags.extend(self.tags.difference(tags));
+        if new.availability.is_empty() {
+            new.availability = std::mem::take(&mut self.availability);
+        }
+        for season in &mut new.seasons {
+            for episode in &mut season.episodes {
+                let Some(old) = self.episode(season.number, episode.number) else {
+                    continue;
+                };
+                episode.watched.extend(
+                    old.watched
+                        .iter()
+                        .filter(|(&id, _)| id != user)
+                        .map(|(&id, dates)| (id, dates.clone())),
+                );
+                merge_ratings(&mut episode.ratings, &old.ratings, user);
+            }
+        }
+        *self = new;
+    }
+
+    pub fn episode(&self, season: u32, episode: u32) -> Option<&Episode> {
+        self.seasons
+            .iter()
+            .find(|s| s.number == season)?
+            .episodes
+            .iter()
+            .find(|e| e.number == episode)
+    }
+
+    pub fn episode_mut(&mut self, season: u32, episode: u32) -> Option<&mut Episode> {
+        self.seasons
+            .iter_mut()
+            .find(|s| s.number == season)?
+            .episodes
+            .iter_mut()
+            .find(|e| e.number == episode)
+    }
+
+    /// All episodes in order together with their season number, excluding specials.
+    pub fn regular_episodes(&self) -> impl Iterator<Item = (u32, &Episode)> {
+        self.seasons
+            .iter()
+            .filter(|season| season.number != 0)
+            .flat_map(|season| season.episodes.iter().map(|e| (season.number, e)))
+    }
+
+    pub fn last_watched(&self) -> Option<NaiveDate> {
+        self.seasons
+            .iter()
+            .flat_map(|season| &season.episodes)
+            .filter_map(Episode::last_watched)
+            .max()
+    }
+
+    /// The first unwatched episode after the last watched one, or the very first episode if
+    /// nothing has been watched yet. `None` once the series has been watched to the end.
+    pub fn next_episode(&self) -> Option<(u32, &Episode)> {
+        let next = self
+            .regular_episodes()
+            .enumerate()
+            .filter(|(_, (_, episode))| episode.is_watched())
+            .last()
+            .map_or(0, |(idx, _)| idx + 1);
+        self.regular_episodes().nth(next)
+    }
+}
+
+impl Episode {
+    /// Inserts `rating` while keeping the ratings sorted from newest to oldest. Gives the rating
+    /// back if the same user already has one for the same date.
+    pub fn add_rating(&mut self, rating: Rating) -> Result<(), Rating> {
+        insert_rating(&mut self.ratings, rating)
+    }
+
+    /// Whether any user watched the episode. Use on a [view](Series::view) to check a single
+    /// user.
+    pub fn is_watched(&self) -> bool {
+        self.watched.values().any(|dates| !dates.is_empty()) || !self.ratings.is_empty()
+    }
+
+    pub fn last_watched(&self) -> Option<NaiveDate> {
+        // ratings are sorted newest first
+        self.watched
+            .values()
+            .filter_map(|dates| dates.last())
+            .max()
+            .copied()
+            .max(self.ratings.first().map(|r| r.date))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeriesStub {
+    pub tmdb_id: u64,
+    pub title: String,
+    pub description: String,
+    pub first_air_date: Option<NaiveDate>,
+    pub poster: Option<String>,
+}
+
 pub type Color = (u8, u8, u8);
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
 pub struct Tag {
     pub id: u32,
+    /// Id of the user owning the tag, set by the server
+    #[serde(default)]
+    pub user: u32,
     pub name: String,
     pub color: Color,
     pub icon: Option<Cow<'static, str>>,
+    /// Incremented with every change, set by the server
+    #[serde(default)]
+    pub revision: u64,
 }
 
 const fn default_watch_speed() -> f32 {
@@ -73,49 +668,120 @@ pub struct Rating {
     #[serde(default = "default_watch_speed")]
     pub speed: f32,
 
-    pub platform: Option<Platform>,
+    /// Id of the platform the movie or episode was watched on
+    pub platform: Option<u32>,
     #[serde(default)]
     pub tags: BTreeSet<u32>,
+
+    /// Id of the user who gave the rating, set by the server
+    #[serde(default)]
+    pub user: u32,
 }
 
-#[derive(
-    Debug,
-    Clone,
-    Copy,
-    PartialEq,
-    Eq,
-    PartialOrd,
-    Ord,
-    Serialize,
-    Deserialize,
-    strum::Display,
-    strum::EnumIter,
-    strum::FromRepr,
-)]
-#[repr(u8)]
-pub enum Platform {
-    #[serde(rename = "Disney+")]
-    #[strum(serialize = "Disney+")]
-    DisneyPlus,
-    Jellyfin,
-    Netflix,
-    #[serde(rename = "Prime Video")]
-    #[strum(serialize = "Prime Video")]
-    PrimeVideo,
-    YouTube,
-    #[serde(rename = "DVD")]
-    #[strum(serialize = "DVD")]
-    Dvd,
-    BluRay,
-    Cinema,
-    #[serde(rename = "TV")]
-    #[strum(serialize = "TV")]
-    Tv,
-    Airplane,
-    #[serde(rename = "Apple TV")]
-    #[strum(serialize = "Apple TV")]
-    AppleTv,
-    Stan,
+/// Converts a rating of 0.5 to 5 stars in half-star steps, as used by Letterboxd, Goodreads and
+/// StoryGraph, to the `1..=10` scale of [`Rating::rating`]. 0 stars usually means unrated.
+pub fn rating_from_stars(stars: f32) -> Option<u8> {
+    let rating = (stars * 2.).round();
+    (1. ..=10.).contains(&rating).then_some(rating as u8)
+}
+
+/// A service or medium on which movies and series are watched.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Platform {
+    pub id: u32,
+    pub name: String,
+    pub color: Color,
+    pub icon: Option<Cow<'static, str>>,
+    /// Ids of the TMDB watch providers belonging to the platform, empty for physical media,
+    /// cinemas or self-hosted servers
+    #[serde(default)]
+    pub tmdb_providers: BTreeSet<u64>,
+}
+
+/// A service offering a movie or series, as reported by TMDB.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Provider {
+    pub tmdb_id: u64,
+    pub name: String,
+}
+
+/// Where a movie or series can be watched in one region, powered by JustWatch.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Availability {
+    /// Included in a subscription
+    pub flatrate: BTreeSet<Provider>,
+    /// Free to watch, possibly with ads
+    pub free: BTreeSet<Provider>,
+    pub rent: BTreeSet<Provider>,
+    pub buy: BTreeSet<Provider>,
+}
+
+/// Ids of the `platforms` which `providers` belong to.
+pub fn provider_platforms<'a>(
+    providers: impl IntoIterator<Item = &'a Provider>,
+    platforms: &BTreeMap<u32, Platform>,
+) -> BTreeSet<u32> {
+    providers
+        .into_iter()
+        .filter_map(|provider| {
+            platforms
+                .values()
+                .find(|platform| platform.tmdb_providers.contains(&provider.tmdb_id))
+        })
+        .map(|platform| platform.id)
+        .collect()
+}
+
+/// Replaces the ids of platforms with TMDB providers in `ids` with the ones included in a
+/// subscription in `region`, none if the region is unknown.
+fn set_flatrate_platforms(
+    ids: &mut BTreeSet<u32>,
+    availability: &BTreeMap<String, Availability>,
+    region: &str,
+    platforms: &BTreeMap<u32, Platform>,
+) {
+    ids.retain(|id| {
+        platforms
+            .get(id)
+            .is_none_or(|platform| platform.tmdb_providers.is_empty())
+    });
+    if let Some(offers) = availability.get(region) {
+        ids.extend(provider_platforms(&offers.flatrate, platforms));
+    }
+}
+
+/// The platforms of new installations, which are the ones that used to be built in. Their ids are
+/// also used when migrating data of older versions.
+pub fn default_platforms() -> BTreeMap<u32, Platform> {
+    let platform = |id, name: &str, color, tmdb_providers: &[u64]| {
+        let platform = Platform {
+            id,
+            name: name.to_owned(),
+            color,
+            icon: None,
+            tmdb_providers: tmdb_providers.iter().copied().collect(),
+        };
+        (id, platform)
+    };
+    BTreeMap::from([
+        platform(1, "Disney+", (17, 60, 207), &[337]),
+        platform(2, "Jellyfin", (170, 92, 195), &[]),
+        // Netflix, Netflix Kids, Netflix basic with Ads
+        platform(3, "Netflix", (229, 9, 20), &[8, 175, 1796]),
+        // Amazon Prime Video and Amazon Video (Prime Video uses both 9 and 119 depending on the
+        // region), Amazon Prime Video with Ads
+        platform(4, "Prime Video", (0, 168, 225), &[9, 10, 119, 2100]),
+        // YouTube Premium, YouTube, YouTube Free
+        platform(5, "YouTube", (255, 0, 0), &[188, 192, 235]),
+        platform(6, "DVD", (110, 110, 110), &[]),
+        platform(7, "BluRay", (0, 144, 206), &[]),
+        platform(8, "Cinema", (180, 30, 60), &[]),
+        platform(9, "TV", (90, 90, 90), &[]),
+        platform(10, "Airplane", (70, 130, 180), &[]),
+        // Apple TV, Apple TV+
+        platform(11, "Apple TV", (0, 0, 0), &[2, 350]),
+        platform(12, "Stan", (0, 114, 206), &[21]),
+    ])
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -130,13 +796,260 @@ pub struct Book {
     pub tags: BTreeSet<u32>,
     pub release_date: Option<NaiveDate>,
     pub score: Option<f64>,
+    /// Incremented with every change, set by the server
+    #[serde(default)]
+    pub revision: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Reading {
+    /// Id of the reader, set by the server
+    #[serde(default)]
+    pub user: u32,
     pub pages_read: BTreeMap<NaiveDate, u16>,
     pub rating: Option<Rating>,
     pub isbn: Option<String>,
     pub start_page: u16,
     pub end_page: u16,
 }
+
+impl Book {
+    /// Assigns all readings to `user` and removes all tags not in `tags`, which are the tags of
+    /// that user.
+    pub fn claim(&mut self, user: u32, tags: &BTreeSet<u32>) {
+        for reading in &mut self.readings {
+            reading.user = user;
+            if let Some(rating) = &mut reading.rating {
+                rating.user = user;
+            }
+        }
+        self.tags.retain(|id| tags.contains(id));
+    }
+
+    /// The book as seen by `user`, who owns the `tags`.
+    pub fn view(&self, user: u32, tags: &BTreeSet<u32>) -> Book {
+        Book {
+            readings: self
+                .readings
+                .iter()
+                .filter(|reading| reading.user == user)
+                .cloned()
+                .collect(),
+            tags: self.tags.intersection(tags).copied().collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Replaces the metadata and the readings and tags of `user` with the ones of `new`, keeping
+    /// those of all other users. The revision is incremented.
+    pub fn update_for_user(&mut self, mut new: Book, user: u32, tags: &BTreeSet<u32>) {
+        new.claim(user, tags);
+        new.revision = self.revision + 1;
+        let mut readings: Vec<_> = self
+            .readings
+            .iter()
+            .filter(|reading| reading.user != user)
+            .cloned()
+            .collect();
+        readings.append(&mut new.readings);
+        new.readings = readings;
+        new.tags.extend(self.tags.difference(tags));
+        *self = new;
+    }
+
+    /// The `idx`th reading of `user`.
+    pub fn reading_mut(&mut self, user: u32, idx: usize) -> Option<&mut Reading> {
+        self.readings
+            .iter_mut()
+            .filter(|reading| reading.user == user)
+            .nth(idx)
+    }
+
+    /// Average rating of all rated readings.
+    pub fn average_rating(&self) -> Option<f64> {
+        let ratings: Vec<_> = self
+            .readings
+            .iter()
+            .filter_map(|reading| Some(reading.rating.as_ref()?.rating as f64))
+            .collect();
+        if ratings.is_empty() {
+            return None;
+        }
+        Some(ratings.iter().sum::<f64>() / ratings.len() as f64)
+    }
+
+    /// Date of the most recent entry in any reading.
+    pub fn last_activity(&self) -> Option<NaiveDate> {
+        self.readings
+            .iter()
+            .filter_map(|reading| reading.pages_read.keys().next_back())
+            .max()
+            .copied()
+    }
+}
+
+impl Reading {
+    /// Number of pages between `start_page` and `end_page`, both inclusive.
+    pub fn pages_total(&self) -> u16 {
+        self.end_page
+            .saturating_add(1)
+            .saturating_sub(self.start_page)
+    }
+
+    pub fn pages_done(&self) -> u32 {
+        self.pages_read.values().map(|&pages| pages as u32).sum()
+    }
+
+    /// A reading is finished once it has been rated or all of its pages were read.
+    pub fn is_finished(&self) -> bool {
+        self.rating.is_some()
+            || (!self.pages_read.is_empty() && self.pages_done() >= self.pages_total() as u32)
+    }
+
+    /// Date of the last entry or the rating of a finished reading.
+    pub fn finished_on(&self) -> Option<NaiveDate> {
+        if !self.is_finished() {
+            return None;
+        }
+        self.pages_read
+            .keys()
+            .next_back()
+            .copied()
+            .max(self.rating.as_ref().map(|rating| rating.date))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stars() {
+        assert_eq!(rating_from_stars(0.5), Some(1));
+        assert_eq!(rating_from_stars(3.5), Some(7));
+        assert_eq!(rating_from_stars(5.), Some(10));
+        assert_eq!(rating_from_stars(0.), None);
+    }
+
+    fn episode(number: u32, watched: &[NaiveDate]) -> Episode {
+        Episode {
+            number,
+            title: String::new(),
+            description: String::new(),
+            air_date: None,
+            runtime: Duration::ZERO,
+            watched: match watched {
+                [] => BTreeMap::new(),
+                dates => BTreeMap::from([(1, dates.iter().copied().collect())]),
+            },
+            ratings: vec![],
+        }
+    }
+
+    fn season(number: u32, episodes: Vec<Episode>) -> Season {
+        Season {
+            number,
+            title: String::new(),
+            description: String::new(),
+            poster: None,
+            air_date: None,
+            episodes,
+        }
+    }
+
+    #[test]
+    fn next_episode() {
+        let day = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let mut series = Series {
+            tmdb_id: 1,
+            title: String::new(),
+            description: String::new(),
+            tags: BTreeSet::new(),
+            platforms: BTreeSet::new(),
+            availability: BTreeMap::new(),
+            poster: None,
+            first_air_date: None,
+            score: 0.,
+            seasons: vec![
+                season(0, vec![episode(1, &[])]),
+                season(1, vec![episode(1, &[]), episode(2, &[day])]),
+                season(2, vec![episode(1, &[])]),
+            ],
+        };
+        let next = |series: &Series| series.next_episode().map(|(s, e)| (s, e.number));
+
+        // skipped episodes before the last watched one are not suggested again
+        assert_eq!(next(&series), Some((2, 1)));
+        series.seasons[1].episodes[1].watched.clear();
+        assert_eq!(next(&series), Some((1, 1)));
+        series
+            .episode_mut(2, 1)
+            .unwrap()
+            .watched
+            .insert(1, [day].into());
+        assert_eq!(next(&series), None);
+        assert_eq!(series.last_watched(), Some(day));
+    }
+
+    #[test]
+    fn update_for_user() {
+        let day = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let rating = |user, rating| Rating {
+            date: day,
+            rating,
+            speed: 1.,
+            platform: None,
+            tags: BTreeSet::new(),
+            user,
+        };
+        let mut movie = Movie {
+            imdb_id: None,
+            tmdb_id: 1,
+            title: "Old".into(),
+            description: String::new(),
+            ratings: vec![rating(1, 5), rating(2, 7)],
+            tags: [10, 20].into(),
+            platforms: BTreeSet::new(),
+            availability: BTreeMap::new(),
+            poster: None,
+            release_date: day,
+            runtime: Duration::ZERO,
+            score: 0.,
+            revision: 0,
+        };
+        let tags = BTreeSet::from([20, 21]);
+        let mut new = movie.view(2, &tags);
+        assert_eq!(new.ratings, [rating(2, 7)]);
+        assert_eq!(new.tags, [20].into());
+
+        new.title = "New".into();
+        new.ratings = vec![rating(1, 9)];
+        new.tags = [10, 21].into();
+        movie.update_for_user(new, 2, &tags);
+        assert_eq!(movie.title, "New");
+        assert_eq!(movie.ratings, [rating(2, 9), rating(1, 5)]);
+        assert_eq!(movie.tags, [10, 21].into());
+    }
+
+    #[test]
+    fn in_region() {
+        let platforms = default_platforms();
+        let provider = |tmdb_id| Provider {
+            tmdb_id,
+            name: String::new(),
+        };
+        let availability = BTreeMap::from([(
+            "DE".to_owned(),
+            Availability {
+                flatrate: [provider(175), provider(0)].into(),
+                ..Default::default()
+            },
+        )]);
+        // Jellyfin is kept, Prime Video is not available in the region and Netflix is
+        let mut ids = BTreeSet::from([2, 4]);
+        set_flatrate_platforms(&mut ids, &availability, "DE", &platforms);
+        assert_eq!(ids, [2, 3].into());
+        set_flatrate_platforms(&mut ids, &availability, "AT", &platforms);
+        assert_eq!(ids, [2].into());
+    }
+}
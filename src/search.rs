@@ -0,0 +1,426 @@
+//! Full-text search over movies, series, books and tags.
+//!
+//! The index is kept in memory and updated through [`AppState::save`], so every change that is
+//! persisted is also searchable. Tags are private, so their names are not indexed as part of the
+//! movies, series and books. Instead, everything tagged with a matching tag is found if the tag is
+//! visible to the searching user.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use actix_web::{
+    get,
+    web::{Data, Query},
+    HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{schema::AppData, storage::Change, users::CurrentUser, AppState};
+
+const TITLE_WEIGHT: f64 = 3.;
+const AUTHOR_WEIGHT: f64 = 2.;
+const TAG_WEIGHT: f64 = 1.5;
+const DESCRIPTION_WEIGHT: f64 = 1.;
+
+/// Factor applied to terms which only start with a query word
+const PREFIX_MATCH: f64 = 0.7;
+/// Factor applied to terms within one edit of a query word
+const ONE_TYPO: f64 = 0.5;
+/// Factor applied to terms within two edits of a long query word
+const TWO_TYPOS: f64 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "kind", content = "id", rename_all = "lowercase")]
+pub enum DocId {
+    Movie(u64),
+    Series(u64),
+    Book(u32),
+    Tag(u32),
+}
+
+struct Doc {
+    title: String,
+    terms: HashSet<String>,
+    tags: BTreeSet<u32>,
+}
+
+#[derive(Default)]
+pub struct SearchIndex {
+    /// map of term to the weighted term frequency in every document containing it
+    postings: HashMap<String, HashMap<DocId, f64>>,
+    docs: HashMap<DocId, Doc>,
+    /// map of tag id to the documents tagged with it
+    tagged: HashMap<u32, HashSet<DocId>>,
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Edit distance between `a` and `b` counting transpositions of adjacent characters as a single
+/// edit, or `None` if it is larger than `max`.
+fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<_> = a.chars().collect();
+    let b: Vec<_> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let mut before_prev = vec![0; b.len() + 1];
+    let mut prev: Vec<_> = (0..=b.len()).collect();
+    for i in 0..a.len() {
+        let mut row = vec![i + 1; b.len() + 1];
+        for j in 0..b.len() {
+            row[j + 1] = (prev[j] + (a[i] != b[j]) as usize)
+                .min(prev[j + 1] + 1)
+                .min(row[j] + 1);
+            if i > 0 && j > 0 && a[i] == b[j - 1] && a[i - 1] == b[j] {
+                row[j + 1] = row[j + 1].min(before_prev[j - 1] + 1);
+            }
+        }
+        if row.iter().all(|&dist| dist > max) {
+            return None;
+        }
+        before_prev = std::mem::replace(&mut prev, row);
+    }
+    Some(prev[b.len()]).filter(|&dist| dist <= max)
+}
+
+/// How well `term` matches the query `word`, `None` if it does not match at all.
+fn match_quality(word: &str, term: &str) -> Option<f64> {
+    if word == term {
+        return Some(1.);
+    }
+    let len = word.chars().count();
+    if len >= 2 && term.starts_with(word) {
+        return Some(PREFIX_MATCH);
+    }
+    match len {
+        0..=3 => None,
+        4..=7 => edit_distance(word, term, 1).map(|_| ONE_TYPO),
+        _ => edit_distance(word, term, 2).map(|dist| if dist == 1 { ONE_TYPO } else { TWO_TYPOS }),
+    }
+}
+
+impl SearchIndex {
+    pub fn new(data: &AppData) -> Self {
+        let mut index = Self::default();
+        index.rebuild(data);
+        index
+    }
+
+    pub fn rebuild(&mut self, data: &AppData) {
+        self.postings.clear();
+        self.docs.clear();
+        self.tagged.clear();
+        let ids = data
+            .movies
+            .keys()
+            .map(|&id| DocId::Movie(id))
+            .chain(data.series.keys().map(|&id| DocId::Series(id)))
+            .chain(data.books.keys().map(|&id| DocId::Book(id)))
+            .chain(data.tags.keys().map(|&id| DocId::Tag(id)));
+        for id in ids.collect::<Vec<_>>() {
+            self.reindex(data, id);
+        }
+    }
+
+    /// Updates the index after `change` has been applied to `data`.
+    pub fn update(&mut self, data: &AppData, change: &Change) {
+        match *change {
+            Change::Movie(id) => self.reindex(data, DocId::Movie(id)),
+            Change::Series(id) => self.reindex(data, DocId::Series(id)),
+            Change::Book(id) => self.reindex(data, DocId::Book(id)),
+            Change::Tag(id) => self.reindex(data, DocId::Tag(id)),
+            Change::All => self.rebuild(data),
+            Change::CacheEntry(_)
+            | Change::TvCacheEntry(_)
+            | Change::ClearCache
+            | Change::User(_)
+            | Change::Goals(_)
+            | Change::WatchlistEntry(..)
+            | Change::Password(_)
+            | Change::ApiToken(_)
+            | Change::Platform(_)
+            | Change::AvailabilityHistory(_)
+            | Change::Notifications(_)
+            | Change::Webhook(_) => {}
+        }
+    }
+
+    fn remove(&mut self, id: DocId) {
+        let Some(Doc { terms, tags, .. }) = self.docs.remove(&id) else {
+            return;
+        };
+        for tag in tags {
+            if let Some(docs) = self.tagged.get_mut(&tag) {
+                docs.remove(&id);
+                if docs.is_empty() {
+                    self.tagged.remove(&tag);
+                }
+            }
+        }
+        for term in terms {
+            if let Some(docs) = self.postings.get_mut(&term) {
+                docs.remove(&id);
+                if docs.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// Re-reads the document `id` from `data`, removing it if it no longer exists.
+    fn reindex(&mut self, data: &AppData, id: DocId) {
+        self.remove(id);
+
+        let (title, tags, fields) = match id {
+            DocId::Movie(id) => {
+                let Some(movie) = data.movies.get(&id) else {
+                    return;
+                };
+                (
+                    movie.title.clone(),
+                    movie.tags.clone(),
+                    vec![
+                        (movie.title.clone(), TITLE_WEIGHT),
+                        (movie.description.clone(), DESCRIPTION_WEIGHT),
+                    ],
+                )
+            }
+            DocId::Series(id) => {
+                let Some(series) = data.series.get(&id) else {
+                    return;
+                };
+                (
+                    series.title.clone(),
+                    series.tags.clone(),
+                    vec![
+                        (series.title.clone(), TITLE_WEIGHT),
+                        (series.description.clone(), DESCRIPTION_WEIGHT),
+                    ],
+                )
+            }
+            DocId::Book(id) => {
+                let Some(book) = data.books.get(&id) else {
+                    return;
+                };
+                (
+                    book.title.clone(),
+                    book.tags.clone(),
+                    vec![
+                        (book.title.clone(), TITLE_WEIGHT),
+                        (book.authors.join(" "), AUTHOR_WEIGHT),
+                        (book.description.clone(), DESCRIPTION_WEIGHT),
+                    ],
+                )
+            }
+            DocId::Tag(id) => {
+                let Some(tag) = data.tags.get(&id) else {
+                    return;
+                };
+                (
+                    tag.name.clone(),
+                    BTreeSet::new(),
+                    vec![(tag.name.clone(), TITLE_WEIGHT)],
+                )
+            }
+        };
+
+        let mut frequencies = HashMap::<String, f64>::new();
+        for (text, weight) in fields {
+            for term in tokenize(&text) {
+                *frequencies.entry(term).or_default() += weight;
+            }
+        }
+        for (term, frequency) in &frequencies {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(id, *frequency);
+        }
+        for &tag in &tags {
+            self.tagged.entry(tag).or_default().insert(id);
+        }
+        self.docs.insert(
+            id,
+            Doc {
+                title,
+                terms: frequencies.into_keys().collect(),
+                tags,
+            },
+        );
+    }
+
+    /// Returns the best matches for `query` among the documents for which `visible` is true, best
+    /// first. Movies, series and books also match the names of their tags if the tag is visible.
+    pub fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        visible: impl Fn(DocId) -> bool,
+    ) -> Vec<SearchHit> {
+        let words: Vec<_> = tokenize(query).collect();
+        let doc_count = self.docs.len() as f64;
+        let mut scores = HashMap::<DocId, (f64, usize)>::new();
+        for word in &words {
+            let mut word_scores = HashMap::<DocId, f64>::new();
+            for (term, docs) in &self.postings {
+                let Some(quality) = match_quality(word, term) else {
+                    continue;
+                };
+                let df = docs.len() as f64;
+                let idf = (1. + (doc_count - df + 0.5) / (df + 0.5)).ln();
+                for (doc, frequency) in docs {
+                    // dampen repeated occurrences
+                    let score = quality * idf * (1. + frequency.ln_1p());
+                    let best = word_scores.entry(*doc).or_default();
+                    *best = best.max(score);
+
+                    let DocId::Tag(tag) = *doc else {
+                        continue;
+                    };
+                    if !visible(*doc) {
+                        continue;
+                    }
+                    let score = quality * idf * (1. + TAG_WEIGHT.ln_1p());
+                    for tagged in self.tagged.get(&tag).into_iter().flatten() {
+                        let best = word_scores.entry(*tagged).or_default();
+                        *best = best.max(score);
+                    }
+                }
+            }
+            for (doc, score) in word_scores {
+                let entry = scores.entry(doc).or_default();
+                entry.0 += score;
+                entry.1 += 1;
+            }
+        }
+
+        let mut hits: Vec<_> = scores
+            .into_iter()
+            .filter(|(id, _)| visible(*id))
+            .map(|(id, (score, matched_words))| SearchHit {
+                id,
+                title: self.docs[&id].title.clone(),
+                // prefer documents matching all words of the query
+                score: score * matched_words as f64 / words.len() as f64,
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(limit);
+        hits
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    id: DocId,
+    title: String,
+    score: f64,
+}
+
+const fn default_limit() -> usize {
+    20
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+#[get("/api/search")]
+async fn search(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Query(SearchQuery { q, limit }): Query<SearchQuery>,
+) -> impl Responder {
+    let tags = state.data.lock().await.user_tags(user.id);
+    let hits = state
+        .search
+        .lock()
+        .expect("mutex should not be poisoned")
+        .search(&q, limit, |id| match id {
+            DocId::Tag(id) => tags.contains(&id),
+            _ => true,
+        });
+    HttpResponse::Ok().json(hits)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::schema::{Book, Tag};
+
+    #[test]
+    fn typos() {
+        assert_eq!(edit_distance("matrix", "matirx", 2), Some(1));
+        assert_eq!(edit_distance("poznanski", "poznansky", 1), Some(1));
+        assert_eq!(edit_distance("dune", "arrival", 2), None);
+        assert_eq!(match_quality("matrx", "matrix"), Some(ONE_TYPO));
+        assert_eq!(match_quality("eps", "epsilon"), Some(PREFIX_MATCH));
+    }
+
+    #[test]
+    fn ranking() {
+        let mut data = AppData::default();
+        data.tags.insert(
+            1,
+            Tag {
+                id: 1,
+                name: "Thriller".into(),
+                ..Default::default()
+            },
+        );
+        for (id, title, description) in [
+            (1, "Erebos", "A computer game that watches its players"),
+            (2, "Game Theory", "An introduction"),
+        ] {
+            data.books.insert(
+                id,
+                Book {
+                    id,
+                    olid: None,
+                    title: title.into(),
+                    description: description.into(),
+                    authors: vec!["Ursula Poznanski".into()],
+                    readings: vec![],
+                    tags: [1].into(),
+                    release_date: None,
+                    score: None,
+                    revision: 0,
+                },
+            );
+        }
+        let mut index = SearchIndex::new(&data);
+
+        let hits = index.search("game", 10, |_| true);
+        assert_eq!(hits[0].id, DocId::Book(2));
+        assert_eq!(hits[1].id, DocId::Book(1));
+        let hits = index.search("poznansky erebos", 10, |_| true);
+        assert_eq!(hits[0].id, DocId::Book(1));
+
+        data.tags.get_mut(&1).unwrap().name = "Mystery".into();
+        index.update(&data, &Change::Tag(1));
+        assert!(index.search("thriller", 10, |_| true).is_empty());
+        assert_eq!(index.search("mystery", 10, |_| true).len(), 3);
+        // the names of tags of other users are not matched
+        let foreign_tags = index.search("mystery", 10, |id| !matches!(id, DocId::Tag(_)));
+        assert!(foreign_tags.is_empty());
+        assert_eq!(
+            index.search("mystery erebos", 10, |id| id != DocId::Tag(1))[0].id,
+            DocId::Book(1)
+        );
+
+        data.books.get_mut(&1).unwrap().tags.clear();
+        index.update(&data, &Change::Book(1));
+        assert_eq!(index.search("mystery", 10, |_| true).len(), 2);
+
+        data.books.remove(&2);
+        index.update(&data, &Change::Book(2));
+        assert_eq!(index.search("game", 10, |_| true).len(), 1);
+    }
+}
@@ -1,34 +1,44 @@
-use std::{
-    cmp,
-    collections::{btree_map::Entry, hash_map},
-};
+use std::collections::btree_map::Entry;
 
 use actix_web::{
     delete, patch, post, put,
     web::{Data, Json, Path, Query},
-    HttpResponse, Responder,
+    HttpRequest, HttpResponse, Responder,
 };
 use chrono::NaiveDate;
+use itertools::Itertools;
 
 use crate::{
-    schema::{Book, Movie, Rating, Reading, Tag},
+    events::{self, EntityChange},
+    revisions,
+    schema::{AppData, Book, Episode, Movie, Rating, Reading, Series, Tag},
+    storage::Change,
+    users::{Admin, CurrentUser},
     AppState,
 };
 
 #[delete("/api/cache")]
-async fn clear_cache(data: Data<AppState>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
+async fn clear_cache(state: Data<AppState>, _: Admin) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
     data_lock.tmdb_cache.clear();
-    match crate::save_to_disk(&data_lock).await {
+    data_lock.tmdb_tv_cache.clear();
+    match state.save(&data_lock, Change::ClearCache).await {
         Ok(()) => HttpResponse::Ok().finish(),
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[post("/api/movie")]
-async fn post_movie(data: Data<AppState>, Json(movie): Json<Movie>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.movies.entry(movie.tmdb_id) {
+async fn post_movie(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(mut movie): Json<Movie>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = movie.tmdb_id;
+    movie.claim(user.id, &data_lock.user_tags(user.id));
+    movie.revision = 0;
+    match data_lock.movies.entry(id) {
         Entry::Vacant(entry) => {
             entry.insert(movie);
         }
@@ -36,116 +46,237 @@ async fn post_movie(data: Data<AppState>, Json(movie): Json<Movie>) -> impl Resp
             return HttpResponse::Conflict().body("a movie with that ID is already present")
         }
     }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    let change = EntityChange::Movie {
+        tmdb_id: id,
+        before: None,
+        after: data_lock.movies.get(&id).cloned(),
+    };
+    match state.save(&data_lock, Change::Movie(id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[patch("/api/movie")]
-async fn patch_movie(data: Data<AppState>, Json(movie): Json<Movie>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.movies.entry(movie.tmdb_id) {
-        Entry::Vacant(_) => {
-            return HttpResponse::NotFound()
-                .body(format!("movie with ID {} does not exist", movie.tmdb_id))
+async fn patch_movie(
+    state: Data<AppState>,
+    user: CurrentUser,
+    req: HttpRequest,
+    Json(movie): Json<Movie>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = movie.tmdb_id;
+    let tags = data_lock.user_tags(user.id);
+    let before = match data_lock.movies.get_mut(&id) {
+        None => return HttpResponse::NotFound().body(format!("movie with ID {id} does not exist")),
+        Some(existing) => {
+            let current = existing.view(user.id, &tags);
+            if let Err(resp) = revisions::check(&req, existing.revision, &current) {
+                return resp;
+            }
+            let before = existing.clone();
+            existing.update_for_user(movie, user.id, &tags);
+            before
         }
-        Entry::Occupied(mut entry) => {
-            *entry.get_mut() = movie;
+    };
+    let before_revision = before.revision;
+    let change = EntityChange::Movie {
+        tmdb_id: id,
+        before: Some(before),
+        after: data_lock.movies.get(&id).cloned(),
+    };
+    match state.save(&data_lock, Change::Movie(id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok()
+                .insert_header(revisions::etag(before_revision + 1))
+                .finish()
         }
-    }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[delete("/api/movie/{id}")]
-async fn delete_movie(data: Data<AppState>, id: Path<u64>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    if data_lock.movies.remove(&id).is_none() {
+async fn delete_movie(state: Data<AppState>, user: CurrentUser, id: Path<u64>) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let Some(movie) = data_lock.movies.get(&id) else {
         return HttpResponse::NotFound().body(format!("movie with ID {id} does not exist"));
-    }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    };
+    // deleting the movie also removes the watchlist entries and tags of everyone else
+    let used_by_others = movie.ratings.iter().any(|rating| rating.user != user.id)
+        || data_lock
+            .watchlists
+            .iter()
+            .any(|(&owner, watchlist)| owner != user.id && watchlist.contains_key(&id))
+        || movie
+            .tags
+            .iter()
+            .filter_map(|tag| data_lock.tags.get(tag))
+            .any(|tag| tag.user != user.id);
+    if !user.admin && used_by_others {
+        return HttpResponse::Conflict().body(format!(
+            "movie with ID {id} is rated, tagged or on the watchlist of other users"
+        ));
+    }
+    let change = EntityChange::Movie {
+        tmdb_id: *id,
+        before: data_lock.movies.remove(&id),
+        after: None,
+    };
+    // the storage removes the watchlist entries and the history together with the movie
+    for watchlist in data_lock.watchlists.values_mut() {
+        watchlist.remove(&id);
+    }
+    data_lock.availability_history.remove(&id);
+    match state.save(&data_lock, Change::Movie(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[post("/api/tag")]
-async fn post_tag(data: Data<AppState>, Json(tag): Json<Tag>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
+async fn post_tag(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(tag): Json<Tag>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
     let tag_id = loop {
         let new_id = rand::random::<u32>();
         if !data_lock.tags.contains_key(&new_id) {
             break new_id;
         }
     };
-    let new_tag = Tag { id: tag_id, ..tag };
+    let new_tag = Tag {
+        id: tag_id,
+        user: user.id,
+        revision: 0,
+        ..tag
+    };
     let resp = HttpResponse::Ok().json(&new_tag);
+    let change = EntityChange::Tag {
+        tag_id,
+        before: None,
+        after: Some(new_tag.clone()),
+    };
     data_lock.tags.insert(tag_id, new_tag);
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => resp,
+    match state.save(&data_lock, Change::Tag(tag_id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            resp
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[patch("/api/tag")]
-async fn patch_tag(data: Data<AppState>, Json(tag): Json<Tag>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.tags.entry(tag.id) {
-        hash_map::Entry::Vacant(_) => {
-            return HttpResponse::NotFound().body(format!("tag with ID {} does not exist", tag.id))
+async fn patch_tag(
+    state: Data<AppState>,
+    user: CurrentUser,
+    req: HttpRequest,
+    Json(tag): Json<Tag>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = tag.id;
+    let revision;
+    let change = match data_lock.tags.get_mut(&id) {
+        Some(existing) if existing.user == user.id => {
+            if let Err(resp) = revisions::check(&req, existing.revision, existing) {
+                return resp;
+            }
+            let new_tag = Tag {
+                user: user.id,
+                revision: existing.revision + 1,
+                ..tag
+            };
+            revision = new_tag.revision;
+            EntityChange::Tag {
+                tag_id: id,
+                before: Some(std::mem::replace(existing, new_tag.clone())),
+                after: Some(new_tag),
+            }
         }
-        hash_map::Entry::Occupied(mut entry) => {
-            *entry.get_mut() = tag;
+        _ => return HttpResponse::NotFound().body(format!("tag with ID {id} does not exist")),
+    };
+    match state.save(&data_lock, Change::Tag(id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok()
+                .insert_header(revisions::etag(revision))
+                .finish()
         }
-    }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[delete("/api/tag/{id}")]
-async fn delete_tag(data: Data<AppState>, id: Path<u32>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    if data_lock.tags.remove(&id).is_none() {
+async fn delete_tag(state: Data<AppState>, user: CurrentUser, id: Path<u32>) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    if data_lock
+        .tags
+        .get(&id)
+        .is_none_or(|tag| tag.user != user.id)
+    {
         return HttpResponse::NotFound().body(format!("tag with ID {id} does not exist"));
     }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    let change = EntityChange::Tag {
+        tag_id: *id,
+        before: data_lock.tags.remove(&id),
+        after: None,
+    };
+    match state.save(&data_lock, Change::Tag(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[put("/api/movie/{id}/rating")]
 async fn movie_put_rating(
-    data: Data<AppState>,
+    state: Data<AppState>,
+    user: CurrentUser,
     id: Path<u64>,
-    Json(rating): Json<Rating>,
+    Json(mut rating): Json<Rating>,
 ) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
+    rating.user = user.id;
+    let change = EntityChange::MovieRating {
+        tmdb_id: *id,
+        before: None,
+        after: Some(rating.clone()),
+    };
+    let mut data_lock = state.data.lock().await;
     match data_lock.movies.get_mut(&id) {
         Some(movie) => {
-            let insert_index = match movie
-                .ratings
-                .binary_search_by_key(&cmp::Reverse(rating.date), |w| cmp::Reverse(w.date))
-            {
-                Ok(_) => {
-                    return HttpResponse::Conflict().body(format!(
-                        "movie with ID {id} already has a rating set for {}",
-                        rating.date.format("%Y-%m-%d")
-                    ))
-                }
-                Err(index) => index,
-            };
-            movie.ratings.insert(insert_index, rating);
+            if let Err(rating) = movie.add_rating(rating) {
+                return HttpResponse::Conflict().body(format!(
+                    "movie with ID {id} already has a rating set for {}",
+                    rating.date.format("%Y-%m-%d")
+                ));
+            }
+            movie.revision += 1;
         }
         None => return HttpResponse::NotFound().body(format!("movie with ID {id} does not exist")),
     }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    match state.save(&data_lock, Change::Movie(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
@@ -155,32 +286,39 @@ struct RatingDateQuery {
     date: NaiveDate,
 }
 
+/// Index of the rating of `user` for `date` in `ratings`.
+fn rating_index(ratings: &[Rating], user: u32, date: NaiveDate) -> Option<usize> {
+    ratings
+        .iter()
+        .position(|rating| rating.user == user && rating.date == date)
+}
+
 #[patch("/api/movie/{id}/rating")]
 async fn movie_patch_rating(
-    data: Data<AppState>,
+    state: Data<AppState>,
+    user: CurrentUser,
     id: Path<u64>,
     Query(RatingDateQuery { date }): Query<RatingDateQuery>,
-    Json(rating): Json<Rating>,
+    Json(mut rating): Json<Rating>,
 ) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.movies.get_mut(&id) {
-        Some(movie) => match movie
-            .ratings
-            .binary_search_by_key(&cmp::Reverse(date), |w| cmp::Reverse(w.date))
-        {
-            Ok(old_idx) => {
-                if date == rating.date {
-                    movie.ratings[old_idx] = rating;
-                } else {
-                    movie.ratings.remove(old_idx);
-                    let new_idx = movie
-                        .ratings
-                        .binary_search_by_key(&cmp::Reverse(rating.date), |w| cmp::Reverse(w.date))
-                        .expect_err("the only rating with this date was just removed");
-                    movie.ratings.insert(new_idx, rating);
+    rating.user = user.id;
+    let after = Some(rating.clone());
+    let mut data_lock = state.data.lock().await;
+    let before = match data_lock.movies.get_mut(&id) {
+        Some(movie) => match rating_index(&movie.ratings, user.id, date) {
+            Some(old_idx) => {
+                let old = movie.ratings.remove(old_idx);
+                if let Err(rating) = movie.add_rating(rating) {
+                    movie.ratings.insert(old_idx, old);
+                    return HttpResponse::Conflict().body(format!(
+                        "movie with ID {id} already has a rating set for {}",
+                        rating.date.format("%Y-%m-%d")
+                    ));
                 }
+                movie.revision += 1;
+                old
             }
-            Err(_) => {
+            None => {
                 return HttpResponse::NotFound().body(format!(
                     "movie with ID {id} has no rating set for {}",
                     date.format("%Y-%m-%d")
@@ -188,29 +326,37 @@ async fn movie_patch_rating(
             }
         },
         None => return HttpResponse::NotFound().body(format!("movie with ID {id} does not exist")),
-    }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    };
+    let change = EntityChange::MovieRating {
+        tmdb_id: *id,
+        before: Some(before),
+        after,
+    };
+    match state.save(&data_lock, Change::Movie(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[delete("/api/movie/{id}/rating")]
 async fn movie_delete_rating(
-    data: Data<AppState>,
+    state: Data<AppState>,
+    user: CurrentUser,
     id: Path<u64>,
     Query(RatingDateQuery { date }): Query<RatingDateQuery>,
 ) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.movies.get_mut(&id) {
-        Some(movie) => match movie
-            .ratings
-            .binary_search_by_key(&cmp::Reverse(date), |w| cmp::Reverse(w.date))
-        {
-            Ok(idx) => {
-                movie.ratings.remove(idx);
+    let mut data_lock = state.data.lock().await;
+    let before = match data_lock.movies.get_mut(&id) {
+        Some(movie) => match rating_index(&movie.ratings, user.id, date) {
+            Some(idx) => {
+                movie.revision += 1;
+                movie.ratings.remove(idx)
             }
-            Err(_) => {
+            None => {
                 return HttpResponse::NotFound().body(format!(
                     "movie with ID {id} has no rating set for {}",
                     date.format("%Y-%m-%d")
@@ -218,97 +364,198 @@ async fn movie_delete_rating(
             }
         },
         None => return HttpResponse::NotFound().body(format!("movie with ID {id} does not exist")),
-    }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    };
+    let change = EntityChange::MovieRating {
+        tmdb_id: *id,
+        before: Some(before),
+        after: None,
+    };
+    match state.save(&data_lock, Change::Movie(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[post("/api/book")]
-async fn post_book(data: Data<AppState>, Json(book): Json<Book>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
+async fn post_book(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(mut book): Json<Book>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    book.claim(user.id, &data_lock.user_tags(user.id));
     let id = loop {
         let new_id = rand::random::<u32>();
         if !data_lock.books.contains_key(&new_id) {
             break new_id;
         }
     };
-    let new_book = Book { id, ..book };
+    let new_book = Book {
+        id,
+        revision: 0,
+        ..book
+    };
     let resp = HttpResponse::Ok().json(&new_book);
+    let change = EntityChange::Book {
+        book_id: id,
+        before: None,
+        after: Some(new_book.clone()),
+    };
     data_lock.books.insert(id, new_book);
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => resp,
+    match state.save(&data_lock, Change::Book(id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            resp
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[patch("/api/book")]
-async fn patch_book(data: Data<AppState>, Json(book): Json<Book>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.books.entry(book.id) {
-        hash_map::Entry::Vacant(_) => {
-            return HttpResponse::NotFound()
-                .body(format!("book with ID {} does not exist", book.id))
+async fn patch_book(
+    state: Data<AppState>,
+    user: CurrentUser,
+    req: HttpRequest,
+    Json(book): Json<Book>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = book.id;
+    let tags = data_lock.user_tags(user.id);
+    let before = match data_lock.books.get_mut(&id) {
+        None => return HttpResponse::NotFound().body(format!("book with ID {id} does not exist")),
+        Some(existing) => {
+            let current = existing.view(user.id, &tags);
+            if let Err(resp) = revisions::check(&req, existing.revision, &current) {
+                return resp;
+            }
+            let before = existing.clone();
+            existing.update_for_user(book, user.id, &tags);
+            before
         }
-        hash_map::Entry::Occupied(mut entry) => {
-            *entry.get_mut() = book;
+    };
+    let before_revision = before.revision;
+    let change = EntityChange::Book {
+        book_id: id,
+        before: Some(before),
+        after: data_lock.books.get(&id).cloned(),
+    };
+    match state.save(&data_lock, Change::Book(id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok()
+                .insert_header(revisions::etag(before_revision + 1))
+                .finish()
         }
-    }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[delete("/api/book/{id}")]
-async fn delete_book(data: Data<AppState>, id: Path<u32>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    if data_lock.books.remove(&id).is_none() {
+async fn delete_book(state: Data<AppState>, user: CurrentUser, id: Path<u32>) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let Some(book) = data_lock.books.get(&id) else {
         return HttpResponse::NotFound().body(format!("book with ID {id} does not exist"));
+    };
+    if !user.admin && book.readings.iter().any(|reading| reading.user != user.id) {
+        return HttpResponse::Conflict().body(format!("book with ID {id} is read by other users"));
     }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    let change = EntityChange::Book {
+        book_id: *id,
+        before: data_lock.books.remove(&id),
+        after: None,
+    };
+    match state.save(&data_lock, Change::Book(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[put("/api/book/{id}/reading")]
 async fn book_add_reading(
-    data: Data<AppState>,
+    state: Data<AppState>,
+    user: CurrentUser,
     id: Path<u32>,
-    Json(reading): Json<Reading>,
+    Json(mut reading): Json<Reading>,
 ) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.books.get_mut(&id) {
-        Some(book) => book.readings.push(reading),
-        None => return HttpResponse::NotFound().body(format!("book with ID {id} does not exist")),
+    reading.user = user.id;
+    if let Some(rating) = &mut reading.rating {
+        rating.user = user.id;
     }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    let mut data_lock = state.data.lock().await;
+    let change = match data_lock.books.get_mut(&id) {
+        Some(book) => {
+            let index = book
+                .readings
+                .iter()
+                .filter(|reading| reading.user == user.id)
+                .count();
+            book.readings.push(reading.clone());
+            book.revision += 1;
+            EntityChange::Reading {
+                book_id: *id,
+                index,
+                before: None,
+                after: Some(reading),
+            }
+        }
+        None => return HttpResponse::NotFound().body(format!("book with ID {id} does not exist")),
+    };
+    match state.save(&data_lock, Change::Book(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[delete("/api/book/{id}/reading/{idx}")]
 async fn book_delete_reading(
-    data: Data<AppState>,
+    state: Data<AppState>,
+    user: CurrentUser,
     id: Path<u32>,
     idx: Path<usize>,
 ) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.books.get_mut(&id) {
+    let mut data_lock = state.data.lock().await;
+    let before = match data_lock.books.get_mut(&id) {
         Some(book) => {
-            if *idx >= book.readings.len() {
+            let Some(reading_idx) = book
+                .readings
+                .iter()
+                .positions(|reading| reading.user == user.id)
+                .nth(*idx)
+            else {
                 return HttpResponse::NotFound()
                     .body(format!("book with ID {id} has no reading with index {idx}"));
-            }
-            book.readings.remove(*idx);
+            };
+            book.revision += 1;
+            book.readings.remove(reading_idx)
         }
         None => return HttpResponse::NotFound().body(format!("book with ID {id} does not exist")),
-    }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    };
+    let change = EntityChange::Reading {
+        book_id: *id,
+        index: *idx,
+        before: Some(before),
+        after: None,
+    };
+    match state.save(&data_lock, Change::Book(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
@@ -321,75 +568,398 @@ struct SetRatingQuery {
 
 #[patch("/api/book/{id}/reading/{idx}")]
 async fn book_reading_set_for_date(
-    data: Data<AppState>,
+    state: Data<AppState>,
+    user: CurrentUser,
     id: Path<u32>,
     idx: Path<usize>,
     Query(SetRatingQuery { date, pages }): Query<SetRatingQuery>,
 ) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.books.get_mut(&id) {
+    let mut data_lock = state.data.lock().await;
+    let change = match data_lock.books.get_mut(&id) {
         Some(book) => {
-            if *idx >= book.readings.len() {
+            let Some(reading) = book.reading_mut(user.id, *idx) else {
                 return HttpResponse::NotFound()
                     .body(format!("book with ID {id} has no reading with index {idx}"));
-            }
+            };
+            let before = reading.clone();
             if pages == 0 {
-                book.readings[*idx].pages_read.remove(&date);
+                reading.pages_read.remove(&date);
             } else {
-                book.readings[*idx].pages_read.insert(date, pages);
+                reading.pages_read.insert(date, pages);
+            }
+            let after = reading.clone();
+            book.revision += 1;
+            EntityChange::Reading {
+                book_id: *id,
+                index: *idx,
+                before: Some(before),
+                after: Some(after),
             }
         }
         None => return HttpResponse::NotFound().body(format!("book with ID {id} does not exist")),
-    }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    };
+    match state.save(&data_lock, Change::Book(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[put("/api/book/{id}/reading/{idx}/rating")]
 async fn book_reading_set_rating(
-    data: Data<AppState>,
+    state: Data<AppState>,
+    user: CurrentUser,
     id: Path<u32>,
     idx: Path<usize>,
     Json(rating): Json<Rating>,
 ) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.books.get_mut(&id) {
+    let mut data_lock = state.data.lock().await;
+    let change = match data_lock.books.get_mut(&id) {
         Some(book) => {
-            if *idx >= book.readings.len() {
+            let Some(reading) = book.reading_mut(user.id, *idx) else {
                 return HttpResponse::NotFound()
                     .body(format!("book with ID {id} has no reading with index {idx}"));
+            };
+            let before = reading.clone();
+            reading.rating = Some(Rating {
+                user: user.id,
+                ..rating
+            });
+            let after = reading.clone();
+            book.revision += 1;
+            EntityChange::Reading {
+                book_id: *id,
+                index: *idx,
+                before: Some(before),
+                after: Some(after),
             }
-            book.readings[*idx].rating = Some(rating);
         }
         None => return HttpResponse::NotFound().body(format!("book with ID {id} does not exist")),
-    }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+    };
+    match state.save(&data_lock, Change::Book(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
 
 #[delete("/api/book/{id}/reading/{idx}/rating")]
 async fn book_reading_delete_rating(
-    data: Data<AppState>,
+    state: Data<AppState>,
+    user: CurrentUser,
     id: Path<u32>,
     idx: Path<usize>,
 ) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    match data_lock.books.get_mut(&id) {
+    let mut data_lock = state.data.lock().await;
+    let change = match data_lock.books.get_mut(&id) {
         Some(book) => {
-            if *idx >= book.readings.len() {
+            let Some(reading) = book.reading_mut(user.id, *idx) else {
                 return HttpResponse::NotFound()
                     .body(format!("book with ID {id} has no reading with index {idx}"));
+            };
+            let before = reading.clone();
+            reading.rating = None;
+            let after = reading.clone();
+            book.revision += 1;
+            EntityChange::Reading {
+                book_id: *id,
+                index: *idx,
+                before: Some(before),
+                after: Some(after),
             }
-            book.readings[*idx].rating = None;
         }
         None => return HttpResponse::NotFound().body(format!("book with ID {id} does not exist")),
+    };
+    match state.save(&data_lock, Change::Book(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().finish(),
+}
+
+#[post("/api/series")]
+async fn post_series(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(mut series): Json<Series>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = series.tmdb_id;
+    series.claim(user.id, &data_lock.user_tags(user.id));
+    match data_lock.series.entry(id) {
+        Entry::Vacant(entry) => {
+            entry.insert(series);
+        }
+        Entry::Occupied(_) => {
+            return HttpResponse::Conflict().body("a series with that ID is already present")
+        }
+    }
+    let change = EntityChange::Series {
+        tmdb_id: id,
+        before: None,
+        after: data_lock.series.get(&id).cloned(),
+    };
+    match state.save(&data_lock, Change::Series(id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[patch("/api/series")]
+async fn patch_series(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(series): Json<Series>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = series.tmdb_id;
+    let tags = data_lock.user_tags(user.id);
+    let before = match data_lock.series.get_mut(&id) {
+        None => {
+            return HttpResponse::NotFound().body(format!("series with ID {id} does not exist"))
+        }
+        Some(existing) => {
+            let before = existing.clone();
+            existing.update_for_user(series, user.id, &tags);
+            before
+        }
+    };
+    let change = EntityChange::Series {
+        tmdb_id: id,
+        before: Some(before),
+        after: data_lock.series.get(&id).cloned(),
+    };
+    match state.save(&data_lock, Change::Series(id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[delete("/api/series/{id}")]
+async fn delete_series(state: Data<AppState>, user: CurrentUser, id: Path<u64>) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let Some(series) = data_lock.series.get(&id) else {
+        return HttpResponse::NotFound().body(format!("series with ID {id} does not exist"));
+    };
+    let watched_by_others = series
+        .seasons
+        .iter()
+        .flat_map(|season| &season.episodes)
+        .any(|episode| {
+            episode.watched.keys().any(|&id| id != user.id)
+                || episode.ratings.iter().any(|rating| rating.user != user.id)
+        });
+    if !user.admin && watched_by_others {
+        return HttpResponse::Conflict().body(format!(
+            "series with ID {id} is watched or rated by other users"
+        ));
+    }
+    let change = EntityChange::Series {
+        tmdb_id: *id,
+        before: data_lock.series.remove(&id),
+        after: None,
+    };
+    match state.save(&data_lock, Change::Series(*id)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+fn episode_mut(
+    data: &mut AppData,
+    (id, season, episode): (u64, u32, u32),
+) -> Result<&mut Episode, HttpResponse> {
+    data.series
+        .get_mut(&id)
+        .ok_or_else(|| {
+            HttpResponse::NotFound().body(format!("series with ID {id} does not exist"))
+        })?
+        .episode_mut(season, episode)
+        .ok_or_else(|| {
+            HttpResponse::NotFound().body(format!(
+                "series with ID {id} has no episode {episode} in season {season}"
+            ))
+        })
+}
+
+#[put("/api/series/{id}/{season}/{episode}/watched")]
+async fn episode_put_watched(
+    state: Data<AppState>,
+    user: CurrentUser,
+    path: Path<(u64, u32, u32)>,
+    Query(RatingDateQuery { date }): Query<RatingDateQuery>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let (tmdb_id, season, number) = *path;
+    let change = match episode_mut(&mut data_lock, *path) {
+        Ok(episode) => {
+            let before = episode.clone();
+            if !episode.watched.entry(user.id).or_default().insert(date) {
+                return HttpResponse::Conflict().body(format!(
+                    "episode is already marked as watched on {}",
+                    date.format("%Y-%m-%d")
+                ));
+            }
+            EntityChange::Episode {
+                tmdb_id,
+                season,
+                episode: number,
+                before: Some(before),
+                after: Some(episode.clone()),
+            }
+        }
+        Err(resp) => return resp,
+    };
+    match state.save(&data_lock, Change::Series(path.0)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[delete("/api/series/{id}/{season}/{episode}/watched")]
+async fn episode_delete_watched(
+    state: Data<AppState>,
+    user: CurrentUser,
+    path: Path<(u64, u32, u32)>,
+    Query(RatingDateQuery { date }): Query<RatingDateQuery>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let (tmdb_id, season, number) = *path;
+    let change = match episode_mut(&mut data_lock, *path) {
+        Ok(episode) => {
+            let before = episode.clone();
+            let Some(dates) = episode.watched.get_mut(&user.id) else {
+                return HttpResponse::NotFound().body(format!(
+                    "episode is not marked as watched on {}",
+                    date.format("%Y-%m-%d")
+                ));
+            };
+            if !dates.remove(&date) {
+                return HttpResponse::NotFound().body(format!(
+                    "episode is not marked as watched on {}",
+                    date.format("%Y-%m-%d")
+                ));
+            }
+            if dates.is_empty() {
+                episode.watched.remove(&user.id);
+            }
+            EntityChange::Episode {
+                tmdb_id,
+                season,
+                episode: number,
+                before: Some(before),
+                after: Some(episode.clone()),
+            }
+        }
+        Err(resp) => return resp,
+    };
+    match state.save(&data_lock, Change::Series(path.0)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[put("/api/series/{id}/{season}/{episode}/rating")]
+async fn episode_put_rating(
+    state: Data<AppState>,
+    user: CurrentUser,
+    path: Path<(u64, u32, u32)>,
+    Json(mut rating): Json<Rating>,
+) -> impl Responder {
+    rating.user = user.id;
+    let (tmdb_id, season, episode) = *path;
+    let change = EntityChange::EpisodeRating {
+        tmdb_id,
+        season,
+        episode,
+        before: None,
+        after: Some(rating.clone()),
+    };
+    let mut data_lock = state.data.lock().await;
+    match episode_mut(&mut data_lock, *path) {
+        Ok(episode) => {
+            if let Err(rating) = episode.add_rating(rating) {
+                return HttpResponse::Conflict().body(format!(
+                    "episode already has a rating set for {}",
+                    rating.date.format("%Y-%m-%d")
+                ));
+            }
+        }
+        Err(resp) => return resp,
+    }
+    match state.save(&data_lock, Change::Series(path.0)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[delete("/api/series/{id}/{season}/{episode}/rating")]
+async fn episode_delete_rating(
+    state: Data<AppState>,
+    user: CurrentUser,
+    path: Path<(u64, u32, u32)>,
+    Query(RatingDateQuery { date }): Query<RatingDateQuery>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let before = match episode_mut(&mut data_lock, *path) {
+        Ok(episode) => match rating_index(&episode.ratings, user.id, date) {
+            Some(idx) => episode.ratings.remove(idx),
+            None => {
+                return HttpResponse::NotFound().body(format!(
+                    "episode has no rating set for {}",
+                    date.format("%Y-%m-%d")
+                ))
+            }
+        },
+        Err(resp) => return resp,
+    };
+    let (tmdb_id, season, episode) = *path;
+    let change = EntityChange::EpisodeRating {
+        tmdb_id,
+        season,
+        episode,
+        before: Some(before),
+        after: None,
+    };
+    match state.save(&data_lock, Change::Series(path.0)).await {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
         Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
     }
 }
@@ -0,0 +1,698 @@
+//! Export and import of the whole collection.
+//!
+//! A snapshot contains all users, movies, series, tags, platforms and books, but not the TMDB cache.
+//! It uses the same versioned format as the data file, so snapshots from older versions are migrated on import.
+//! Optionally, snapshots are bundled with all cached posters and covers into a tar archive.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::File,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use actix_web::{
+    get, post,
+    rt::task,
+    web::{self, Bytes, Data, Query},
+    HttpRequest, HttpResponse, Responder,
+};
+use anyhow::{bail, Context, Result};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::mpsc};
+
+use crate::{
+    config::config,
+    events, migrations,
+    schema::{Book, Movie, Platform, Rating, Series, Tag, User},
+    storage::Change,
+    users::Admin,
+    AppState,
+};
+
+/// Maximum accepted size of a snapshot, which is read into memory
+const MAX_SNAPSHOT_SIZE: usize = 64 << 20;
+/// Maximum accepted size of an uploaded archive, which is buffered in a temporary file
+const MAX_ARCHIVE_SIZE: usize = 1 << 30;
+/// Name of the snapshot file inside of archives
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const TAR_CONTENT_TYPE: &str = "application/x-tar";
+/// Names of the image directories inside of archives and their location on disk
+fn image_dirs() -> [(&'static str, PathBuf); 2] {
+    let config = config();
+    [
+        ("posters", config.posters_dir()),
+        ("covers", config.covers_dir()),
+    ]
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub users: BTreeMap<u32, User>,
+    pub movies: BTreeMap<u64, Movie>,
+    pub series: BTreeMap<u64, Series>,
+    pub tags: HashMap<u32, Tag>,
+    pub platforms: BTreeMap<u32, Platform>,
+    pub books: HashMap<u32, Book>,
+}
+
+#[derive(serde::Deserialize)]
+struct ExportQuery {
+    /// Bundle posters and covers into a tar archive
+    #[serde(default)]
+    images: bool,
+}
+
+/// [`Write`] implementation forwarding everything to an async channel.
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver was dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_archive(snapshot: &[u8], writer: impl Write) -> io::Result<()> {
+    let mut archive = tar::Builder::new(io::BufWriter::with_capacity(1 << 16, writer));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(snapshot.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    archive.append_data(&mut header, SNAPSHOT_FILE, snapshot)?;
+    for (name, dir) in image_dirs() {
+        if dir.is_dir() {
+            archive.append_dir_all(name, dir)?;
+        }
+    }
+    archive.into_inner()?.flush()
+}
+
+#[get("/api/export")]
+async fn export(
+    state: Data<AppState>,
+    _: Admin,
+    Query(ExportQuery { images }): Query<ExportQuery>,
+) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    let snapshot = Snapshot {
+        users: data_lock.users.clone(),
+        movies: data_lock.movies.clone(),
+        series: data_lock.series.clone(),
+        tags: data_lock.tags.clone(),
+        platforms: data_lock.platforms.clone(),
+        books: data_lock.books.clone(),
+    };
+    drop(data_lock);
+    let json = match migrations::to_string_pretty(&snapshot) {
+        Ok(json) => json,
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to serialize snapshot: {err}"))
+        }
+    };
+    let date = chrono::Local::now().format("%Y-%m-%d");
+
+    if !images {
+        return HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"entrackment-{date}.json\""),
+            ))
+            .body(json);
+    }
+
+    // the archive is written on a blocking thread and streamed to the client while it is created
+    let (tx, rx) = mpsc::channel(16);
+    task::spawn_blocking(move || {
+        if let Err(err) = write_archive(json.as_bytes(), ChannelWriter(tx.clone())) {
+            // fails if the client has already disconnected, so there is nothing more to do
+            let _ = tx.blocking_send(Err(err));
+        }
+    });
+    HttpResponse::Ok()
+        .content_type(TAR_CONTENT_TYPE)
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"entrackment-{date}.tar\""),
+        ))
+        .streaming(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (chunk, rx))
+        }))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ImportMode {
+    /// Only add entries that do not exist yet and report conflicts for differing ones
+    #[default]
+    Merge,
+    /// Replace all users, movies, series, tags, platforms and books with the imported ones
+    Replace,
+}
+
+#[derive(serde::Deserialize)]
+struct ImportQuery {
+    #[serde(default)]
+    mode: ImportMode,
+    /// Only report what would change without changing anything
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct EntityReport<K> {
+    /// Entries that do not exist yet
+    added: Vec<K>,
+    /// Existing entries that are overwritten (only in replace mode)
+    updated: Vec<K>,
+    /// Existing entries that are not part of the import (only removed in replace mode)
+    removed: Vec<K>,
+    /// Existing entries that differ from the imported ones and are kept (only in merge mode)
+    conflicts: Vec<K>,
+    /// Number of entries that are identical in both
+    unchanged: usize,
+}
+
+impl<K: Ord + Copy> EntityReport<K> {
+    fn new<V: PartialEq>(
+        current: BTreeMap<K, &V>,
+        incoming: BTreeMap<K, &V>,
+        mode: ImportMode,
+    ) -> Self {
+        let mut report = Self {
+            added: vec![],
+            updated: vec![],
+            removed: vec![],
+            conflicts: vec![],
+            unchanged: 0,
+        };
+        for (&id, new) in &incoming {
+            match current.get(&id) {
+                None => report.added.push(id),
+                Some(old) if old == new => report.unchanged += 1,
+                Some(_) if mode == ImportMode::Replace => report.updated.push(id),
+                Some(_) => report.conflicts.push(id),
+            }
+        }
+        if mode == ImportMode::Replace {
+            report.removed = current
+                .keys()
+                .filter(|id| !incoming.contains_key(id))
+                .copied()
+                .collect();
+        }
+        report
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ImportReport {
+    mode: ImportMode,
+    dry_run: bool,
+    users: EntityReport<u32>,
+    movies: EntityReport<u64>,
+    series: EntityReport<u64>,
+    tags: EntityReport<u32>,
+    platforms: EntityReport<u32>,
+    books: EntityReport<u32>,
+    /// Number of poster and cover images contained in the archive
+    images: usize,
+}
+
+/// Ids of the users, tags and platforms which imported entries may refer to.
+#[derive(Debug, Default)]
+struct KnownIds {
+    users: BTreeSet<u32>,
+    tags: BTreeSet<u32>,
+    platforms: BTreeSet<u32>,
+}
+
+impl KnownIds {
+    fn extend<U, T, P>(
+        &mut self,
+        users: &BTreeMap<u32, U>,
+        tags: &HashMap<u32, T>,
+        platforms: &BTreeMap<u32, P>,
+    ) {
+        self.users.extend(users.keys());
+        self.tags.extend(tags.keys());
+        self.platforms.extend(platforms.keys());
+    }
+
+    /// Returns a description of the first reference of the given entries to an unknown user, tag
+    /// or platform.
+    fn check<'a>(
+        &self,
+        tags: impl IntoIterator<Item = &'a Tag>,
+        movies: impl IntoIterator<Item = &'a Movie>,
+        series: impl IntoIterator<Item = &'a Series>,
+        books: impl IntoIterator<Item = &'a Book>,
+    ) -> Result<(), String> {
+        for tag in tags {
+            self.check_user(tag.user, || format!("tag {}", tag.id))?;
+        }
+        for movie in movies {
+            let entry = || format!("movie {}", movie.tmdb_id);
+            self.check_tags(&movie.tags, entry)?;
+            for &platform in &movie.platforms {
+                self.check_platform(platform, entry)?;
+            }
+            for rating in &movie.ratings {
+                self.check_rating(rating, entry)?;
+            }
+        }
+        for series in series {
+            let entry = || format!("series {}", series.tmdb_id);
+            self.check_tags(&series.tags, entry)?;
+            for &platform in &series.platforms {
+                self.check_platform(platform, entry)?;
+            }
+            for episode in series.seasons.iter().flat_map(|season| &season.episodes) {
+                for &user in episode.watched.keys() {
+                    self.check_user(user, entry)?;
+                }
+                for rating in &episode.ratings {
+                    self.check_rating(rating, entry)?;
+                }
+            }
+        }
+        for book in books {
+            let entry = || format!("book {}", book.id);
+            self.check_tags(&book.tags, entry)?;
+            for reading in &book.readings {
+                self.check_user(reading.user, entry)?;
+                if let Some(rating) = &reading.rating {
+                    self.check_rating(rating, entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_rating(&self, rating: &Rating, entry: impl Fn() -> String) -> Result<(), String> {
+        self.check_user(rating.user, &entry)?;
+        if let Some(platform) = rating.platform {
+            self.check_platform(platform, &entry)?;
+        }
+        self.check_tags(&rating.tags, entry)
+    }
+
+    fn check_user(&self, id: u32, entry: impl Fn() -> String) -> Result<(), String> {
+        match self.users.contains(&id) {
+            true => Ok(()),
+            false => Err(format!("{} refers to unknown user {id}", entry())),
+        }
+    }
+
+    fn check_tags(&self, ids: &BTreeSet<u32>, entry: impl Fn() -> String) -> Result<(), String> {
+        match ids.iter().find(|id| !self.tags.contains(id)) {
+            None => Ok(()),
+            Some(id) => Err(format!("{} refers to unknown tag {id}", entry())),
+        }
+    }
+
+    fn check_platform(&self, id: u32, entry: impl Fn() -> String) -> Result<(), String> {
+        match self.platforms.contains(&id) {
+            true => Ok(()),
+            false => Err(format!("{} refers to unknown platform {id}", entry())),
+        }
+    }
+}
+
+/// Returns the destination of an image file in the archive within one of the image `dirs` or
+/// `None` if the entry is no image.
+fn image_destination(path: &Path, dirs: &[(&str, PathBuf)]) -> Option<PathBuf> {
+    let mut components = path.components();
+    let Component::Normal(first) = components.next()? else {
+        return None;
+    };
+    let (_, base_dir) = dirs.iter().find(|(name, _)| first == *name)?;
+    let rest = components.as_path();
+    if rest.as_os_str().is_empty() || !rest.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(base_dir.join(rest))
+}
+
+/// Reads the snapshot from a tar archive and extracts all images if `extract` is set.
+/// Returns the snapshot JSON and the number of images in the archive.
+fn read_archive(archive: &Path, extract: bool) -> Result<(String, usize)> {
+    let mut snapshot = None;
+    let mut images = 0;
+    let dirs = image_dirs();
+    let archive = io::BufReader::new(File::open(archive)?);
+    for entry in tar::Archive::new(archive).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        if path == Path::new(SNAPSHOT_FILE) {
+            if entry.size() > MAX_SNAPSHOT_SIZE as u64 {
+                bail!("'{SNAPSHOT_FILE}' is larger than {MAX_SNAPSHOT_SIZE} bytes");
+            }
+            let mut json = String::new();
+            entry.read_to_string(&mut json)?;
+            snapshot = Some(json);
+        } else if let Some(dest) = image_destination(&path, &dirs) {
+            images += 1;
+            if extract {
+                if let Some(parent) = dest.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                entry
+                    .unpack(&dest)
+                    .with_context(|| format!("failed to extract '{}'", path.display()))?;
+            }
+        }
+    }
+    match snapshot {
+        Some(snapshot) => Ok((snapshot, images)),
+        None => bail!("archive does not contain '{SNAPSHOT_FILE}'"),
+    }
+}
+
+/// A file which is deleted when this is dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Streams an uploaded archive into a new temporary file in the data directory.
+async fn receive_archive(mut payload: web::Payload) -> Result<TempFile, HttpResponse> {
+    let internal_error =
+        |err| HttpResponse::InternalServerError().body(format!("Failed to buffer archive: {err}"));
+    let dir = &config().data_dir;
+    fs::create_dir_all(dir).await.map_err(internal_error)?;
+    let path = TempFile(dir.join(format!(".import-{}.tar", rand::random::<u32>())));
+    let mut file = fs::File::create(&path.0).await.map_err(internal_error)?;
+    let mut size = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| {
+            HttpResponse::BadRequest().body(format!("Failed to read body: {err}"))
+        })?;
+        size += chunk.len();
+        if size > MAX_ARCHIVE_SIZE {
+            return Err(HttpResponse::PayloadTooLarge().finish());
+        }
+        file.write_all(&chunk).await.map_err(internal_error)?;
+    }
+    file.flush().await.map_err(internal_error)?;
+    Ok(path)
+}
+
+#[post("/api/import")]
+async fn import(
+    state: Data<AppState>,
+    _: Admin,
+    req: HttpRequest,
+    Query(ImportQuery { mode, dry_run }): Query<ImportQuery>,
+    payload: web::Payload,
+) -> impl Responder {
+    let is_archive = req
+        .headers()
+        .get("Content-Type")
+        .is_some_and(|content_type| content_type == TAR_CONTENT_TYPE);
+
+    let (archive, json, images) = if is_archive {
+        let archive = match receive_archive(payload).await {
+            Ok(archive) => archive,
+            Err(resp) => return resp,
+        };
+        let path = archive.0.clone();
+        match task::spawn_blocking(move || read_archive(&path, false)).await {
+            Ok(Ok((json, images))) => (Some(archive), json, images),
+            Ok(Err(err)) => {
+                return HttpResponse::BadRequest().body(format!("Invalid archive: {err:#}"))
+            }
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        }
+    } else {
+        let body = match payload.to_bytes_limited(MAX_SNAPSHOT_SIZE).await {
+            Ok(Ok(body)) => body,
+            Ok(Err(err)) => {
+                return HttpResponse::BadRequest().body(format!("Failed to read body: {err}"))
+            }
+            Err(_) => return HttpResponse::PayloadTooLarge().finish(),
+        };
+        match String::from_utf8(body.to_vec()) {
+            Ok(json) => (None, json, 0),
+            Err(_) => return HttpResponse::BadRequest().body("snapshot is not valid UTF-8"),
+        }
+    };
+    let snapshot: Snapshot = match migrations::from_str(&json) {
+        Ok(snapshot) => snapshot,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid snapshot: {err:#}")),
+    };
+    if mode == ImportMode::Replace && !snapshot.users.values().any(|user| user.admin) {
+        return HttpResponse::BadRequest().body("snapshot has to contain at least one admin");
+    }
+
+    let mut data_lock = state.data.lock().await;
+    let report = ImportReport {
+        mode,
+        dry_run,
+        users: EntityReport::new(
+            data_lock.users.iter().map(|(&k, v)| (k, v)).collect(),
+            snapshot.users.iter().map(|(&k, v)| (k, v)).collect(),
+            mode,
+        ),
+        movies: EntityReport::new(
+            data_lock.movies.iter().map(|(&k, v)| (k, v)).collect(),
+            snapshot.movies.iter().map(|(&k, v)| (k, v)).collect(),
+            mode,
+        ),
+        series: EntityReport::new(
+            data_lock.series.iter().map(|(&k, v)| (k, v)).collect(),
+            snapshot.series.iter().map(|(&k, v)| (k, v)).collect(),
+            mode,
+        ),
+        tags: EntityReport::new(
+            data_lock.tags.iter().map(|(&k, v)| (k, v)).collect(),
+            snapshot.tags.iter().map(|(&k, v)| (k, v)).collect(),
+            mode,
+        ),
+        platforms: EntityReport::new(
+            data_lock.platforms.iter().map(|(&k, v)| (k, v)).collect(),
+            snapshot.platforms.iter().map(|(&k, v)| (k, v)).collect(),
+            mode,
+        ),
+        books: EntityReport::new(
+            data_lock.books.iter().map(|(&k, v)| (k, v)).collect(),
+            snapshot.books.iter().map(|(&k, v)| (k, v)).collect(),
+            mode,
+        ),
+        images,
+    };
+
+    // entries may only refer to users, tags and platforms which exist after the import
+    let mut known = KnownIds::default();
+    known.extend(&snapshot.users, &snapshot.tags, &snapshot.platforms);
+    let references = match mode {
+        ImportMode::Replace => known.check(
+            snapshot.tags.values(),
+            snapshot.movies.values(),
+            snapshot.series.values(),
+            snapshot.books.values(),
+        ),
+        ImportMode::Merge => {
+            known.extend(&data_lock.users, &data_lock.tags, &data_lock.platforms);
+            known.check(
+                report.tags.added.iter().map(|id| &snapshot.tags[id]),
+                report.movies.added.iter().map(|id| &snapshot.movies[id]),
+                report.series.added.iter().map(|id| &snapshot.series[id]),
+                report.books.added.iter().map(|id| &snapshot.books[id]),
+            )
+        }
+    };
+    if let Err(err) = references {
+        return HttpResponse::BadRequest().body(format!("Invalid snapshot: {err}"));
+    }
+    if dry_run {
+        return HttpResponse::Ok().json(report);
+    }
+
+    match mode {
+        ImportMode::Replace => {
+            if let Err(err) = state.backups.create(&*state.storage, &data_lock).await {
+                return HttpResponse::InternalServerError().body(format!(
+                    "Failed to back up current data before replacing: {err}"
+                ));
+            }
+            data_lock.users = snapshot.users;
+            data_lock.movies = snapshot.movies;
+            data_lock.series = snapshot.series;
+            data_lock.tags = snapshot.tags;
+            data_lock.platforms = snapshot.platforms;
+            data_lock.books = snapshot.books;
+            let data = &mut *data_lock;
+            data.goals.retain(|user, _| data.users.contains_key(user));
+            data.notifications
+                .retain(|user, _| data.users.contains_key(user));
+            // users whose record changed may be different people with the same id, so they have to
+            // get new credentials
+            let keeps_credentials =
+                |user: &u32| data.users.contains_key(user) && !report.users.updated.contains(user);
+            data.passwords.retain(|user, _| keeps_credentials(user));
+            data.api_tokens
+                .retain(|_, token| keeps_credentials(&token.user));
+            data.watchlists
+                .retain(|user, _| data.users.contains_key(user));
+            for watchlist in data.watchlists.values_mut() {
+                watchlist.retain(|id, _| data.movies.contains_key(id));
+            }
+            data.availability_history
+                .retain(|id, _| data.movies.contains_key(id));
+        }
+        ImportMode::Merge => {
+            let Snapshot {
+                mut users,
+                mut movies,
+                mut series,
+                mut tags,
+                mut platforms,
+                mut books,
+            } = snapshot;
+            for id in &report.users.added {
+                data_lock
+                    .users
+                    .insert(*id, users.remove(id).expect("id is from snapshot"));
+            }
+            for id in &report.movies.added {
+                data_lock
+                    .movies
+                    .insert(*id, movies.remove(id).expect("id is from snapshot"));
+            }
+            for id in &report.series.added {
+                data_lock
+                    .series
+                    .insert(*id, series.remove(id).expect("id is from snapshot"));
+            }
+            for id in &report.tags.added {
+                data_lock
+                    .tags
+                    .insert(*id, tags.remove(id).expect("id is from snapshot"));
+            }
+            for id in &report.platforms.added {
+                data_lock
+                    .platforms
+                    .insert(*id, platforms.remove(id).expect("id is from snapshot"));
+            }
+            for id in &report.books.added {
+                data_lock
+                    .books
+                    .insert(*id, books.remove(id).expect("id is from snapshot"));
+            }
+        }
+    }
+    if let Err(err) = state.save(&data_lock, Change::All).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to save new data to disk: {err}"));
+    }
+    drop(data_lock);
+    events::reset().await;
+
+    if let Some(archive) = archive {
+        match task::spawn_blocking(move || read_archive(&archive.0, true)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => {
+                return HttpResponse::InternalServerError().body(format!(
+                    "Imported data, but failed to extract images: {err:#}"
+                ))
+            }
+            Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        }
+    }
+    HttpResponse::Ok().json(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn entity_reports() {
+        let current = BTreeMap::from([(1, &"a"), (2, &"b"), (3, &"c")]);
+        let incoming = BTreeMap::from([(2, &"b"), (3, &"changed"), (4, &"d")]);
+
+        let merge = EntityReport::new(current.clone(), incoming.clone(), ImportMode::Merge);
+        assert_eq!(merge.added, [4]);
+        assert!(merge.updated.is_empty());
+        assert!(merge.removed.is_empty());
+        assert_eq!(merge.conflicts, [3]);
+        assert_eq!(merge.unchanged, 1);
+
+        let replace = EntityReport::new(current, incoming, ImportMode::Replace);
+        assert_eq!(replace.added, [4]);
+        assert_eq!(replace.updated, [3]);
+        assert_eq!(replace.removed, [1]);
+        assert!(replace.conflicts.is_empty());
+        assert_eq!(replace.unchanged, 1);
+    }
+
+    #[test]
+    fn image_destinations() {
+        let dirs = [
+            ("posters", PathBuf::from("/data/posters")),
+            ("covers", PathBuf::from("/data/covers")),
+        ];
+        let dest = |path: &str| image_destination(Path::new(path), &dirs);
+        assert_eq!(
+            dest("posters/big/a.jpg"),
+            Some(PathBuf::from("/data/posters/big/a.jpg"))
+        );
+        assert_eq!(
+            dest("covers/small/42"),
+            Some(PathBuf::from("/data/covers/small/42"))
+        );
+        assert_eq!(dest("posters"), None);
+        assert_eq!(dest("snapshot.json"), None);
+        assert_eq!(dest("posters/../../etc/passwd"), None);
+        assert_eq!(dest("posters/big/../../../a.jpg"), None);
+        assert_eq!(dest("/posters/big/a.jpg"), None);
+        assert_eq!(dest("/etc/passwd"), None);
+        assert_eq!(dest("../posters/big/a.jpg"), None);
+    }
+
+    #[test]
+    fn dangling_references() {
+        let tag = Tag {
+            id: 1,
+            user: 2,
+            ..Default::default()
+        };
+        let book = Book {
+            id: 42,
+            olid: None,
+            title: "Erebos".into(),
+            description: String::new(),
+            authors: vec![],
+            readings: vec![],
+            tags: [1].into(),
+            release_date: None,
+            score: None,
+            revision: 0,
+        };
+        let check = |known: &KnownIds| known.check([&tag], [], [], [&book]);
+
+        let mut known = KnownIds::default();
+        assert_eq!(check(&known), Err("tag 1 refers to unknown user 2".into()));
+        known.users.insert(2);
+        assert_eq!(check(&known), Err("book 42 refers to unknown tag 1".into()));
+        known.tags.insert(1);
+        assert_eq!(check(&known), Ok(()));
+    }
+}
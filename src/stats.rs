@@ -0,0 +1,500 @@
+//! Aggregated statistics about the watch and reading history.
+//!
+//! Every endpoint accepts the optional query parameters `from` and `to` (inclusive dates) which
+//! restrict the history that is taken into account.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use actix_web::{
+    get,
+    web::{Data, Query},
+    HttpResponse, Responder,
+};
+use chrono::{Datelike, Days, Months, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    listing::in_range,
+    schema::{AppData, Movie, Rating},
+    users::CurrentUser,
+    AppState,
+};
+
+/// Maximum number of periods in a series
+const MAX_POINTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct DateRange {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl DateRange {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        in_range(date, self.from, self.to)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Interval {
+    Day,
+    /// Weeks start on Monday
+    Week,
+    Month,
+    Year,
+}
+
+impl Interval {
+    /// Returns the first day of the period containing `date`.
+    fn start_of(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Interval::Day => date,
+            Interval::Week => date
+                .checked_sub_days(Days::new(date.weekday().num_days_from_monday() as u64))
+                .unwrap_or(NaiveDate::MIN),
+            Interval::Month => date.with_day(1).expect("every month has a first day"),
+            Interval::Year => date.with_ordinal(1).expect("every year has a first day"),
+        }
+    }
+
+    /// Returns the first day of the period after the one starting at `start`, or `None` if it
+    /// would be after [`NaiveDate::MAX`].
+    fn next(self, start: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Interval::Day => start.checked_add_days(Days::new(1)),
+            Interval::Week => start.checked_add_days(Days::new(7)),
+            Interval::Month => start.checked_add_months(Months::new(1)),
+            Interval::Year => start.checked_add_months(Months::new(12)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct IntervalQuery {
+    interval: Option<Interval>,
+}
+
+#[derive(Debug, Serialize)]
+struct Point<T> {
+    /// First day of the period
+    start: NaiveDate,
+    #[serde(flatten)]
+    value: T,
+}
+
+/// Turns values grouped by period start into a series without gaps, covering `range` if it is
+/// bounded or the periods with values otherwise. Fails if that takes more than [`MAX_POINTS`]
+/// periods.
+fn series<T: Default>(
+    mut values: BTreeMap<NaiveDate, T>,
+    interval: Interval,
+    range: DateRange,
+) -> Result<Vec<Point<T>>, HttpResponse> {
+    let first = range
+        .from
+        .map(|from| interval.start_of(from))
+        .or(values.keys().next().copied());
+    let last = range
+        .to
+        .map(|to| interval.start_of(to))
+        .or(values.keys().next_back().copied());
+    let (Some(mut start), Some(last)) = (first, last) else {
+        return Ok(vec![]);
+    };
+    let mut points = vec![];
+    while start <= last {
+        if points.len() == MAX_POINTS {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "the range spans more than {MAX_POINTS} periods, use a longer interval"
+            )));
+        }
+        points.push(Point {
+            start,
+            value: values.remove(&start).unwrap_or_default(),
+        });
+        let Some(next) = interval.next(start) else {
+            break;
+        };
+        start = next;
+    }
+    Ok(points)
+}
+
+/// All ratings of all movies inside `range`.
+pub fn watches(data: &AppData, range: DateRange) -> impl Iterator<Item = (&Movie, &Rating)> {
+    data.movies.values().flat_map(move |movie| {
+        movie
+            .ratings
+            .iter()
+            .filter(move |rating| range.contains(rating.date))
+            .map(move |rating| (movie, rating))
+    })
+}
+
+/// Time spent watching a movie, taking the playback speed into account.
+pub fn watch_hours(movie: &Movie, rating: &Rating) -> f64 {
+    movie.runtime.as_secs_f64() / 3600. / rating.speed.max(0.1) as f64
+}
+
+#[derive(Debug, Default, Serialize)]
+struct WatchCount {
+    count: usize,
+    hours: f64,
+}
+
+#[get("/api/stats/movies/watched")]
+async fn movies_watched(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Query(range): Query<DateRange>,
+    Query(IntervalQuery { interval }): Query<IntervalQuery>,
+) -> impl Responder {
+    let interval = interval.unwrap_or(Interval::Month);
+    let data = state.data.lock().await.view(user.id);
+    let mut values = BTreeMap::<_, WatchCount>::new();
+    for (movie, rating) in watches(&data, range) {
+        let value = values.entry(interval.start_of(rating.date)).or_default();
+        value.count += 1;
+        value.hours += watch_hours(movie, rating);
+    }
+    match series(values, interval, range) {
+        Ok(points) => HttpResponse::Ok().json(points),
+        Err(resp) => resp,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HoursWatched {
+    /// Time spent watching, shortened by faster playback
+    hours: f64,
+    /// Total runtime of everything watched
+    runtime_hours: f64,
+    watches: usize,
+}
+
+#[get("/api/stats/movies/hours")]
+async fn hours_watched(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Query(range): Query<DateRange>,
+) -> impl Responder {
+    let data = state.data.lock().await.view(user.id);
+    let mut stats = HoursWatched {
+        hours: 0.,
+        runtime_hours: 0.,
+        watches: 0,
+    };
+    for (movie, rating) in watches(&data, range) {
+        stats.hours += watch_hours(movie, rating);
+        stats.runtime_hours += movie.runtime.as_secs_f64() / 3600.;
+        stats.watches += 1;
+    }
+    HttpResponse::Ok().json(stats)
+}
+
+#[derive(Debug, Serialize)]
+struct RatingDistribution {
+    /// number of movie ratings for every rating from 1 to 10
+    movies: BTreeMap<u8, usize>,
+    /// number of book ratings for every rating from 1 to 10
+    books: BTreeMap<u8, usize>,
+}
+
+#[get("/api/stats/ratings")]
+async fn rating_distribution(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Query(range): Query<DateRange>,
+) -> impl Responder {
+    let data = state.data.lock().await.view(user.id);
+    let mut distribution = RatingDistribution {
+        movies: (1..=10).map(|rating| (rating, 0)).collect(),
+        books: (1..=10).map(|rating| (rating, 0)).collect(),
+    };
+    for (_, rating) in watches(&data, range) {
+        *distribution.movies.entry(rating.rating).or_default() += 1;
+    }
+    let book_ratings = data
+        .books
+        .values()
+        .flat_map(|book| &book.readings)
+        .filter_map(|reading| reading.rating.as_ref())
+        .filter(|rating| range.contains(rating.date));
+    for rating in book_ratings {
+        *distribution.books.entry(rating.rating).or_default() += 1;
+    }
+    HttpResponse::Ok().json(distribution)
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlatformShare {
+    /// Id of the platform, `null` for watches without a platform
+    pub platform: Option<u32>,
+    /// `null` for watches without a platform or on unknown platforms
+    pub name: Option<String>,
+    pub count: usize,
+    pub hours: f64,
+    /// Fraction of all watches in range `0..=1`
+    pub share: f64,
+}
+
+/// Number of watches and hours per platform, most used first.
+pub fn platform_shares(data: &AppData, range: DateRange) -> Vec<PlatformShare> {
+    let mut platforms = BTreeMap::<Option<u32>, WatchCount>::new();
+    for (movie, rating) in watches(data, range) {
+        let value = platforms.entry(rating.platform).or_default();
+        value.count += 1;
+        value.hours += watch_hours(movie, rating);
+    }
+    let total: usize = platforms.values().map(|value| value.count).sum();
+    let mut shares: Vec<_> = platforms
+        .into_iter()
+        .map(|(platform, WatchCount { count, hours })| PlatformShare {
+            platform,
+            name: platform
+                .and_then(|id| data.platforms.get(&id))
+                .map(|platform| platform.name.clone()),
+            count,
+            hours,
+            share: count as f64 / total as f64,
+        })
+        .collect();
+    shares.sort_by_key(|share| std::cmp::Reverse(share.count));
+    shares
+}
+
+#[get("/api/stats/platforms")]
+async fn platform_share(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Query(range): Query<DateRange>,
+) -> impl Responder {
+    HttpResponse::Ok().json(platform_shares(
+        &state.data.lock().await.view(user.id),
+        range,
+    ))
+}
+
+const fn default_top_tags() -> usize {
+    10
+}
+
+#[derive(Deserialize)]
+struct TopTagsQuery {
+    #[serde(default = "default_top_tags")]
+    limit: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TagUsage {
+    pub id: u32,
+    pub name: String,
+    /// Number of watches of movies with this tag, including tags of single ratings
+    pub watches: usize,
+    /// Number of readings of books with this tag
+    pub readings: usize,
+}
+
+/// Usage of all tags used at least once, most used first.
+pub fn tag_usage(data: &AppData, range: DateRange) -> Vec<TagUsage> {
+    let mut usage = HashMap::<u32, TagUsage>::new();
+    for (movie, rating) in watches(data, range) {
+        for id in movie.tags.union(&rating.tags) {
+            usage.entry(*id).or_default().watches += 1;
+        }
+    }
+    for book in data.books.values() {
+        let readings = book
+            .readings
+            .iter()
+            .filter(|reading| reading.pages_read.keys().any(|date| range.contains(*date)))
+            .count();
+        for id in &book.tags {
+            usage.entry(*id).or_default().readings += readings;
+        }
+    }
+    let mut tags: Vec<_> = usage
+        .into_iter()
+        .filter(|(_, usage)| usage.watches + usage.readings > 0)
+        .filter_map(|(id, usage)| {
+            Some(TagUsage {
+                id,
+                name: data.tags.get(&id)?.name.clone(),
+                ..usage
+            })
+        })
+        .collect();
+    tags.sort_by(|a, b| {
+        (b.watches + b.readings)
+            .cmp(&(a.watches + a.readings))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    tags
+}
+
+#[get("/api/stats/tags")]
+async fn top_tags(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Query(range): Query<DateRange>,
+    Query(TopTagsQuery { limit }): Query<TopTagsQuery>,
+) -> impl Responder {
+    let mut tags = tag_usage(&state.data.lock().await.view(user.id), range);
+    tags.truncate(limit);
+    HttpResponse::Ok().json(tags)
+}
+
+#[derive(Debug, Default, Serialize)]
+struct PagesRead {
+    pages: u32,
+}
+
+#[get("/api/stats/books/pages")]
+async fn pages_read(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Query(range): Query<DateRange>,
+    Query(IntervalQuery { interval }): Query<IntervalQuery>,
+) -> impl Responder {
+    let interval = interval.unwrap_or(Interval::Day);
+    let data = state.data.lock().await.view(user.id);
+    let mut values = BTreeMap::<_, PagesRead>::new();
+    let entries = data
+        .books
+        .values()
+        .flat_map(|book| &book.readings)
+        .flat_map(|reading| &reading.pages_read)
+        .filter(|(date, _)| range.contains(**date));
+    for (date, pages) in entries {
+        values.entry(interval.start_of(*date)).or_default().pages += *pages as u32;
+    }
+    match series(values, interval, range) {
+        Ok(points) => HttpResponse::Ok().json(points),
+        Err(resp) => resp,
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct BooksFinished {
+    count: usize,
+    /// IDs of the finished books
+    books: BTreeSet<u32>,
+}
+
+#[get("/api/stats/books/finished")]
+async fn books_finished(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Query(range): Query<DateRange>,
+    Query(IntervalQuery { interval }): Query<IntervalQuery>,
+) -> impl Responder {
+    let interval = interval.unwrap_or(Interval::Year);
+    let data = state.data.lock().await.view(user.id);
+    let mut values = BTreeMap::<_, BooksFinished>::new();
+    for book in data.books.values() {
+        let finished = book
+            .readings
+            .iter()
+            .filter_map(|reading| reading.finished_on())
+            .filter(|date| range.contains(*date));
+        for date in finished {
+            let value = values.entry(interval.start_of(date)).or_default();
+            value.count += 1;
+            value.books.insert(book.id);
+        }
+    }
+    match series(values, interval, range) {
+        Ok(points) => HttpResponse::Ok().json(points),
+        Err(resp) => resp,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Streak {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub days: u64,
+}
+
+/// Splits `dates` into runs of consecutive days.
+pub fn streaks(dates: &BTreeSet<NaiveDate>) -> Vec<Streak> {
+    let mut streaks: Vec<Streak> = vec![];
+    for &date in dates {
+        match streaks.last_mut() {
+            Some(streak) if streak.end.succ_opt() == Some(date) => {
+                streak.end = date;
+                streak.days += 1;
+            }
+            _ => streaks.push(Streak {
+                start: date,
+                end: date,
+                days: 1,
+            }),
+        }
+    }
+    streaks
+}
+
+/// The longest run of consecutive days in `dates`, the earliest one if there are multiple.
+pub fn longest_streak(dates: &BTreeSet<NaiveDate>) -> Option<Streak> {
+    streaks(dates)
+        .into_iter()
+        .rev()
+        .max_by_key(|streak| streak.days)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn periods() {
+        let date = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+        assert_eq!(Interval::Week.start_of(date), date - Days::new(3));
+        assert_eq!(
+            Interval::Month.start_of(date),
+            NaiveDate::from_ymd_opt(2024, 2, 1).unwrap()
+        );
+
+        let values = BTreeMap::from([
+            (NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(), 3),
+            (NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(), 1),
+        ]);
+        let points = series(values, Interval::Month, DateRange::default()).unwrap();
+        assert_eq!(
+            points.iter().map(|point| point.value).collect::<Vec<_>>(),
+            [3, 0, 1]
+        );
+    }
+
+    #[test]
+    fn huge_ranges() {
+        let range = DateRange {
+            from: Some(NaiveDate::MIN),
+            to: Some(NaiveDate::MAX),
+        };
+        assert!(series(BTreeMap::<_, u32>::new(), Interval::Day, range).is_err());
+        let range = DateRange {
+            from: Some(NaiveDate::MAX - Days::new(40)),
+            to: Some(NaiveDate::MAX),
+        };
+        let points = series(BTreeMap::<_, u32>::new(), Interval::Month, range).unwrap();
+        assert_eq!(points.len(), 2);
+    }
+
+    #[test]
+    fn streak() {
+        let date = |day| NaiveDate::from_ymd_opt(2024, 1, day).unwrap();
+        let dates = BTreeSet::from([date(1), date(2), date(4), date(5), date(6), date(9)]);
+        assert_eq!(
+            longest_streak(&dates),
+            Some(Streak {
+                start: date(4),
+                end: date(6),
+                days: 3,
+            })
+        );
+        assert_eq!(streaks(&dates).len(), 3);
+    }
+}
@@ -0,0 +1,49 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::fs;
+
+use super::{write_atomically, Change, Storage};
+use crate::{migrations, schema::AppData};
+
+/// Stores the whole [`AppData`] as a single pretty-printed JSON file, see [`migrations`] for the
+/// versioning of its format.
+pub struct JsonStorage {
+    path: PathBuf,
+}
+
+impl JsonStorage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl Storage for JsonStorage {
+    async fn load(&self) -> Result<AppData> {
+        let saved_data = fs::read_to_string(&self.path)
+            .await
+            .unwrap_or_else(|_| String::from("{}"));
+        migrations::from_str(&saved_data)
+    }
+
+    async fn save(&self, data: &AppData, _change: Change) -> Result<()> {
+        write_atomically(&self.path, migrations::to_string_pretty(data)?).await
+    }
+
+    fn backup_extension(&self) -> &'static str {
+        "json"
+    }
+
+    async fn backup(&self, data: &AppData, path: &Path) -> Result<()> {
+        write_atomically(path, migrations::to_string_pretty(data)?).await
+    }
+
+    async fn restore(&self, path: &Path) -> Result<AppData> {
+        let contents = fs::read_to_string(path).await?;
+        let data = migrations::from_str(&contents)?;
+        write_atomically(&self.path, migrations::to_string_pretty(&data)?).await?;
+        Ok(data)
+    }
+}
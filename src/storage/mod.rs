@@ -0,0 +1,148 @@
+use std::{ffi::OsString, path::Path};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use tokio::{
+    fs::{self, File},
+    io::AsyncWriteExt,
+};
+
+use crate::{
+    config::{config, StorageBackend},
+    schema::AppData,
+};
+
+mod json;
+mod sqlite;
+
+pub use json::JsonStorage;
+pub use sqlite::SqliteStorage;
+
+/// Describes which part of [`AppData`] was modified and needs to be persisted.
+///
+/// Backends that cannot store parts of the data individually are free to ignore
+/// this and write everything.
+#[derive(Debug, Clone)]
+pub enum Change {
+    /// The movie with this TMDB id was added, modified or removed
+    Movie(u64),
+    /// The tag with this id was added, modified or removed
+    Tag(u32),
+    /// The platform with this id was added, modified or removed
+    Platform(u32),
+    /// The book with this id was added, modified or removed
+    Book(u32),
+    /// The series with this TMDB id was added, modified or removed
+    Series(u64),
+    /// The TMDB cache entry with this key was added or modified
+    CacheEntry(String),
+    /// The TMDB TV cache entry for the series with this TMDB id was added or modified
+    TvCacheEntry(u64),
+    /// The whole TMDB cache, including the TV cache, was cleared
+    ClearCache,
+    /// The user with this id was added or modified
+    User(u32),
+    /// The reading goals of the user with this id were modified
+    Goals(u32),
+    /// The watchlist entry of the user with the first id for the movie with the second id was
+    /// added, modified or removed
+    WatchlistEntry(u32, u64),
+    /// The password of the user with this id was set or removed
+    Password(u32),
+    /// The API token with this id was added or removed
+    ApiToken(u32),
+    /// The availability history of the movie with this TMDB id was extended
+    AvailabilityHistory(u64),
+    /// The notification settings of the user with this id were modified
+    Notifications(u32),
+    /// The webhook with this id was added, modified or removed
+    Webhook(u32),
+    /// Anything might have changed, everything has to be written again
+    All,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Reads the complete [`AppData`] from the storage.
+    async fn load(&self) -> Result<AppData>;
+
+    /// Persists the given `change`. `data` is the state after the change was applied.
+    async fn save(&self, data: &AppData, change: Change) -> Result<()>;
+
+    /// File extension (without the dot) used for backups of this storage.
+    fn backup_extension(&self) -> &'static str;
+
+    /// Writes a complete and consistent copy of the stored data to the new file at `path`.
+    async fn backup(&self, data: &AppData, path: &Path) -> Result<()>;
+
+    /// Replaces all stored data with the contents of the backup at `path` and returns the
+    /// restored data.
+    async fn restore(&self, path: &Path) -> Result<AppData>;
+}
+
+/// Replaces the file at `path` with `contents` such that it either contains the old or the new
+/// contents, even if the process is killed in the middle of writing.
+pub async fn write_atomically(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().context("path has no file name")?);
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut file = File::create(&tmp_path).await?;
+    file.write_all(contents.as_ref()).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp_path, path).await?;
+
+    // the rename itself is only durable once the containing directory is synced as well
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Opens the storage backend selected in the [`config`].
+///
+/// When using SQLite and the database is still empty, an existing JSON data file is imported
+/// once and renamed afterwards.
+pub async fn open() -> Result<Box<dyn Storage>> {
+    let config = config();
+    match config.storage {
+        StorageBackend::Json => Ok(Box::new(JsonStorage::new(config.data_file()))),
+        StorageBackend::Sqlite => {
+            let sqlite = SqliteStorage::open(config.database_file())?;
+            if sqlite.is_empty()? && fs::try_exists(config.data_file()).await? {
+                import_json(&sqlite, config.data_file()).await?;
+            }
+            Ok(Box::new(sqlite))
+        }
+    }
+}
+
+/// Copies everything from the JSON data file at `path` into `sqlite` and renames the JSON file
+/// by appending `.imported` to its name.
+pub async fn import_json(sqlite: &SqliteStorage, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let data = JsonStorage::new(path)
+        .load()
+        .await
+        .with_context(|| format!("failed to read '{}' for import", path.display()))?;
+    sqlite.save(&data, Change::All).await?;
+
+    let mut imported_path = path.as_os_str().to_owned();
+    imported_path.push(".imported");
+    fs::rename(path, &imported_path).await?;
+    println!(
+        "imported {} movies, {} tags and {} books from '{}'",
+        data.movies.len(),
+        data.tags.len(),
+        data.books.len(),
+        path.display(),
+    );
+    Ok(())
+}
@@ -0,0 +1,1448 @@
+use std::{borrow::Cow, path::Path, sync::Mutex, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use rusqlite::{backup::Backup, params, Connection, OpenFlags, OptionalExtension, Transaction};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{Change, Storage};
+use crate::schema::{
+    ApiToken, AppData, AvailabilityEvent, Book, Cached, Episode, Goals, Movie, Notifications,
+    Platform, Rating, Reading, Season, Series, Tag, User, WatchlistEntry, Webhook,
+};
+
+/// SQL scripts to upgrade the database schema. The database's `user_version` is the number of
+/// scripts that have already been applied.
+const MIGRATIONS: &[&str] = &[
+    r#"
+CREATE TABLE movies (
+    tmdb_id      INTEGER PRIMARY KEY,
+    imdb_id      INTEGER,
+    title        TEXT NOT NULL,
+    description  TEXT NOT NULL,
+    tags         TEXT NOT NULL,
+    platforms    TEXT NOT NULL,
+    poster       TEXT,
+    release_date TEXT NOT NULL,
+    runtime      INTEGER NOT NULL,
+    score        REAL NOT NULL
+);
+
+CREATE TABLE ratings (
+    movie_id INTEGER NOT NULL REFERENCES movies(tmdb_id) ON DELETE CASCADE,
+    date     TEXT NOT NULL,
+    rating   INTEGER NOT NULL,
+    speed    REAL NOT NULL,
+    platform INTEGER,
+    tags     TEXT NOT NULL,
+    PRIMARY KEY (movie_id, date)
+);
+
+CREATE TABLE tags (
+    id    INTEGER PRIMARY KEY,
+    name  TEXT NOT NULL,
+    color TEXT NOT NULL,
+    icon  TEXT
+);
+
+CREATE TABLE books (
+    id           INTEGER PRIMARY KEY,
+    olid         TEXT,
+    title        TEXT NOT NULL,
+    description  TEXT NOT NULL,
+    authors      TEXT NOT NULL,
+    tags         TEXT NOT NULL,
+    release_date TEXT,
+    score        REAL
+);
+
+CREATE TABLE readings (
+    book_id    INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+    idx        INTEGER NOT NULL,
+    pages_read TEXT NOT NULL,
+    rating     TEXT,
+    isbn       TEXT,
+    start_page INTEGER NOT NULL,
+    end_page   INTEGER NOT NULL,
+    PRIMARY KEY (book_id, idx)
+);
+
+CREATE TABLE tmdb_cache (
+    key   TEXT PRIMARY KEY,
+    movie TEXT NOT NULL
+);
+"#,
+    r#"
+CREATE TABLE settings (
+    key   TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+"#,
+    r#"
+CREATE TABLE watchlist (
+    tmdb_id      INTEGER PRIMARY KEY REFERENCES movies(tmdb_id) ON DELETE CASCADE,
+    priority     TEXT NOT NULL,
+    added        TEXT NOT NULL,
+    note         TEXT,
+    suggested_by TEXT
+);
+"#,
+    r#"
+CREATE TABLE series (
+    tmdb_id        INTEGER PRIMARY KEY,
+    title          TEXT NOT NULL,
+    description    TEXT NOT NULL,
+    tags           TEXT NOT NULL,
+    platforms      TEXT NOT NULL,
+    poster         TEXT,
+    first_air_date TEXT,
+    score          REAL NOT NULL
+);
+
+CREATE TABLE seasons (
+    series_id   INTEGER NOT NULL REFERENCES series(tmdb_id) ON DELETE CASCADE,
+    number      INTEGER NOT NULL,
+    title       TEXT NOT NULL,
+    description TEXT NOT NULL,
+    poster      TEXT,
+    air_date    TEXT,
+    PRIMARY KEY (series_id, number)
+);
+
+CREATE TABLE episodes (
+    series_id   INTEGER NOT NULL,
+    season      INTEGER NOT NULL,
+    number      INTEGER NOT NULL,
+    title       TEXT NOT NULL,
+    description TEXT NOT NULL,
+    air_date    TEXT,
+    runtime     INTEGER NOT NULL,
+    watched     TEXT NOT NULL,
+    ratings     TEXT NOT NULL,
+    PRIMARY KEY (series_id, season, number),
+    FOREIGN KEY (series_id, season) REFERENCES seasons(series_id, number) ON DELETE CASCADE
+);
+
+CREATE TABLE tmdb_tv_cache (
+    tmdb_id INTEGER PRIMARY KEY,
+    series  TEXT NOT NULL
+);
+"#,
+    r#"
+CREATE TABLE users (
+    id    INTEGER PRIMARY KEY,
+    name  TEXT NOT NULL UNIQUE,
+    admin INTEGER NOT NULL
+);
+INSERT INTO users (id, name, admin) VALUES (1, 'admin', 1);
+
+ALTER TABLE ratings RENAME TO old_ratings;
+CREATE TABLE ratings (
+    movie_id INTEGER NOT NULL REFERENCES movies(tmdb_id) ON DELETE CASCADE,
+    user_id  INTEGER NOT NULL,
+    date     TEXT NOT NULL,
+    rating   INTEGER NOT NULL,
+    speed    REAL NOT NULL,
+    platform INTEGER,
+    tags     TEXT NOT NULL,
+    PRIMARY KEY (movie_id, user_id, date)
+);
+INSERT INTO ratings (movie_id, user_id, date, rating, speed, platform, tags)
+    SELECT movie_id, 1, date, rating, speed, platform, tags FROM old_ratings;
+DROP TABLE old_ratings;
+
+ALTER TABLE readings ADD COLUMN user_id INTEGER NOT NULL DEFAULT 1;
+UPDATE readings SET rating = json_set(rating, '$.user', 1) WHERE rating IS NOT NULL;
+
+ALTER TABLE tags ADD COLUMN user_id INTEGER NOT NULL DEFAULT 1;
+
+ALTER TABLE watchlist RENAME TO old_watchlist;
+CREATE TABLE watchlist (
+    user_id      INTEGER NOT NULL,
+    tmdb_id      INTEGER NOT NULL REFERENCES movies(tmdb_id) ON DELETE CASCADE,
+    priority     TEXT NOT NULL,
+    added        TEXT NOT NULL,
+    note         TEXT,
+    suggested_by TEXT,
+    PRIMARY KEY (user_id, tmdb_id)
+);
+INSERT INTO watchlist (user_id, tmdb_id, priority, added, note, suggested_by)
+    SELECT 1, tmdb_id, priority, added, note, suggested_by FROM old_watchlist;
+DROP TABLE old_watchlist;
+
+UPDATE settings SET key = 'goals/1' WHERE key = 'goals';
+
+UPDATE episodes SET
+    watched = CASE watched WHEN '[]' THEN '{}' ELSE json_object('1', json(watched)) END,
+    ratings = (SELECT json_group_array(json_set(value, '$.user', 1)) FROM json_each(ratings));
+-- cached series still use the old format for episodes
+DELETE FROM tmdb_tv_cache;
+"#,
+    r#"
+CREATE TABLE passwords (
+    user_id INTEGER PRIMARY KEY,
+    hash    TEXT NOT NULL
+);
+
+CREATE TABLE api_tokens (
+    id      INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    name    TEXT NOT NULL,
+    scope   TEXT NOT NULL,
+    hash    TEXT NOT NULL UNIQUE,
+    created TEXT NOT NULL
+);
+"#,
+    r#"
+ALTER TABLE users ADD COLUMN region TEXT;
+
+-- the old offers do not say which region they are from, the next refresh fetches them again
+ALTER TABLE movies ADD COLUMN availability TEXT NOT NULL DEFAULT '{}';
+ALTER TABLE series ADD COLUMN availability TEXT NOT NULL DEFAULT '{}';
+UPDATE tmdb_cache SET movie = json_set(movie, '$.availability', json('{}'));
+UPDATE tmdb_tv_cache SET series = json_set(series, '$.availability', json('{}'));
+"#,
+    r#"
+CREATE TABLE platforms (
+    id             INTEGER PRIMARY KEY,
+    name           TEXT NOT NULL,
+    color          TEXT NOT NULL,
+    icon           TEXT,
+    tmdb_providers TEXT NOT NULL
+);
+INSERT INTO platforms (id, name, color, icon, tmdb_providers) VALUES
+    (1, 'Disney+', '[17,60,207]', NULL, '[337]'),
+    (2, 'Jellyfin', '[170,92,195]', NULL, '[]'),
+    (3, 'Netflix', '[229,9,20]', NULL, '[8,175,1796]'),
+    (4, 'Prime Video', '[0,168,225]', NULL, '[9,10,119,2100]'),
+    (5, 'YouTube', '[255,0,0]', NULL, '[188,192,235]'),
+    (6, 'DVD', '[110,110,110]', NULL, '[]'),
+    (7, 'BluRay', '[0,144,206]', NULL, '[]'),
+    (8, 'Cinema', '[180,30,60]', NULL, '[]'),
+    (9, 'TV', '[90,90,90]', NULL, '[]'),
+    (10, 'Airplane', '[70,130,180]', NULL, '[]'),
+    (11, 'Apple TV', '[0,0,0]', NULL, '[2,350]'),
+    (12, 'Stan', '[0,114,206]', NULL, '[21]');
+
+-- ratings stored the index of the built-in platform, which is one less than its new id
+UPDATE ratings SET platform = platform + 1;
+
+CREATE TEMP TABLE builtin_platforms (
+    name          TEXT PRIMARY KEY,
+    id            INTEGER NOT NULL,
+    provider_id   INTEGER,
+    provider_name TEXT
+);
+INSERT INTO builtin_platforms (name, id, provider_id, provider_name) VALUES
+    ('Disney+', 1, 337, 'Disney Plus'),
+    ('Jellyfin', 2, NULL, NULL),
+    ('Netflix', 3, 8, 'Netflix'),
+    ('Prime Video', 4, 9, 'Amazon Prime Video'),
+    ('YouTube', 5, 192, 'YouTube'),
+    ('DVD', 6, NULL, NULL),
+    ('BluRay', 7, NULL, NULL),
+    ('Cinema', 8, NULL, NULL),
+    ('TV', 9, NULL, NULL),
+    ('Airplane', 10, NULL, NULL),
+    ('Apple TV', 11, 2, 'Apple TV'),
+    ('Stan', 12, 21, 'Stan');
+
+UPDATE readings SET rating = json_set(rating, '$.platform',
+    (SELECT id FROM builtin_platforms WHERE name = rating ->> '$.platform'))
+WHERE rating IS NOT NULL;
+UPDATE episodes SET ratings = (
+    SELECT json_group_array(json_set(value, '$.platform',
+        (SELECT id FROM builtin_platforms WHERE name = value ->> '$.platform')))
+    FROM json_each(ratings)
+);
+
+UPDATE movies SET platforms = (
+    SELECT json_group_array(b.id)
+    FROM json_each(movies.platforms) JOIN builtin_platforms AS b ON b.name = value
+);
+UPDATE series SET platforms = (
+    SELECT json_group_array(b.id)
+    FROM json_each(series.platforms) JOIN builtin_platforms AS b ON b.name = value
+);
+UPDATE movies SET availability = (
+    SELECT json_group_object(region.key, json((
+        SELECT json_group_object(tier.key, json((
+            SELECT json_group_array(CASE provider.type
+                WHEN 'object' THEN json(provider.value)
+                ELSE json_object('tmdb_id', b.provider_id, 'name', b.provider_name)
+            END)
+            FROM json_each(tier.value) AS provider
+            LEFT JOIN builtin_platforms AS b ON b.name = provider.value
+            WHERE provider.type = 'object' OR b.provider_id IS NOT NULL
+        )))
+        FROM json_each(region.value) AS tier
+    )))
+    FROM json_each(movies.availability) AS region
+);
+UPDATE series SET availability = (
+    SELECT json_group_object(region.key, json((
+        SELECT json_group_object(tier.key, json((
+            SELECT json_group_array(CASE provider.type
+                WHEN 'object' THEN json(provider.value)
+                ELSE json_object('tmdb_id', b.provider_id, 'name', b.provider_name)
+            END)
+            FROM json_each(tier.value) AS provider
+            LEFT JOIN builtin_platforms AS b ON b.name = provider.value
+            WHERE provider.type = 'object' OR b.provider_id IS NOT NULL
+        )))
+        FROM json_each(region.value) AS tier
+    )))
+    FROM json_each(series.availability) AS region
+);
+
+DROP TABLE builtin_platforms;
+-- cached entries still use the old format
+DELETE FROM tmdb_cache;
+DELETE FROM tmdb_tv_cache;
+"#,
+    r#"
+-- existing entries count as expired
+ALTER TABLE tmdb_cache ADD COLUMN fetched TEXT NOT NULL DEFAULT '1970-01-01 00:00:00+00:00';
+ALTER TABLE tmdb_tv_cache ADD COLUMN fetched TEXT NOT NULL DEFAULT '1970-01-01 00:00:00+00:00';
+"#,
+    r#"
+CREATE TABLE availability_history (
+    tmdb_id  INTEGER NOT NULL REFERENCES movies(tmdb_id) ON DELETE CASCADE,
+    idx      INTEGER NOT NULL,
+    time     TEXT NOT NULL,
+    region   TEXT NOT NULL,
+    platform INTEGER NOT NULL,
+    change   TEXT NOT NULL,
+    PRIMARY KEY (tmdb_id, idx)
+);
+"#,
+    r#"
+CREATE TABLE webhooks (
+    id     INTEGER PRIMARY KEY,
+    url    TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    events TEXT NOT NULL
+);
+"#,
+    r#"
+ALTER TABLE movies ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE tags ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE books ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+"#,
+];
+
+/// Stores [`AppData`] in an SQLite database, so that changes to single entities only require
+/// writing the affected rows.
+pub struct SqliteStorage {
+    // The queries are small and fast enough that running them directly on the async executor is
+    // fine. The mutex is only needed for `Sync` and is never contended because all writes already
+    // happen while holding the `AppState` lock.
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Opens or creates the database at `path` and upgrades its schema if necessary.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+        migrate(&mut conn)?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Returns `true` if the database does not contain any data yet.
+    pub fn is_empty(&self) -> Result<bool> {
+        let conn = self.conn.lock().expect("mutex should not be poisoned");
+        let any: Option<i64> = conn
+            .query_row(
+                "SELECT 1 FROM movies UNION ALL SELECT 1 FROM tags UNION ALL SELECT 1 FROM books
+                 UNION ALL SELECT 1 FROM series UNION ALL SELECT 1 FROM tmdb_cache
+                 UNION ALL SELECT 1 FROM tmdb_tv_cache LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(any.is_none())
+    }
+}
+
+/// Applies all [`MIGRATIONS`] that have not been applied to `conn` yet.
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(anyhow!(
+            "database schema version {version} is newer than the latest known version {}",
+            MIGRATIONS.len()
+        ));
+    }
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("failed to migrate database to version {}", idx + 1))?;
+        tx.pragma_update(None, "user_version", idx + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn to_json(value: &impl Serialize) -> Result<String> {
+    Ok(serde_json::to_string(value)?)
+}
+
+fn from_json<T: DeserializeOwned>(json: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(json).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, err.into())
+    })
+}
+
+fn write_movie(tx: &Transaction, id: u64, movie: Option<&Movie>) -> Result<()> {
+    tx.execute("DELETE FROM ratings WHERE movie_id = ?1", [id])?;
+    let Some(movie) = movie else {
+        tx.execute("DELETE FROM movies WHERE tmdb_id = ?1", [id])?;
+        return Ok(());
+    };
+    tx.execute(
+        "INSERT INTO movies
+            (tmdb_id, imdb_id, title, description, tags, platforms, poster, release_date, runtime,
+             score, availability, revision)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+         ON CONFLICT (tmdb_id) DO UPDATE SET
+            imdb_id = excluded.imdb_id, title = excluded.title,
+            description = excluded.description, tags = excluded.tags,
+            platforms = excluded.platforms, poster = excluded.poster,
+            release_date = excluded.release_date, runtime = excluded.runtime,
+            score = excluded.score, availability = excluded.availability,
+            revision = excluded.revision",
+        params![
+            movie.tmdb_id,
+            movie.imdb_id,
+            movie.title,
+            movie.description,
+            to_json(&movie.tags)?,
+            to_json(&movie.platforms)?,
+            movie.poster,
+            movie.release_date,
+            movie.runtime.as_secs(),
+            movie.score,
+            to_json(&movie.availability)?,
+            movie.revision,
+        ],
+    )?;
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO ratings (movie_id, user_id, date, rating, speed, platform, tags)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    for rating in &movie.ratings {
+        stmt.execute(params![
+            id,
+            rating.user,
+            rating.date,
+            rating.rating,
+            rating.speed,
+            rating.platform,
+            to_json(&rating.tags)?,
+        ])?;
+    }
+    Ok(())
+}
+
+fn write_tag(tx: &Transaction, id: u32, tag: Option<&Tag>) -> Result<()> {
+    match tag {
+        Some(tag) => tx.execute(
+            "INSERT OR REPLACE INTO tags (id, user_id, name, color, icon, revision)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                tag.id,
+                tag.user,
+                tag.name,
+                to_json(&tag.color)?,
+                tag.icon.as_deref(),
+                tag.revision,
+            ],
+        )?,
+        None => tx.execute("DELETE FROM tags WHERE id = ?1", [id])?,
+    };
+    Ok(())
+}
+
+fn write_platform(tx: &Transaction, id: u32, platform: Option<&Platform>) -> Result<()> {
+    match platform {
+        Some(platform) => tx.execute(
+            "INSERT OR REPLACE INTO platforms (id, name, color, icon, tmdb_providers)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                platform.id,
+                platform.name,
+                to_json(&platform.color)?,
+                platform.icon.as_deref(),
+                to_json(&platform.tmdb_providers)?,
+            ],
+        )?,
+        None => tx.execute("DELETE FROM platforms WHERE id = ?1", [id])?,
+    };
+    Ok(())
+}
+
+fn write_book(tx: &Transaction, id: u32, book: Option<&Book>) -> Result<()> {
+    tx.execute("DELETE FROM readings WHERE book_id = ?1", [id])?;
+    let Some(book) = book else {
+        tx.execute("DELETE FROM books WHERE id = ?1", [id])?;
+        return Ok(());
+    };
+    tx.execute(
+        "INSERT INTO books
+            (id, olid, title, description, authors, tags, release_date, score, revision)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT (id) DO UPDATE SET
+            olid = excluded.olid, title = excluded.title, description = excluded.description,
+            authors = excluded.authors, tags = excluded.tags,
+            release_date = excluded.release_date, score = excluded.score,
+            revision = excluded.revision",
+        params![
+            book.id,
+            book.olid.map(|olid| olid.to_string()),
+            book.title,
+            book.description,
+            to_json(&book.authors)?,
+            to_json(&book.tags)?,
+            book.release_date,
+            book.score,
+            book.revision,
+        ],
+    )?;
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO readings
+            (book_id, idx, user_id, pages_read, rating, isbn, start_page, end_page)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?;
+    for (idx, reading) in book.readings.iter().enumerate() {
+        stmt.execute(params![
+            id,
+            idx,
+            reading.user,
+            to_json(&reading.pages_read)?,
+            reading.rating.as_ref().map(to_json).transpose()?,
+            reading.isbn,
+            reading.start_page,
+            reading.end_page,
+        ])?;
+    }
+    Ok(())
+}
+
+fn write_series(tx: &Transaction, id: u64, series: Option<&Series>) -> Result<()> {
+    tx.execute("DELETE FROM seasons WHERE series_id = ?1", [id])?;
+    let Some(series) = series else {
+        tx.execute("DELETE FROM series WHERE tmdb_id = ?1", [id])?;
+        return Ok(());
+    };
+    tx.execute(
+        "INSERT INTO series
+            (tmdb_id, title, description, tags, platforms, poster, first_air_date, score,
+             availability)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT (tmdb_id) DO UPDATE SET
+            title = excluded.title, description = excluded.description, tags = excluded.tags,
+            platforms = excluded.platforms, poster = excluded.poster,
+            first_air_date = excluded.first_air_date, score = excluded.score,
+            availability = excluded.availability",
+        params![
+            series.tmdb_id,
+            series.title,
+            series.description,
+            to_json(&series.tags)?,
+            to_json(&series.platforms)?,
+            series.poster,
+            series.first_air_date,
+            series.score,
+            to_json(&series.availability)?,
+        ],
+    )?;
+    let mut season_stmt = tx.prepare_cached(
+        "INSERT INTO seasons (series_id, number, title, description, poster, air_date)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    let mut episode_stmt = tx.prepare_cached(
+        "INSERT INTO episodes
+            (series_id, season, number, title, description, air_date, runtime, watched, ratings)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+    )?;
+    for season in &series.seasons {
+        season_stmt.execute(params![
+            id,
+            season.number,
+            season.title,
+            season.description,
+            season.poster,
+            season.air_date,
+        ])?;
+        for episode in &season.episodes {
+            episode_stmt.execute(params![
+                id,
+                season.number,
+                episode.number,
+                episode.title,
+                episode.description,
+                episode.air_date,
+                episode.runtime.as_secs(),
+                to_json(&episode.watched)?,
+                to_json(&episode.ratings)?,
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+fn write_cache_entry(tx: &Transaction, key: &str, entry: Option<&Cached<Movie>>) -> Result<()> {
+    match entry {
+        Some(entry) => tx.execute(
+            "INSERT OR REPLACE INTO tmdb_cache (key, movie, fetched) VALUES (?1, ?2, ?3)",
+            params![key, to_json(&entry.value)?, entry.fetched],
+        )?,
+        None => tx.execute("DELETE FROM tmdb_cache WHERE key = ?1", [key])?,
+    };
+    Ok(())
+}
+
+fn write_tv_cache_entry(tx: &Transaction, id: u64, entry: Option<&Cached<Series>>) -> Result<()> {
+    match entry {
+        Some(entry) => tx.execute(
+            "INSERT OR REPLACE INTO tmdb_tv_cache (tmdb_id, series, fetched) VALUES (?1, ?2, ?3)",
+            params![id, to_json(&entry.value)?, entry.fetched],
+        )?,
+        None => tx.execute("DELETE FROM tmdb_tv_cache WHERE tmdb_id = ?1", [id])?,
+    };
+    Ok(())
+}
+
+fn write_user(tx: &Transaction, id: u32, user: Option<&User>) -> Result<()> {
+    match user {
+        Some(user) => tx.execute(
+            "INSERT OR REPLACE INTO users (id, name, admin, region) VALUES (?1, ?2, ?3, ?4)",
+            params![user.id, user.name, user.admin, user.region],
+        )?,
+        None => tx.execute("DELETE FROM users WHERE id = ?1", [id])?,
+    };
+    Ok(())
+}
+
+fn write_goals(tx: &Transaction, user: u32, goals: Option<&Goals>) -> Result<()> {
+    let key = format!("goals/{user}");
+    match goals {
+        Some(goals) => tx.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            params![key, to_json(goals)?],
+        )?,
+        None => tx.execute("DELETE FROM settings WHERE key = ?1", [key])?,
+    };
+    Ok(())
+}
+
+fn write_notifications(
+    tx: &Transaction,
+    user: u32,
+    notifications: Option<&Notifications>,
+) -> Result<()> {
+    let key = format!("notifications/{user}");
+    match notifications {
+        Some(notifications) => tx.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES (?1, ?2)",
+            params![key, to_json(notifications)?],
+        )?,
+        None => tx.execute("DELETE FROM settings WHERE key = ?1", [key])?,
+    };
+    Ok(())
+}
+
+fn write_availability_history(
+    tx: &Transaction,
+    id: u64,
+    events: Option<&Vec<AvailabilityEvent>>,
+) -> Result<()> {
+    tx.execute("DELETE FROM availability_history WHERE tmdb_id = ?1", [id])?;
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO availability_history (tmdb_id, idx, time, region, platform, change)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    for (idx, event) in events.into_iter().flatten().enumerate() {
+        stmt.execute(params![
+            id,
+            idx,
+            event.time,
+            event.region,
+            event.platform,
+            <&str>::from(event.change),
+        ])?;
+    }
+    Ok(())
+}
+
+fn write_webhook(tx: &Transaction, id: u32, webhook: Option<&Webhook>) -> Result<()> {
+    match webhook {
+        Some(webhook) => tx.execute(
+            "INSERT OR REPLACE INTO webhooks (id, url, secret, events) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                webhook.id,
+                webhook.url,
+                webhook.secret,
+                to_json(&webhook.events)?
+            ],
+        )?,
+        None => tx.execute("DELETE FROM webhooks WHERE id = ?1", [id])?,
+    };
+    Ok(())
+}
+
+fn write_watchlist_entry(
+    tx: &Transaction,
+    user: u32,
+    id: u64,
+    entry: Option<&WatchlistEntry>,
+) -> Result<()> {
+    match entry {
+        Some(entry) => tx.execute(
+            "INSERT OR REPLACE INTO watchlist
+                (user_id, tmdb_id, priority, added, note, suggested_by)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                user,
+                entry.tmdb_id,
+                <&str>::from(entry.priority),
+                entry.added,
+                entry.note,
+                entry.suggested_by,
+            ],
+        )?,
+        None => tx.execute(
+            "DELETE FROM watchlist WHERE user_id = ?1 AND tmdb_id = ?2",
+            params![user, id],
+        )?,
+    };
+    Ok(())
+}
+
+fn write_password(tx: &Transaction, user: u32, hash: Option<&String>) -> Result<()> {
+    match hash {
+        Some(hash) => tx.execute(
+            "INSERT OR REPLACE INTO passwords (user_id, hash) VALUES (?1, ?2)",
+            params![user, hash],
+        )?,
+        None => tx.execute("DELETE FROM passwords WHERE user_id = ?1", [user])?,
+    };
+    Ok(())
+}
+
+fn write_api_token(tx: &Transaction, id: u32, token: Option<&ApiToken>) -> Result<()> {
+    match token {
+        Some(token) => tx.execute(
+            "INSERT OR REPLACE INTO api_tokens (id, user_id, name, scope, hash, created)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                token.id,
+                token.user,
+                token.name,
+                <&str>::from(token.scope),
+                token.hash,
+                token.created,
+            ],
+        )?,
+        None => tx.execute("DELETE FROM api_tokens WHERE id = ?1", [id])?,
+    };
+    Ok(())
+}
+
+fn write_all(tx: &Transaction, data: &AppData) -> Result<()> {
+    tx.execute_batch(
+        "DELETE FROM watchlist; DELETE FROM ratings; DELETE FROM movies; DELETE FROM tags;
+         DELETE FROM platforms; DELETE FROM readings; DELETE FROM books; DELETE FROM episodes; DELETE FROM seasons;
+         DELETE FROM series; DELETE FROM tmdb_cache; DELETE FROM tmdb_tv_cache;
+         DELETE FROM users; DELETE FROM settings WHERE key LIKE 'goals/%';
+         DELETE FROM settings WHERE key LIKE 'notifications/%';
+         DELETE FROM passwords; DELETE FROM api_tokens; DELETE FROM availability_history;
+         DELETE FROM webhooks;",
+    )?;
+    for (&id, user) in &data.users {
+        write_user(tx, id, Some(user))?;
+    }
+    for (&user, hash) in &data.passwords {
+        write_password(tx, user, Some(hash))?;
+    }
+    for (&id, token) in &data.api_tokens {
+        write_api_token(tx, id, Some(token))?;
+    }
+    for (&user, goals) in &data.goals {
+        write_goals(tx, user, Some(goals))?;
+    }
+    for (&user, notifications) in &data.notifications {
+        write_notifications(tx, user, Some(notifications))?;
+    }
+    for (&id, webhook) in &data.webhooks {
+        write_webhook(tx, id, Some(webhook))?;
+    }
+    for (&id, movie) in &data.movies {
+        write_movie(tx, id, Some(movie))?;
+    }
+    for (&id, events) in &data.availability_history {
+        write_availability_history(tx, id, Some(events))?;
+    }
+    for (&id, tag) in &data.tags {
+        write_tag(tx, id, Some(tag))?;
+    }
+    for (&id, platform) in &data.platforms {
+        write_platform(tx, id, Some(platform))?;
+    }
+    for (&id, book) in &data.books {
+        write_book(tx, id, Some(book))?;
+    }
+    for (&id, series) in &data.series {
+        write_series(tx, id, Some(series))?;
+    }
+    for (key, entry) in &data.tmdb_cache {
+        write_cache_entry(tx, key, Some(entry))?;
+    }
+    for (&id, entry) in &data.tmdb_tv_cache {
+        write_tv_cache_entry(tx, id, Some(entry))?;
+    }
+    for (&user, watchlist) in &data.watchlists {
+        for (&id, entry) in watchlist {
+            write_watchlist_entry(tx, user, id, Some(entry))?;
+        }
+    }
+    Ok(())
+}
+
+fn read_all(conn: &Connection) -> Result<AppData> {
+    let mut data = AppData::default();
+
+    let mut stmt = conn.prepare("SELECT id, name, admin, region FROM users")?;
+    let users = stmt.query_map([], |row| {
+        Ok(User {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            admin: row.get(2)?,
+            region: row.get(3)?,
+        })
+    })?;
+    for user in users {
+        let user = user?;
+        data.users.insert(user.id, user);
+    }
+
+    let mut stmt = conn.prepare("SELECT user_id, hash FROM passwords")?;
+    let passwords = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    for entry in passwords {
+        let (user, hash) = entry?;
+        data.passwords.insert(user, hash);
+    }
+
+    let mut stmt =
+        conn.prepare("SELECT id, user_id, name, scope, hash, created FROM api_tokens")?;
+    let tokens = stmt.query_map([], |row| {
+        Ok(ApiToken {
+            id: row.get(0)?,
+            user: row.get(1)?,
+            name: row.get(2)?,
+            scope: row
+                .get::<_, String>(3)?
+                .parse()
+                .map_err(|err: strum::ParseError| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        3,
+                        rusqlite::types::Type::Text,
+                        err.into(),
+                    )
+                })?,
+            hash: row.get(4)?,
+            created: row.get(5)?,
+        })
+    })?;
+    for token in tokens {
+        let token = token?;
+        data.api_tokens.insert(token.id, token);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT tmdb_id, imdb_id, title, description, tags, platforms, poster, release_date,
+            runtime, score, availability, revision
+         FROM movies",
+    )?;
+    let movies = stmt.query_map([], |row| {
+        Ok(Movie {
+            tmdb_id: row.get(0)?,
+            imdb_id: row.get(1)?,
+            title: row.get(2)?,
+            description: row.get(3)?,
+            ratings: vec![],
+            tags: from_json(&row.get::<_, String>(4)?)?,
+            platforms: from_json(&row.get::<_, String>(5)?)?,
+            availability: from_json(&row.get::<_, String>(10)?)?,
+            poster: row.get(6)?,
+            release_date: row.get(7)?,
+            runtime: Duration::from_secs(row.get(8)?),
+            score: row.get(9)?,
+            revision: row.get(11)?,
+        })
+    })?;
+    for movie in movies {
+        let movie = movie?;
+        data.movies.insert(movie.tmdb_id, movie);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT movie_id, user_id, date, rating, speed, platform, tags FROM ratings
+         ORDER BY movie_id, date DESC",
+    )?;
+    let ratings = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u64>(0)?,
+            Rating {
+                date: row.get(2)?,
+                rating: row.get(3)?,
+                speed: row.get(4)?,
+                platform: row.get(5)?,
+                tags: from_json(&row.get::<_, String>(6)?)?,
+                user: row.get(1)?,
+            },
+        ))
+    })?;
+    for rating in ratings {
+        let (movie_id, rating) = rating?;
+        if let Some(movie) = data.movies.get_mut(&movie_id) {
+            movie.ratings.push(rating);
+        }
+    }
+
+    let mut stmt = conn.prepare("SELECT id, user_id, name, color, icon, revision FROM tags")?;
+    let tags = stmt.query_map([], |row| {
+        Ok(Tag {
+            id: row.get(0)?,
+            user: row.get(1)?,
+            name: row.get(2)?,
+            color: from_json(&row.get::<_, String>(3)?)?,
+            icon: row.get::<_, Option<String>>(4)?.map(Cow::Owned),
+            revision: row.get(5)?,
+        })
+    })?;
+    for tag in tags {
+        let tag = tag?;
+        data.tags.insert(tag.id, tag);
+    }
+
+    let mut stmt = conn.prepare("SELECT id, name, color, icon, tmdb_providers FROM platforms")?;
+    let platforms = stmt.query_map([], |row| {
+        Ok(Platform {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            color: from_json(&row.get::<_, String>(2)?)?,
+            icon: row.get::<_, Option<String>>(3)?.map(Cow::Owned),
+            tmdb_providers: from_json(&row.get::<_, String>(4)?)?,
+        })
+    })?;
+    for platform in platforms {
+        let platform = platform?;
+        data.platforms.insert(platform.id, platform);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT id, olid, title, description, authors, tags, release_date, score, revision
+         FROM books",
+    )?;
+    let books = stmt.query_map([], |row| {
+        Ok(Book {
+            id: row.get(0)?,
+            olid: row
+                .get::<_, Option<String>>(1)?
+                .map(|olid| olid.parse())
+                .transpose()
+                .map_err(|err: anyhow::Error| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        1,
+                        rusqlite::types::Type::Text,
+                        err.into(),
+                    )
+                })?,
+            title: row.get(2)?,
+            description: row.get(3)?,
+            authors: from_json(&row.get::<_, String>(4)?)?,
+            readings: vec![],
+            tags: from_json(&row.get::<_, String>(5)?)?,
+            release_date: row.get(6)?,
+            score: row.get(7)?,
+            revision: row.get(8)?,
+        })
+    })?;
+    for book in books {
+        let book = book?;
+        data.books.insert(book.id, book);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT book_id, user_id, pages_read, rating, isbn, start_page, end_page FROM readings
+         ORDER BY book_id, idx",
+    )?;
+    let readings = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u32>(0)?,
+            Reading {
+                user: row.get(1)?,
+                pages_read: from_json(&row.get::<_, String>(2)?)?,
+                rating: row
+                    .get::<_, Option<String>>(3)?
+                    .map(|json| from_json(&json))
+                    .transpose()?,
+                isbn: row.get(4)?,
+                start_page: row.get(5)?,
+                end_page: row.get(6)?,
+            },
+        ))
+    })?;
+    for reading in readings {
+        let (book_id, reading) = reading?;
+        if let Some(book) = data.books.get_mut(&book_id) {
+            book.readings.push(reading);
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT tmdb_id, title, description, tags, platforms, poster, first_air_date, score,
+            availability
+         FROM series",
+    )?;
+    let series = stmt.query_map([], |row| {
+        Ok(Series {
+            tmdb_id: row.get(0)?,
+            title: row.get(1)?,
+            description: row.get(2)?,
+            tags: from_json(&row.get::<_, String>(3)?)?,
+            platforms: from_json(&row.get::<_, String>(4)?)?,
+            availability: from_json(&row.get::<_, String>(8)?)?,
+            poster: row.get(5)?,
+            first_air_date: row.get(6)?,
+            score: row.get(7)?,
+            seasons: vec![],
+        })
+    })?;
+    for series in series {
+        let series = series?;
+        data.series.insert(series.tmdb_id, series);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT series_id, number, title, description, poster, air_date FROM seasons
+         ORDER BY series_id, number",
+    )?;
+    let seasons = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u64>(0)?,
+            Season {
+                number: row.get(1)?,
+                title: row.get(2)?,
+                description: row.get(3)?,
+                poster: row.get(4)?,
+                air_date: row.get(5)?,
+                episodes: vec![],
+            },
+        ))
+    })?;
+    for season in seasons {
+        let (series_id, season) = season?;
+        if let Some(series) = data.series.get_mut(&series_id) {
+            series.seasons.push(season);
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT series_id, season, number, title, description, air_date, runtime, watched, ratings
+         FROM episodes ORDER BY series_id, season, number",
+    )?;
+    let episodes = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u64>(0)?,
+            row.get::<_, u32>(1)?,
+            Episode {
+                number: row.get(2)?,
+                title: row.get(3)?,
+                description: row.get(4)?,
+                air_date: row.get(5)?,
+                runtime: Duration::from_secs(row.get(6)?),
+                watched: from_json(&row.get::<_, String>(7)?)?,
+                ratings: from_json(&row.get::<_, String>(8)?)?,
+            },
+        ))
+    })?;
+    for episode in episodes {
+        let (series_id, season_number, episode) = episode?;
+        let season = data.series.get_mut(&series_id).and_then(|series| {
+            series
+                .seasons
+                .iter_mut()
+                .find(|s| s.number == season_number)
+        });
+        if let Some(season) = season {
+            season.episodes.push(episode);
+        }
+    }
+
+    let mut stmt = conn.prepare("SELECT key, movie, fetched FROM tmdb_cache")?;
+    let entries = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            Cached {
+                value: from_json(&row.get::<_, String>(1)?)?,
+                fetched: row.get(2)?,
+            },
+        ))
+    })?;
+    for entry in entries {
+        let (key, movie) = entry?;
+        data.tmdb_cache.insert(key, movie);
+    }
+
+    let mut stmt = conn.prepare("SELECT tmdb_id, series, fetched FROM tmdb_tv_cache")?;
+    let entries = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u64>(0)?,
+            Cached {
+                value: from_json(&row.get::<_, String>(1)?)?,
+                fetched: row.get(2)?,
+            },
+        ))
+    })?;
+    for entry in entries {
+        let (id, series) = entry?;
+        data.tmdb_tv_cache.insert(id, series);
+    }
+
+    let mut stmt = conn.prepare("SELECT key, value FROM settings WHERE key LIKE 'goals/%'")?;
+    let goals = stmt.query_map([], |row| {
+        let key = row.get::<_, String>(0)?;
+        let user = key["goals/".len()..].parse().map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))
+        })?;
+        Ok((user, from_json(&row.get::<_, String>(1)?)?))
+    })?;
+    for entry in goals {
+        let (user, goals) = entry?;
+        data.goals.insert(user, goals);
+    }
+
+    let mut stmt =
+        conn.prepare("SELECT key, value FROM settings WHERE key LIKE 'notifications/%'")?;
+    let notifications = stmt.query_map([], |row| {
+        let key = row.get::<_, String>(0)?;
+        let user = key["notifications/".len()..].parse().map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err))
+        })?;
+        Ok((user, from_json(&row.get::<_, String>(1)?)?))
+    })?;
+    for entry in notifications {
+        let (user, notifications) = entry?;
+        data.notifications.insert(user, notifications);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT tmdb_id, time, region, platform, change FROM availability_history
+         ORDER BY tmdb_id, idx",
+    )?;
+    let events = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u64>(0)?,
+            AvailabilityEvent {
+                time: row.get(1)?,
+                region: row.get(2)?,
+                platform: row.get(3)?,
+                change: row
+                    .get::<_, String>(4)?
+                    .parse()
+                    .map_err(|err: strum::ParseError| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            4,
+                            rusqlite::types::Type::Text,
+                            err.into(),
+                        )
+                    })?,
+            },
+        ))
+    })?;
+    for entry in events {
+        let (id, event) = entry?;
+        data.availability_history.entry(id).or_default().push(event);
+    }
+
+    let mut stmt = conn.prepare("SELECT id, url, secret, events FROM webhooks")?;
+    let webhooks = stmt.query_map([], |row| {
+        Ok(Webhook {
+            id: row.get(0)?,
+            url: row.get(1)?,
+            secret: row.get(2)?,
+            events: from_json(&row.get::<_, String>(3)?)?,
+        })
+    })?;
+    for webhook in webhooks {
+        let webhook = webhook?;
+        data.webhooks.insert(webhook.id, webhook);
+    }
+
+    let mut stmt = conn
+        .prepare("SELECT user_id, tmdb_id, priority, added, note, suggested_by FROM watchlist")?;
+    let entries = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u32>(0)?,
+            WatchlistEntry {
+                tmdb_id: row.get(1)?,
+                priority: row
+                    .get::<_, String>(2)?
+                    .parse()
+                    .map_err(|err: strum::ParseError| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            2,
+                            rusqlite::types::Type::Text,
+                            err.into(),
+                        )
+                    })?,
+                added: row.get(3)?,
+                note: row.get(4)?,
+                suggested_by: row.get(5)?,
+            },
+        ))
+    })?;
+    for entry in entries {
+        let (user, entry) = entry?;
+        data.watchlists
+            .entry(user)
+            .or_default()
+            .insert(entry.tmdb_id, entry);
+    }
+
+    Ok(data)
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn load(&self) -> Result<AppData> {
+        read_all(&self.conn.lock().expect("mutex should not be poisoned"))
+    }
+
+    async fn save(&self, data: &AppData, change: Change) -> Result<()> {
+        let mut conn = self.conn.lock().expect("mutex should not be poisoned");
+        let tx = conn.transaction()?;
+        match change {
+            Change::Movie(id) => write_movie(&tx, id, data.movies.get(&id))?,
+            Change::Tag(id) => write_tag(&tx, id, data.tags.get(&id))?,
+            Change::Platform(id) => write_platform(&tx, id, data.platforms.get(&id))?,
+            Change::Book(id) => write_book(&tx, id, data.books.get(&id))?,
+            Change::Series(id) => write_series(&tx, id, data.series.get(&id))?,
+            Change::CacheEntry(key) => write_cache_entry(&tx, &key, data.tmdb_cache.get(&key))?,
+            Change::TvCacheEntry(id) => write_tv_cache_entry(&tx, id, data.tmdb_tv_cache.get(&id))?,
+            Change::ClearCache => {
+                tx.execute_batch("DELETE FROM tmdb_cache; DELETE FROM tmdb_tv_cache;")?;
+            }
+            Change::User(id) => write_user(&tx, id, data.users.get(&id))?,
+            Change::Goals(user) => write_goals(&tx, user, data.goals.get(&user))?,
+            Change::WatchlistEntry(user, id) => write_watchlist_entry(
+                &tx,
+                user,
+                id,
+                data.watchlists
+                    .get(&user)
+                    .and_then(|watchlist| watchlist.get(&id)),
+            )?,
+            Change::Password(user) => write_password(&tx, user, data.passwords.get(&user))?,
+            Change::ApiToken(id) => write_api_token(&tx, id, data.api_tokens.get(&id))?,
+            Change::AvailabilityHistory(id) => {
+                write_availability_history(&tx, id, data.availability_history.get(&id))?
+            }
+            Change::Notifications(user) => {
+                write_notifications(&tx, user, data.notifications.get(&user))?
+            }
+            Change::Webhook(id) => write_webhook(&tx, id, data.webhooks.get(&id))?,
+            Change::All => write_all(&tx, data)?,
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn backup_extension(&self) -> &'static str {
+        "db"
+    }
+
+    async fn backup(&self, _data: &AppData, path: &Path) -> Result<()> {
+        let path = path.to_str().context("backup path is not valid UTF-8")?;
+        self.conn
+            .lock()
+            .expect("mutex should not be poisoned")
+            .execute("VACUUM INTO ?1", [path])?;
+        Ok(())
+    }
+
+    async fn restore(&self, path: &Path) -> Result<AppData> {
+        // the backup might be from an older schema, so it is upgraded and read in memory first
+        // and only replaces the live database once that succeeded
+        let source = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut restored = Connection::open_in_memory()?;
+        Backup::new(&source, &mut restored)?.run_to_completion(1024, Duration::ZERO, None)?;
+        migrate(&mut restored)?;
+        let data = read_all(&restored)?;
+
+        let mut conn = self.conn.lock().expect("mutex should not be poisoned");
+        Backup::new(&restored, &mut conn)?.run_to_completion(1024, Duration::ZERO, None)?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        migrations,
+        storage::{import_json, JsonStorage},
+    };
+
+    /// Returns an empty directory for the test called `name`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("entrackment-sqlite-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn data() -> AppData {
+        let movie = json!({
+            "imdb_id": 133093,
+            "tmdb_id": 603,
+            "title": "The Matrix",
+            "description": "",
+            "ratings": [
+                {"date": "2024-02-01", "rating": 18, "speed": 1.5, "platform": null},
+                {
+                    "date": "2024-01-01",
+                    "rating": 16,
+                    "speed": 1.0,
+                    "platform": "Netflix",
+                    "tags": [17]
+                }
+            ],
+            "tags": [17],
+            "platforms": ["Netflix"],
+            "poster": "/matrix.jpg",
+            "release_date": "1999-03-31",
+            "runtime": {"secs": 8160, "nanos": 0},
+            "score": 8.2
+        });
+        let mut cached = movie.clone();
+        cached["ratings"] = json!([]);
+        // written in the format of version 0, so that it stays valid when the format changes
+        let mut value = json!({
+            "movies": {"603": movie},
+            "tags": {"17": {"id": 17, "name": "classic", "color": [1, 2, 3], "icon": null}},
+            "tmdb_cache": {"603": cached},
+            "books": {"42": {
+                "id": 42,
+                "olid": null,
+                "title": "Erebos",
+                "description": "",
+                "authors": ["Ursula Poznanski"],
+                "readings": [{
+                    "pages_read": {"2024-03-01": 120, "2024-03-02": 365},
+                    "rating": {"date": "2024-03-02", "rating": 17, "speed": 1.0, "platform": null},
+                    "isbn": null,
+                    "start_page": 1,
+                    "end_page": 485
+                }],
+                "tags": [17],
+                "release_date": null,
+                "score": null
+            }}
+        });
+        migrations::migrate(&mut value).unwrap();
+        let mut data: AppData = serde_json::from_value(value).unwrap();
+        // empty watchlists are not stored
+        data.watchlists.retain(|_, watchlist| !watchlist.is_empty());
+        data
+    }
+
+    fn json(data: &AppData) -> Value {
+        serde_json::to_value(data).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn roundtrip() {
+        let dir = test_dir("roundtrip");
+        let storage = SqliteStorage::open(dir.join("data.db")).unwrap();
+        assert!(storage.is_empty().unwrap());
+        let mut data = data();
+        storage.save(&data, Change::All).await.unwrap();
+        assert!(!storage.is_empty().unwrap());
+        assert_eq!(json(&storage.load().await.unwrap()), json(&data));
+
+        // single changes only touch the affected rows
+        data.movies.get_mut(&603).unwrap().ratings.pop();
+        storage.save(&data, Change::Movie(603)).await.unwrap();
+        data.tags.get_mut(&17).unwrap().name = "renamed".into();
+        storage.save(&data, Change::Tag(17)).await.unwrap();
+        data.books.remove(&42);
+        storage.save(&data, Change::Book(42)).await.unwrap();
+        drop(storage);
+        let storage = SqliteStorage::open(dir.join("data.db")).unwrap();
+        assert_eq!(json(&storage.load().await.unwrap()), json(&data));
+
+        drop(storage);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn imports_json() {
+        let dir = test_dir("import");
+        let data = data();
+        JsonStorage::new(dir.join("data.json"))
+            .save(&data, Change::All)
+            .await
+            .unwrap();
+        let storage = SqliteStorage::open(dir.join("data.db")).unwrap();
+        import_json(&storage, dir.join("data.json")).await.unwrap();
+        assert_eq!(json(&storage.load().await.unwrap()), json(&data));
+        assert!(!dir.join("data.json").exists());
+        assert!(dir.join("data.json.imported").exists());
+
+        drop(storage);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn restore_migrates_old_backups() {
+        let dir = test_dir("restore");
+        let backup_path = dir.join("backup.db");
+        let backup = Connection::open(&backup_path).unwrap();
+        backup.execute_batch(MIGRATIONS[0]).unwrap();
+        backup.pragma_update(None, "user_version", 1).unwrap();
+        backup
+            .execute(
+                "INSERT INTO tags (id, name, color, icon) VALUES (1, 'old', '[1,2,3]', NULL)",
+                [],
+            )
+            .unwrap();
+        drop(backup);
+
+        let storage = SqliteStorage::open(dir.join("data.db")).unwrap();
+        let data = storage.restore(&backup_path).await.unwrap();
+        assert_eq!(data.tags[&1].name, "old");
+        assert_eq!(data.tags[&1].user, 1);
+        let loaded = storage.load().await.unwrap();
+        assert_eq!(loaded.tags[&1].name, "old");
+        let conn = storage.conn.lock().unwrap();
+        let version: usize = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        drop(conn);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,22 +1,34 @@
 use std::{
     borrow::Cow,
-    collections::{hash_map::Entry, BTreeSet},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
     time::Duration,
 };
 
 use actix_web::{
+    error::{ErrorBadRequest, ErrorUnauthorized},
     get,
     web::{Data, Query},
-    HttpResponse, Responder,
+    FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder,
 };
+use chrono::{NaiveDate, Utc};
+use futures_util::future::{self, LocalBoxFuture};
 use itertools::Itertools;
 use tmdb_api::{
-    movie::{details::MovieDetails, search::MovieSearch, watch_providers::MovieWatchProviders},
+    movie::{details::MovieDetails, search::MovieSearch},
     prelude::Command,
+    tvshow::{details::TVShowDetails, search::TVShowSearch},
 };
 
 use crate::{
-    schema::{Movie, MovieStub, Platform},
+    auth::Authenticated,
+    config::{self, config},
+    schema::{
+        AppData, Availability, Cached, Episode, Movie, MovieStub, Provider, Season, Series,
+        SeriesStub,
+    },
+    storage::Change,
+    users::CurrentUser,
     AppState, TMDB,
 };
 
@@ -37,16 +49,13 @@ fn err_to_string(error: tmdb_api::error::Error) -> String {
     }
 }
 
-#[get("/api/tmdb/search")]
-async fn search(Query(SearchQuery { title }): Query<SearchQuery>) -> impl Responder {
-    if title.trim_end().is_empty() {
-        return HttpResponse::Ok().json(Vec::<MovieStub>::new());
-    }
-
-    let search_results = match MovieSearch::new(title).execute(&TMDB).await {
-        Ok(results) => results,
-        Err(err) => return HttpResponse::ServiceUnavailable().body(err_to_string(err)),
-    };
+/// Searches TMDB for movies with the given title, optionally restricted to a release year.
+pub async fn search_movies(title: String, year: Option<u16>) -> Result<Vec<MovieStub>, String> {
+    let search_results = MovieSearch::new(title)
+        .with_year(year)
+        .execute(&TMDB)
+        .await
+        .map_err(err_to_string)?;
     let stubs = search_results.results.into_iter().map(|result| MovieStub {
         tmdb_id: result.inner.id,
         title: result.inner.title,
@@ -54,7 +63,22 @@ async fn search(Query(SearchQuery { title }): Query<SearchQuery>) -> impl Respon
         release_date: result.inner.release_date.unwrap_or_default(),
         poster: result.inner.poster_path,
     });
-    HttpResponse::Ok().json(stubs.collect_vec())
+    Ok(stubs.collect_vec())
+}
+
+#[get("/api/tmdb/search")]
+async fn search(
+    _: CurrentUser,
+    Query(SearchQuery { title }): Query<SearchQuery>,
+) -> impl Responder {
+    if title.trim_end().is_empty() {
+        return HttpResponse::Ok().json(Vec::<MovieStub>::new());
+    }
+
+    match search_movies(title, None).await {
+        Ok(stubs) => HttpResponse::Ok().json(stubs),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err),
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -62,6 +86,46 @@ struct ByIdQuery {
     id: String,
 }
 
+#[derive(serde::Deserialize)]
+struct RegionQuery {
+    region: Option<String>,
+}
+
+/// Region whose streaming offers are shown: the `region` query parameter if given, otherwise the
+/// region of the current user or the one of the server.
+pub struct Region(pub String);
+
+impl FromRequest for Region {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let state = req
+            .app_data::<Data<AppState>>()
+            .expect("app state should be registered")
+            .clone();
+        let auth = req.extensions().get::<Authenticated>().copied();
+        let query = Query::<RegionQuery>::from_query(req.query_string());
+        Box::pin(async move {
+            let Query(RegionQuery { region }) = query?;
+            if let Some(region) = region {
+                return config::parse_region(&region)
+                    .map(Region)
+                    .map_err(|err| ErrorBadRequest(err.to_string()));
+            }
+            let Some(auth) = auth else {
+                return Err(ErrorUnauthorized("not logged in"));
+            };
+            let data_lock = state.data.lock().await;
+            let region = data_lock
+                .users
+                .get(&auth.user)
+                .and_then(|user| user.region.clone());
+            Ok(Region(region.unwrap_or_else(|| config().region.clone())))
+        })
+    }
+}
+
 struct FindByImdbId(u32);
 impl Command for FindByImdbId {
     type Output = FindResult;
@@ -85,91 +149,445 @@ struct FindMovie {
     id: u64,
 }
 
+/// Reasons why looking up a movie by its id can fail.
+#[derive(Debug)]
+pub enum LookupError {
+    MalformedId(String),
+    NotFound(String),
+    Tmdb(String),
+    Storage(anyhow::Error),
+}
+
+impl Display for LookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LookupError::MalformedId(id) => write!(f, "malformed ID '{id}'"),
+            LookupError::NotFound(id) => write!(f, "no movie with IMDb ID {id} found"),
+            LookupError::Tmdb(err) => write!(f, "{err}"),
+            LookupError::Storage(err) => write!(f, "Failed to save new data to disk: {err}"),
+        }
+    }
+}
+
+impl From<LookupError> for HttpResponse {
+    fn from(err: LookupError) -> Self {
+        match err {
+            LookupError::MalformedId(_) => HttpResponse::BadRequest(),
+            LookupError::NotFound(_) => HttpResponse::NotFound(),
+            LookupError::Tmdb(_) => HttpResponse::ServiceUnavailable(),
+            LookupError::Storage(_) => HttpResponse::InternalServerError(),
+        }
+        .body(err.to_string())
+    }
+}
+
+/// Resolves a TMDB id or a `tt`-prefixed IMDb id to a TMDB id.
+async fn resolve_tmdb_id(id: &str) -> Result<u64, LookupError> {
+    match id.strip_prefix("tt") {
+        Some(imdb_id) => {
+            let imdb_id = imdb_id
+                .parse::<u32>()
+                .map_err(|_| LookupError::MalformedId(id.to_owned()))?;
+            let result = FindByImdbId(imdb_id)
+                .execute(&TMDB)
+                .await
+                .map_err(|err| LookupError::Tmdb(err_to_string(err)))?;
+            match result.movie_results.first() {
+                Some(movie) => Ok(movie.id),
+                None => Err(LookupError::NotFound(format!("tt{imdb_id:07}"))),
+            }
+        }
+        None => id
+            .parse::<u64>()
+            .map_err(|_| LookupError::MalformedId(id.to_owned())),
+    }
+}
+
+/// Watch providers of a movie or series. The command of `tmdb_api` misses the free tiers, so this
+/// uses its own types.
+struct WatchProviders {
+    /// `movie` or `tv`
+    kind: &'static str,
+    id: u64,
+}
+
+impl Command for WatchProviders {
+    type Output = TmdbWatchProviders;
+
+    fn path(&self) -> Cow<'static, str> {
+        format!("/{}/{}/watch/providers", self.kind, self.id).into()
+    }
+
+    fn params(&self) -> Vec<(&'static str, Cow<'_, str>)> {
+        vec![]
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TmdbWatchProviders {
+    /// map of region to the offers in that region
+    results: HashMap<String, TmdbOffers>,
+}
+
+#[derive(serde::Deserialize)]
+struct TmdbOffers {
+    #[serde(default)]
+    flatrate: Vec<TmdbProvider>,
+    #[serde(default)]
+    free: Vec<TmdbProvider>,
+    #[serde(default)]
+    ads: Vec<TmdbProvider>,
+    #[serde(default)]
+    rent: Vec<TmdbProvider>,
+    #[serde(default)]
+    buy: Vec<TmdbProvider>,
+}
+
+#[derive(serde::Deserialize)]
+struct TmdbProvider {
+    provider_id: u64,
+    provider_name: String,
+}
+
+impl From<TmdbProvider> for Provider {
+    fn from(provider: TmdbProvider) -> Self {
+        Provider {
+            tmdb_id: provider.provider_id,
+            name: provider.provider_name,
+        }
+    }
+}
+
+impl From<TmdbOffers> for Availability {
+    fn from(offers: TmdbOffers) -> Self {
+        let providers =
+            |providers: Vec<TmdbProvider>| providers.into_iter().map(Provider::from).collect();
+        Availability {
+            flatrate: providers(offers.flatrate),
+            free: providers(offers.free.into_iter().chain(offers.ads).collect()),
+            rent: providers(offers.rent),
+            buy: providers(offers.buy),
+        }
+    }
+}
+
+/// Regions whose offers are stored: the one of the server, the ones of all users and `region`.
+pub fn regions(data: &AppData, region: &str) -> BTreeSet<String> {
+    let user_regions = data.users.values().filter_map(|user| user.region.clone());
+    [config().region.clone(), region.to_owned()]
+        .into_iter()
+        .chain(user_regions)
+        .collect()
+}
+
+/// Fetches where a movie (`kind` is `movie`) or series (`tv`) can be watched in each of the
+/// `regions`.
+async fn fetch_availability(
+    kind: &'static str,
+    id: u64,
+    regions: &BTreeSet<String>,
+) -> Result<BTreeMap<String, Availability>, LookupError> {
+    // powered by JustWatch
+    let mut providers = WatchProviders { kind, id }
+        .execute(&TMDB)
+        .await
+        .map_err(|err| LookupError::Tmdb(err_to_string(err)))?;
+    let availability = regions.iter().map(|region| {
+        let offers = providers.results.remove(region).map(Availability::from);
+        (region.clone(), offers.unwrap_or_default())
+    });
+    Ok(availability.collect())
+}
+
+/// Whether `entry` is older than the cache TTL from the [`config`].
+pub fn is_expired<T>(entry: &Cached<T>) -> bool {
+    Utc::now() - entry.fetched > config().cache_ttl()
+}
+
+/// Fetches all information about a movie from TMDB including its offers in the `regions`,
+/// bypassing the cache.
+pub async fn fetch_movie(id: &str, regions: &BTreeSet<String>) -> Result<Movie, LookupError> {
+    let tmdb_id = resolve_tmdb_id(id).await?;
+    let availability = fetch_availability("movie", tmdb_id, regions).await?;
+
+    let tmdb_movie = MovieDetails::new(tmdb_id)
+        .execute(&TMDB)
+        .await
+        .map_err(|err| LookupError::Tmdb(err_to_string(err)))?;
+    // TMDB sends an empty string for movies without an IMDb id
+    let imdb_id = match tmdb_movie.imdb_id.as_deref() {
+        None | Some("") => None,
+        Some(imdb_id) => Some(imdb_id.trim_start_matches('t').parse().map_err(|_| {
+            LookupError::Tmdb(format!("TMDB returned the invalid IMDb ID '{imdb_id}'"))
+        })?),
+    };
+    Ok(Movie {
+        imdb_id,
+        tmdb_id: tmdb_movie.inner.id,
+        title: tmdb_movie.inner.title,
+        description: tmdb_movie.inner.overview,
+        ratings: vec![],
+        tags: BTreeSet::new(),
+        platforms: BTreeSet::new(),
+        availability,
+        poster: tmdb_movie.inner.poster_path,
+        release_date: tmdb_movie.inner.release_date.unwrap_or_default(),
+        runtime: Duration::from_secs(tmdb_movie.runtime.unwrap_or(0) * 60),
+        score: tmdb_movie.inner.vote_average,
+        revision: 0,
+    })
+}
+
+/// Returns the movie with the given TMDB or `tt`-prefixed IMDb id from the cache, or fetches it
+/// from TMDB and adds it to the cache. Its platforms are the ones in `region`, expired cache
+/// entries and those without offers for that region are fetched again. Expired entries are still
+/// returned if TMDB cannot be reached.
+///
+/// The lock on the app data is not held while fetching.
+pub async fn movie_by_id(state: &AppState, id: &str, region: &str) -> Result<Movie, LookupError> {
+    let (regions, stale) = {
+        let data_lock = state.data.lock().await;
+        let cached = data_lock.tmdb_cache.get(id);
+        if let Some(entry) = cached {
+            if entry.value.availability.contains_key(region) && !is_expired(entry) {
+                let mut movie = entry.value.clone();
+                movie.in_region(region, &data_lock.platforms);
+                return Ok(movie);
+            }
+        }
+        let stale = cached.map(|entry| entry.value.clone());
+        (regions(&data_lock, region), stale)
+    };
+    let fetched = fetch_movie(id, &regions).await;
+
+    let mut data_lock = state.data.lock().await;
+    let mut movie = match (fetched, stale) {
+        (Ok(movie), _) => movie,
+        (Err(LookupError::Tmdb(_)), Some(mut movie)) => {
+            movie.in_region(region, &data_lock.platforms);
+            return Ok(movie);
+        }
+        (Err(err), _) => return Err(err),
+    };
+    // cached and tracked movies have the platforms of the server's region
+    movie.in_region(&config().region, &data_lock.platforms);
+    data_lock
+        .tmdb_cache
+        .insert(id.to_owned(), Cached::new(movie.clone()));
+    state
+        .save(&data_lock, Change::CacheEntry(id.to_owned()))
+        .await
+        .map_err(LookupError::Storage)?;
+    movie.in_region(region, &data_lock.platforms);
+    Ok(movie)
+}
+
 #[get("/api/tmdb/by_id")]
-async fn by_id(data: Data<AppState>, Query(ByIdQuery { id }): Query<ByIdQuery>) -> impl Responder {
-    let mut data_lock = data.0.lock().await;
-    let movie = match data_lock.tmdb_cache.entry(id.clone()) {
-        Entry::Vacant(entry) => {
-            let tmdb_id = match id.strip_prefix("tt") {
-                Some(imdb_id) => match imdb_id.parse::<u32>() {
-                    Ok(id) => match FindByImdbId(id).execute(&TMDB).await {
-                        Ok(result) => match result.movie_results.first() {
-                            Some(movie) => movie.id,
-                            None => {
-                                return HttpResponse::NotFound()
-                                    .body(format!("no movie with IMDb ID tt{id:07} found"))
-                            }
-                        },
-                        Err(err) => {
-                            return HttpResponse::ServiceUnavailable().body(err_to_string(err))
-                        }
-                    },
-                    Err(_) => {
-                        return HttpResponse::BadRequest()
-                            .body(format!("malformed ID 'tt{imdb_id}'"))
-                    }
-                },
-                None => match id.parse::<u64>() {
-                    Ok(id) => id,
-                    Err(_) => {
-                        return HttpResponse::BadRequest().body(format!("malformed ID '{id}'"))
-                    }
-                },
-            };
+async fn by_id(
+    state: Data<AppState>,
+    _: CurrentUser,
+    Region(region): Region,
+    Query(ByIdQuery { id }): Query<ByIdQuery>,
+) -> impl Responder {
+    match movie_by_id(&state, &id, &region).await {
+        Ok(movie) => HttpResponse::Ok().json(movie),
+        Err(err) => err.into(),
+    }
+}
 
-            // powered by JustWatch
-            let platforms = match MovieWatchProviders::new(tmdb_id).execute(&TMDB).await {
-                Ok(providers) => {
-                    let mut platforms = BTreeSet::new();
-                    if let Some(de) = providers.results.get("DE") {
-                        for provider in &de.flatrate {
-                            // Disney Plus: 337
-                            // Netflix: 8
-                            // Amazon Prime Video: 119
-                            if provider.provider_id == 337 {
-                                platforms.insert(Platform::DisneyPlus);
-                            } else if provider.provider_id == 8 {
-                                platforms.insert(Platform::Netflix);
-                            } else if provider.provider_id == 119 {
-                                platforms.insert(Platform::PrimeVideo);
-                            }
-                        }
-                    }
-                    platforms
-                }
-                Err(err) => return HttpResponse::ServiceUnavailable().body(err_to_string(err)),
-            };
+/// Searches TMDB for TV series with the given title.
+pub async fn search_series(title: String) -> Result<Vec<SeriesStub>, String> {
+    let search_results = TVShowSearch::new(title)
+        .execute(&TMDB)
+        .await
+        .map_err(err_to_string)?;
+    let stubs = search_results.results.into_iter().map(|result| SeriesStub {
+        tmdb_id: result.inner.id,
+        title: result.inner.name,
+        description: result.inner.overview.unwrap_or_default(),
+        first_air_date: result.inner.first_air_date,
+        poster: result.inner.poster_path,
+    });
+    Ok(stubs.collect_vec())
+}
 
-            let tmdb_movie = match MovieDetails::new(tmdb_id).execute(&TMDB).await {
-                Ok(movie) => movie,
-                Err(err) => return HttpResponse::ServiceUnavailable().body(err_to_string(err)),
-            };
-            let movie = Movie {
-                // TODO: don't unwrap
-                imdb_id: tmdb_movie.imdb_id.map(|id| {
-                    id.trim_start_matches('t')
-                        .parse()
-                        .unwrap_or_else(|_| panic!("invalid IMDb ID '{id}'"))
-                }),
-                tmdb_id: tmdb_movie.inner.id,
-                title: tmdb_movie.inner.title,
-                description: tmdb_movie.inner.overview,
-                ratings: vec![],
-                tags: BTreeSet::new(),
-                platforms,
-                poster: tmdb_movie.inner.poster_path,
-                release_date: tmdb_movie.inner.release_date.unwrap_or_default(),
-                runtime: Duration::from_secs(tmdb_movie.runtime.unwrap_or(0) * 60),
-                score: tmdb_movie.inner.vote_average,
-            };
-            entry.insert(movie)
+#[get("/api/tmdb/tv/search")]
+async fn search_tv(
+    _: CurrentUser,
+    Query(SearchQuery { title }): Query<SearchQuery>,
+) -> impl Responder {
+    if title.trim_end().is_empty() {
+        return HttpResponse::Ok().json(Vec::<SeriesStub>::new());
+    }
+
+    match search_series(title).await {
+        Ok(stubs) => HttpResponse::Ok().json(stubs),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err),
+    }
+}
+
+/// The season details command of `tmdb_api` requires fields which are missing for episodes that
+/// have not been aired yet, so this uses its own more lenient types.
+struct SeasonDetails {
+    tv_id: u64,
+    season: u32,
+}
+
+impl Command for SeasonDetails {
+    type Output = TmdbSeason;
+
+    fn path(&self) -> Cow<'static, str> {
+        format!("/tv/{}/season/{}", self.tv_id, self.season).into()
+    }
+
+    fn params(&self) -> Vec<(&'static str, Cow<'_, str>)> {
+        vec![]
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TmdbSeason {
+    season_number: u32,
+    name: String,
+    #[serde(default)]
+    overview: String,
+    poster_path: Option<String>,
+    air_date: Option<NaiveDate>,
+    episodes: Vec<TmdbEpisode>,
+}
+
+#[derive(serde::Deserialize)]
+struct TmdbEpisode {
+    episode_number: u32,
+    name: String,
+    #[serde(default)]
+    overview: String,
+    air_date: Option<NaiveDate>,
+    runtime: Option<u64>,
+}
+
+/// Fetches a series with all of its seasons and episodes and its offers in the `regions` from
+/// TMDB, bypassing the cache.
+async fn fetch_series(tmdb_id: u64, regions: &BTreeSet<String>) -> Result<Series, LookupError> {
+    let availability = fetch_availability("tv", tmdb_id, regions).await?;
+
+    let tmdb_series = TVShowDetails::new(tmdb_id)
+        .execute(&TMDB)
+        .await
+        .map_err(|err| LookupError::Tmdb(err_to_string(err)))?;
+    let default_runtime = tmdb_series.episode_run_time.first().copied().unwrap_or(0);
+    let requests: Vec<_> = tmdb_series
+        .seasons
+        .iter()
+        .map(|season| SeasonDetails {
+            tv_id: tmdb_id,
+            season: season.inner.season_number as u32,
+        })
+        .collect();
+    let seasons = future::join_all(requests.iter().map(|request| request.execute(&TMDB))).await;
+    let mut seasons = seasons
+        .into_iter()
+        .map(|season| {
+            let season = season.map_err(|err| LookupError::Tmdb(err_to_string(err)))?;
+            Ok(Season {
+                number: season.season_number,
+                title: season.name,
+                description: season.overview,
+                poster: season.poster_path,
+                air_date: season.air_date,
+                episodes: season
+                    .episodes
+                    .into_iter()
+                    .map(|episode| Episode {
+                        number: episode.episode_number,
+                        title: episode.name,
+                        description: episode.overview,
+                        air_date: episode.air_date,
+                        runtime: Duration::from_secs(
+                            episode.runtime.unwrap_or(default_runtime) * 60,
+                        ),
+                        watched: BTreeMap::new(),
+                        ratings: vec![],
+                    })
+                    .sorted_by_key(|episode| episode.number)
+                    .collect(),
+            })
+        })
+        .collect::<Result<Vec<_>, LookupError>>()?;
+    seasons.sort_by_key(|season| season.number);
+
+    Ok(Series {
+        tmdb_id: tmdb_series.inner.id,
+        title: tmdb_series.inner.name,
+        description: tmdb_series.inner.overview.unwrap_or_default(),
+        tags: BTreeSet::new(),
+        platforms: BTreeSet::new(),
+        availability,
+        poster: tmdb_series.inner.poster_path,
+        first_air_date: tmdb_series.inner.first_air_date,
+        score: tmdb_series.inner.vote_average,
+        seasons,
+    })
+}
+
+/// Returns the series with the given TMDB id from the cache, or fetches it from TMDB and adds it
+/// to the cache. Its platforms are the ones in `region`, expired cache entries and those without
+/// offers for that region are fetched again. Expired entries are still returned if TMDB cannot be
+/// reached.
+///
+/// The lock on the app data is not held while fetching.
+pub async fn series_by_id(state: &AppState, id: u64, region: &str) -> Result<Series, LookupError> {
+    let (regions, stale) = {
+        let data_lock = state.data.lock().await;
+        let cached = data_lock.tmdb_tv_cache.get(&id);
+        if let Some(entry) = cached {
+            if entry.value.availability.contains_key(region) && !is_expired(entry) {
+                let mut series = entry.value.clone();
+                series.in_region(region, &data_lock.platforms);
+                return Ok(series);
+            }
+        }
+        let stale = cached.map(|entry| entry.value.clone());
+        (regions(&data_lock, region), stale)
+    };
+    let fetched = fetch_series(id, &regions).await;
+
+    let mut data_lock = state.data.lock().await;
+    let mut series = match (fetched, stale) {
+        (Ok(series), _) => series,
+        (Err(LookupError::Tmdb(_)), Some(mut series)) => {
+            series.in_region(region, &data_lock.platforms);
+            return Ok(series);
         }
-        Entry::Occupied(entry) => entry.into_mut(),
+        (Err(err), _) => return Err(err),
     };
-    let movie = movie.clone();
-    match crate::save_to_disk(&data_lock).await {
-        Ok(()) => HttpResponse::Ok().json(movie),
-        Err(err) => HttpResponse::InternalServerError()
-            .body(format!("Failed to save new data to disk: {err}")),
+    // cached and tracked series have the platforms of the server's region
+    series.in_region(&config().region, &data_lock.platforms);
+    data_lock
+        .tmdb_tv_cache
+        .insert(id, Cached::new(series.clone()));
+    state
+        .save(&data_lock, Change::TvCacheEntry(id))
+        .await
+        .map_err(LookupError::Storage)?;
+    series.in_region(region, &data_lock.platforms);
+    Ok(series)
+}
+
+#[derive(serde::Deserialize)]
+struct TvByIdQuery {
+    id: u64,
+}
+
+#[get("/api/tmdb/tv/by_id")]
+async fn tv_by_id(
+    _: CurrentUser,
+    state: Data<AppState>,
+    Region(region): Region,
+    Query(TvByIdQuery { id }): Query<TvByIdQuery>,
+) -> impl Responder {
+    match series_by_id(&state, id, &region).await {
+        Ok(series) => HttpResponse::Ok().json(series),
+        Err(err) => err.into(),
     }
 }
@@ -0,0 +1,221 @@
+//! User accounts and resolving the user a request is made for.
+//!
+//! The user is the one the request was authenticated for, see [`crate::auth`].
+
+use std::collections::btree_map::Entry;
+
+use actix_web::{
+    delete,
+    error::{ErrorForbidden, ErrorUnauthorized},
+    get, patch, post, put,
+    web::{Data, Json, Path},
+    FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+
+use crate::{
+    auth::{self, Authenticated},
+    config,
+    schema::User,
+    storage::Change,
+    AppState,
+};
+
+/// The user on whose behalf a request is made.
+#[derive(Debug, Clone, Copy)]
+pub struct CurrentUser {
+    pub id: u32,
+    pub admin: bool,
+}
+
+impl FromRequest for CurrentUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let state = req
+            .app_data::<Data<AppState>>()
+            .expect("app state should be registered")
+            .clone();
+        let auth = req.extensions().get::<Authenticated>().copied();
+        Box::pin(async move {
+            let Some(auth) = auth else {
+                return Err(ErrorUnauthorized("not logged in"));
+            };
+            let data_lock = state.data.lock().await;
+            let user = data_lock
+                .users
+                .get(&auth.user)
+                .ok_or_else(|| ErrorUnauthorized("user does not exist anymore"))?;
+            Ok(CurrentUser {
+                id: user.id,
+                admin: user.admin,
+            })
+        })
+    }
+}
+
+/// A [`CurrentUser`] who is an admin. Requests of other users are rejected.
+#[derive(Debug, Clone, Copy)]
+pub struct Admin(pub CurrentUser);
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let user = CurrentUser::from_request(req, payload);
+        Box::pin(async move {
+            let user = user.await?;
+            match user.admin {
+                true => Ok(Admin(user)),
+                false => Err(ErrorForbidden("only admins are allowed to do this")),
+            }
+        })
+    }
+}
+
+#[get("/api/user")]
+async fn get_current_user(state: Data<AppState>, user: CurrentUser) -> impl Responder {
+    match state.data.lock().await.users.get(&user.id) {
+        Some(user) => HttpResponse::Ok().json(user),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Checks the region of `user` and brings it into the canonical upper case form.
+fn normalize_region(user: &mut User) -> Result<(), HttpResponse> {
+    if let Some(region) = &user.region {
+        let region = config::parse_region(region)
+            .map_err(|err| HttpResponse::BadRequest().body(err.to_string()))?;
+        user.region = Some(region);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct RegionChange {
+    /// `None` uses the region of the server
+    region: Option<String>,
+}
+
+/// Sets the region whose streaming offers are shown to the current user. Movies and series are
+/// only fetched again for the new region once they are requested from TMDB.
+#[put("/api/user/region")]
+async fn put_region(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(RegionChange { region }): Json<RegionChange>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let Some(current) = data_lock.users.get_mut(&user.id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let mut changed = User {
+        region,
+        ..current.clone()
+    };
+    if let Err(resp) = normalize_region(&mut changed) {
+        return resp;
+    }
+    *current = changed;
+    match state.save(&data_lock, Change::User(user.id)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[get("/api/users")]
+async fn get_users(state: Data<AppState>, _: Admin) -> impl Responder {
+    HttpResponse::Ok().json(&state.data.lock().await.users)
+}
+
+#[post("/api/users")]
+async fn post_user(state: Data<AppState>, _: Admin, Json(mut user): Json<User>) -> impl Responder {
+    if let Err(resp) = normalize_region(&mut user) {
+        return resp;
+    }
+    let mut data_lock = state.data.lock().await;
+    if data_lock
+        .users
+        .values()
+        .any(|other| other.name == user.name)
+    {
+        return HttpResponse::Conflict().body("a user with that name already exists");
+    }
+    let id = loop {
+        let new_id = rand::random::<u32>();
+        if !data_lock.users.contains_key(&new_id) {
+            break new_id;
+        }
+    };
+    let new_user = User { id, ..user };
+    let resp = HttpResponse::Ok().json(&new_user);
+    data_lock.users.insert(id, new_user);
+    match state.save(&data_lock, Change::User(id)).await {
+        Ok(()) => resp,
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[patch("/api/users")]
+async fn patch_user(state: Data<AppState>, _: Admin, Json(mut user): Json<User>) -> impl Responder {
+    if let Err(resp) = normalize_region(&mut user) {
+        return resp;
+    }
+    let mut data_lock = state.data.lock().await;
+    let id = user.id;
+    if data_lock
+        .users
+        .values()
+        .any(|other| other.id != id && other.name == user.name)
+    {
+        return HttpResponse::Conflict().body("a user with that name already exists");
+    }
+    let other_admins = data_lock
+        .users
+        .values()
+        .any(|other| other.id != id && other.admin);
+    match data_lock.users.entry(id) {
+        Entry::Vacant(_) => {
+            return HttpResponse::NotFound().body(format!("user with ID {id} does not exist"))
+        }
+        Entry::Occupied(mut entry) => {
+            if !user.admin && !other_admins {
+                return HttpResponse::Conflict().body("there has to be at least one admin");
+            }
+            *entry.get_mut() = user;
+        }
+    }
+    match state.save(&data_lock, Change::User(id)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+/// Deletes a user together with all of their ratings, readings, tags, watchlist, goals and API
+/// tokens.
+#[delete("/api/users/{id}")]
+async fn delete_user(state: Data<AppState>, Admin(admin): Admin, id: Path<u32>) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    if !data_lock.users.contains_key(&id) {
+        return HttpResponse::NotFound().body(format!("user with ID {id} does not exist"));
+    }
+    if admin.id == *id {
+        return HttpResponse::Conflict().body("admins cannot delete themselves");
+    }
+    if !data_lock
+        .users
+        .values()
+        .any(|other| other.id != *id && other.admin)
+    {
+        return HttpResponse::Conflict().body("there has to be at least one admin");
+    }
+    data_lock.remove_user(*id);
+    auth::end_sessions(*id).await;
+    match state.save(&data_lock, Change::All).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
@@ -0,0 +1,387 @@
+//! Movies a user wants to watch later, see [`WatchlistEntry`].
+
+use std::{
+    cmp::Reverse,
+    collections::{btree_map::Entry, BTreeSet},
+};
+
+use actix_web::{
+    delete, get, patch, post,
+    web::{Data, Json, Path, Query},
+    HttpResponse, Responder,
+};
+use chrono::{Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use serde_with::{formats::CommaSeparator, serde_as, StringWithSeparator};
+
+use crate::{
+    config::config,
+    events::{self, EntityChange},
+    listing::Pagination,
+    schema::{AppData, Movie, Priority, WatchlistEntry},
+    storage::Change,
+    tmdb::{self, Region},
+    users::CurrentUser,
+    AppState,
+};
+
+#[serde_as]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct WatchlistQuery {
+    /// Comma-separated platform ids, only movies streaming on at least one of them are listed
+    #[serde_as(as = "StringWithSeparator::<CommaSeparator, u32>")]
+    available_on: BTreeSet<u32>,
+    min_priority: Option<Priority>,
+    /// Case-insensitive name of the person who suggested the movie
+    suggested_by: Option<String>,
+}
+
+#[derive(Serialize)]
+struct WatchlistItem<'a> {
+    #[serde(flatten)]
+    entry: &'a WatchlistEntry,
+    movie: &'a Movie,
+    /// The platforms from the query on which the movie is available in the region
+    available_on: BTreeSet<u32>,
+}
+
+/// The entries of the watchlist in the [view](AppData::view) `data` which match `query`.
+fn filter<'a>(data: &'a AppData, query: &WatchlistQuery) -> Vec<WatchlistItem<'a>> {
+    data.watchlists
+        .values()
+        .flat_map(|watchlist| watchlist.values())
+        .filter_map(|entry| {
+            let movie = data.movies.get(&entry.tmdb_id)?;
+            Some(WatchlistItem {
+                entry,
+                movie,
+                available_on: movie
+                    .platforms
+                    .intersection(&query.available_on)
+                    .copied()
+                    .collect(),
+            })
+        })
+        .filter(|item| query.available_on.is_empty() || !item.available_on.is_empty())
+        .filter(|item| {
+            query
+                .min_priority
+                .is_none_or(|priority| item.entry.priority >= priority)
+        })
+        .filter(|item| {
+            query.suggested_by.as_ref().is_none_or(|name| {
+                item.entry
+                    .suggested_by
+                    .as_ref()
+                    .is_some_and(|suggested_by| suggested_by.eq_ignore_ascii_case(name))
+            })
+        })
+        .collect()
+}
+
+/// Sorts by priority, oldest entries first.
+fn sort_key(item: &WatchlistItem) -> (Reverse<Priority>, NaiveDate, u64) {
+    (
+        Reverse(item.entry.priority),
+        item.entry.added,
+        item.entry.tmdb_id,
+    )
+}
+
+/// Lists the watchlist sorted by priority, oldest entries first.
+#[get("/api/watchlist")]
+async fn get_watchlist(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Region(region): Region,
+    Query(query): Query<WatchlistQuery>,
+    Query(pagination): Query<Pagination>,
+) -> impl Responder {
+    let mut data = state.data.lock().await.view(user.id);
+    for movie in data.movies.values_mut() {
+        movie.in_region(&region, &data.platforms);
+    }
+    let items = filter(&data, &query);
+    match pagination.paginate(items, (), sort_key, Ord::cmp) {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(err) => HttpResponse::BadRequest().body(err),
+    }
+}
+
+#[derive(Deserialize)]
+struct NewEntry {
+    tmdb_id: u64,
+    #[serde(default)]
+    priority: Priority,
+    /// Defaults to today
+    added: Option<NaiveDate>,
+    note: Option<String>,
+    suggested_by: Option<String>,
+}
+
+/// Adds a movie to the watchlist. Movies which are not tracked yet are fetched from TMDB first.
+#[post("/api/watchlist")]
+async fn post_watchlist(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(new): Json<NewEntry>,
+) -> impl Responder {
+    let id = new.tmdb_id;
+    let mut data_lock = state.data.lock().await;
+    let watchlist = data_lock.watchlists.get(&user.id);
+    if watchlist.is_some_and(|watchlist| watchlist.contains_key(&id)) {
+        return HttpResponse::Conflict().body("that movie is already on the watchlist");
+    }
+    let mut movie_change = None;
+    if !data_lock.movies.contains_key(&id) {
+        drop(data_lock);
+        let movie = match tmdb::movie_by_id(&state, &id.to_string(), &config().region).await {
+            Ok(movie) => movie,
+            Err(err) => return err.into(),
+        };
+        data_lock = state.data.lock().await;
+        if let Entry::Vacant(entry) = data_lock.movies.entry(id) {
+            entry.insert(movie.clone());
+            if state.save(&data_lock, Change::Movie(id)).await.is_err() {
+                return HttpResponse::InternalServerError().body("Failed to save new data to disk");
+            }
+            movie_change = Some(EntityChange::Movie {
+                tmdb_id: id,
+                before: None,
+                after: Some(movie),
+            });
+        }
+    }
+
+    let entry = WatchlistEntry {
+        tmdb_id: id,
+        priority: new.priority,
+        added: new.added.unwrap_or_else(|| Local::now().date_naive()),
+        note: new.note,
+        suggested_by: new.suggested_by,
+    };
+    let resp = HttpResponse::Ok().json(&entry);
+    let change = EntityChange::WatchlistEntry {
+        tmdb_id: id,
+        before: None,
+        after: Some(entry.clone()),
+    };
+    // the movie might have been added concurrently while the lock was released above
+    let added = match data_lock.watchlists.entry(user.id).or_default().entry(id) {
+        Entry::Occupied(_) => false,
+        Entry::Vacant(vacant) => {
+            vacant.insert(entry);
+            true
+        }
+    };
+    let saved = match added {
+        true => state
+            .save(&data_lock, Change::WatchlistEntry(user.id, id))
+            .await
+            .is_ok(),
+        false => false,
+    };
+    drop(data_lock);
+    // the movie was saved in any case
+    if let Some(movie_change) = movie_change {
+        events::publish(&state, user.id, movie_change).await;
+    }
+    if !added {
+        return HttpResponse::Conflict().body("that movie is already on the watchlist");
+    }
+    if !saved {
+        return HttpResponse::InternalServerError().body("Failed to save new data to disk");
+    }
+    events::publish(&state, user.id, change).await;
+    resp
+}
+
+#[patch("/api/watchlist")]
+async fn patch_watchlist(
+    state: Data<AppState>,
+    user: CurrentUser,
+    Json(entry): Json<WatchlistEntry>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = entry.tmdb_id;
+    let existing = data_lock
+        .watchlists
+        .get_mut(&user.id)
+        .and_then(|watchlist| watchlist.get_mut(&id));
+    let before = match existing {
+        Some(existing) => std::mem::replace(existing, entry.clone()),
+        None => {
+            return HttpResponse::NotFound()
+                .body(format!("movie with ID {id} is not on the watchlist"))
+        }
+    };
+    let change = EntityChange::WatchlistEntry {
+        tmdb_id: id,
+        before: Some(before),
+        after: Some(entry),
+    };
+    match state
+        .save(&data_lock, Change::WatchlistEntry(user.id, id))
+        .await
+    {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[delete("/api/watchlist/{id}")]
+async fn delete_watchlist(
+    state: Data<AppState>,
+    user: CurrentUser,
+    id: Path<u64>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let removed = data_lock
+        .watchlists
+        .get_mut(&user.id)
+        .and_then(|watchlist| watchlist.remove(&id));
+    if removed.is_none() {
+        return HttpResponse::NotFound()
+            .body(format!("movie with ID {id} is not on the watchlist"));
+    }
+    let change = EntityChange::WatchlistEntry {
+        tmdb_id: *id,
+        before: removed,
+        after: None,
+    };
+    match state
+        .save(&data_lock, Change::WatchlistEntry(user.id, *id))
+        .await
+    {
+        Ok(()) => {
+            drop(data_lock);
+            events::publish(&state, user.id, change).await;
+            HttpResponse::Ok().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::*;
+
+    fn movie(tmdb_id: u64, platforms: &[u32]) -> Movie {
+        Movie {
+            imdb_id: None,
+            tmdb_id,
+            title: String::new(),
+            description: String::new(),
+            ratings: vec![],
+            tags: BTreeSet::new(),
+            platforms: platforms.iter().copied().collect(),
+            availability: Default::default(),
+            poster: None,
+            release_date: NaiveDate::from_ymd_opt(1999, 3, 31).unwrap(),
+            runtime: Duration::from_secs(120 * 60),
+            score: 7.,
+            revision: 0,
+        }
+    }
+
+    fn data() -> AppData {
+        let entry = |tmdb_id, priority, day, suggested_by: Option<&str>| {
+            let entry = WatchlistEntry {
+                tmdb_id,
+                priority,
+                added: NaiveDate::from_ymd_opt(2024, 1, day).unwrap(),
+                note: None,
+                suggested_by: suggested_by.map(str::to_owned),
+            };
+            (tmdb_id, entry)
+        };
+        let movies = [
+            movie(1, &[1]),
+            movie(2, &[2]),
+            movie(3, &[]),
+            movie(4, &[1, 2]),
+        ];
+        let watchlist = BTreeMap::from([
+            entry(1, Priority::Normal, 3, Some("Alice")),
+            entry(2, Priority::High, 5, None),
+            entry(3, Priority::Low, 1, Some("Bob")),
+            entry(4, Priority::Normal, 2, Some("alice")),
+        ]);
+        AppData {
+            movies: movies
+                .into_iter()
+                .map(|movie| (movie.tmdb_id, movie))
+                .collect(),
+            watchlists: [(1, watchlist)].into(),
+            ..Default::default()
+        }
+    }
+
+    fn ids(data: &AppData, query: WatchlistQuery) -> Vec<u64> {
+        let mut items = filter(data, &query);
+        items.sort_by_key(sort_key);
+        items.iter().map(|item| item.entry.tmdb_id).collect()
+    }
+
+    #[test]
+    fn order() {
+        // highest priority first, then oldest first
+        assert_eq!(ids(&data(), WatchlistQuery::default()), [2, 4, 1, 3]);
+    }
+
+    #[test]
+    fn filters() {
+        let data = data();
+        let query = WatchlistQuery {
+            available_on: [1].into(),
+            ..Default::default()
+        };
+        assert_eq!(ids(&data, query), [4, 1]);
+        let items = filter(
+            &data,
+            &WatchlistQuery {
+                available_on: [2, 3].into(),
+                ..Default::default()
+            },
+        );
+        let available_on: BTreeMap<_, _> = items
+            .iter()
+            .map(|item| (item.entry.tmdb_id, item.available_on.clone()))
+            .collect();
+        assert_eq!(
+            available_on,
+            BTreeMap::from([(2, [2].into()), (4, [2].into())])
+        );
+
+        let query = WatchlistQuery {
+            min_priority: Some(Priority::Normal),
+            ..Default::default()
+        };
+        assert_eq!(ids(&data, query), [2, 4, 1]);
+        let query = WatchlistQuery {
+            min_priority: Some(Priority::High),
+            ..Default::default()
+        };
+        assert_eq!(ids(&data, query), [2]);
+
+        let query = WatchlistQuery {
+            suggested_by: Some("ALICE".into()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&data, query), [4, 1]);
+
+        let query = WatchlistQuery {
+            available_on: [2].into(),
+            min_priority: Some(Priority::Normal),
+            suggested_by: Some("alice".into()),
+        };
+        assert_eq!(ids(&data, query), [4]);
+    }
+}
@@ -0,0 +1,484 @@
+//! Outgoing webhooks which are called whenever movies, tags, ratings, books, readings, series or
+//! watchlist entries are changed through the API.
+//!
+//! Every change is sent as an [`Event`] with the entity before and after the change in a POST
+//! request. The body is signed with the secret of the webhook, the `X-Entrackment-Signature`
+//! header contains `sha256=` followed by the hex-encoded HMAC-SHA256 of the body. Failed
+//! deliveries are retried with increasing delays. The most recent deliveries are stored in the
+//! data directory, so that pending retries are resumed after a restart, and can be listed from
+//! `/api/webhooks/deliveries`.
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    io::ErrorKind,
+    time::Duration,
+};
+
+use actix_web::{
+    delete, get, patch, post,
+    rt::{self, time::sleep},
+    web::{Data, Json, Path, Query},
+    HttpResponse, Responder,
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{fs, sync::Mutex};
+
+use crate::{
+    auth::new_secret,
+    config::config,
+    events::EntityChange,
+    schema::{AppData, Webhook},
+    storage::{write_atomically, Change},
+    users::Admin,
+    AppState, CLIENT,
+};
+
+/// Delays before retrying a failed delivery, a delivery fails for good after the last one
+const RETRY_DELAYS: [Duration; 4] = [
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+    Duration::from_secs(10 * 60),
+    Duration::from_secs(60 * 60),
+];
+/// Time after which a webhook request is aborted
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of deliveries which are kept in the log
+const KEEP_DELIVERIES: usize = 1000;
+
+/// Deliveries from newest to oldest
+static DELIVERIES: Lazy<Mutex<VecDeque<Delivery>>> = Lazy::new(Default::default);
+
+/// Body of webhook requests
+#[derive(Debug, Serialize)]
+pub struct Event<'a> {
+    /// Random id, which is the same for all webhooks and all attempts to deliver the event
+    id: u32,
+    /// Name of the event like `movie.created`
+    event: &'a str,
+    time: DateTime<Utc>,
+    /// Id of the user who made the change
+    user: u32,
+    /// The change as seen by the user who made it
+    #[serde(flatten)]
+    change: EntityChange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DeliveryState {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Attempt {
+    time: DateTime<Utc>,
+    /// HTTP status of the response, `null` if no response was received
+    status: Option<u16>,
+    /// Why the attempt failed, `null` if it succeeded
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+    id: u32,
+    webhook: u32,
+    /// Id of the delivered event
+    event_id: u32,
+    event: String,
+    created: DateTime<Utc>,
+    state: DeliveryState,
+    attempts: Vec<Attempt>,
+    /// When the next attempt is made, `null` if there is none
+    next_attempt: Option<DateTime<Utc>>,
+    /// Request body, which is only kept until the delivery succeeded or failed for good
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+}
+
+impl Delivery {
+    /// Records `attempt` and schedules the next one if it failed and retries are left.
+    fn record(&mut self, attempt: Attempt) {
+        let succeeded = attempt.error.is_none();
+        let time = attempt.time;
+        self.attempts.push(attempt);
+        let delay = RETRY_DELAYS
+            .get(self.attempts.len() - 1)
+            .filter(|_| !succeeded);
+        self.next_attempt = delay.map(|&delay| time + delay);
+        self.state = match (succeeded, delay) {
+            (true, _) => DeliveryState::Succeeded,
+            (false, Some(_)) => DeliveryState::Pending,
+            (false, None) => DeliveryState::Failed,
+        };
+        if self.state != DeliveryState::Pending {
+            self.body = None;
+        }
+    }
+}
+
+/// Whether `webhook` is interested in events named `name`.
+fn subscribed(webhook: &Webhook, name: &str) -> bool {
+    webhook.events.is_empty() || webhook.events.contains(name)
+}
+
+/// `sha256=` followed by the hex-encoded HMAC-SHA256 of `body` with `secret` as key.
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Writes `deliveries` to the data directory.
+async fn store(deliveries: &VecDeque<Delivery>) {
+    let result = match serde_json::to_vec(deliveries) {
+        Ok(json) => write_atomically(&config().deliveries_file(), json).await,
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = result {
+        eprintln!("failed to store webhook deliveries: {err:#}");
+    }
+}
+
+/// Records `attempt` in the delivery with the given id and returns when the next attempt is made.
+async fn record(id: u32, attempt: Attempt) -> Option<DateTime<Utc>> {
+    let mut deliveries = DELIVERIES.lock().await;
+    let delivery = deliveries.iter_mut().find(|d| d.id == id)?;
+    delivery.record(attempt);
+    let next_attempt = delivery.next_attempt;
+    store(&deliveries).await;
+    next_attempt
+}
+
+/// Sends `body` to `webhook` from `next_attempt` on until it is accepted or all retries failed,
+/// recording every attempt in the delivery with the given id.
+async fn deliver(
+    id: u32,
+    webhook: Webhook,
+    event: String,
+    body: String,
+    mut next_attempt: DateTime<Utc>,
+) {
+    let signature = signature(&webhook.secret, body.as_bytes());
+    loop {
+        if let Ok(delay) = (next_attempt - Utc::now()).to_std() {
+            sleep(delay).await;
+        }
+        let resp = CLIENT
+            .post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .header("X-Entrackment-Event", &event)
+            .header("X-Entrackment-Delivery", id.to_string())
+            .header("X-Entrackment-Signature", &signature)
+            .timeout(REQUEST_TIMEOUT)
+            .body(body.clone())
+            .send()
+            .await;
+        let (status, error) = match resp {
+            Ok(resp) if resp.status().is_success() => (Some(resp.status().as_u16()), None),
+            Ok(resp) => (
+                Some(resp.status().as_u16()),
+                Some(format!("unexpected status {}", resp.status())),
+            ),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        let attempt = Attempt {
+            time: Utc::now(),
+            status,
+            error,
+        };
+        match record(id, attempt).await {
+            Some(time) => next_attempt = time,
+            None => return,
+        }
+    }
+}
+
+/// Loads the stored deliveries and resumes the pending ones, which were interrupted by a restart.
+/// Deliveries of webhooks that do not exist anymore fail.
+pub async fn resume(data: &AppData) -> Result<()> {
+    let json = match fs::read(config().deliveries_file()).await {
+        Ok(json) => json,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut deliveries: VecDeque<Delivery> = serde_json::from_slice(&json)?;
+    let mut pending = vec![];
+    for delivery in &mut deliveries {
+        if delivery.state != DeliveryState::Pending {
+            continue;
+        }
+        match (
+            data.webhooks.get(&delivery.webhook),
+            delivery.body.clone(),
+            delivery.next_attempt,
+        ) {
+            (Some(webhook), Some(body), Some(next_attempt)) => pending.push((
+                delivery.id,
+                webhook.clone(),
+                delivery.event.clone(),
+                body,
+                next_attempt,
+            )),
+            _ => {
+                delivery.state = DeliveryState::Failed;
+                delivery.next_attempt = None;
+                delivery.body = None;
+            }
+        }
+    }
+    *DELIVERIES.lock().await = deliveries;
+    for (id, webhook, event, body, next_attempt) in pending {
+        rt::spawn(deliver(id, webhook, event, body, next_attempt));
+    }
+    Ok(())
+}
+
+/// The webhooks interested in events named `name`.
+pub fn subscribed_to(data: &AppData, name: &str) -> Vec<Webhook> {
+    data.webhooks
+        .values()
+        .filter(|webhook| subscribed(webhook, name))
+        .cloned()
+        .collect()
+}
+
+/// Sends `change` made by `user` to `webhooks`, see [`subscribed_to`]. `change` has to be the
+/// [view](EntityChange::view) of `user`. The deliveries run in the background.
+pub async fn emit(webhooks: Vec<Webhook>, user: u32, change: EntityChange) {
+    let name = change.name();
+    let event = Event {
+        id: rand::random(),
+        event: &name,
+        time: Utc::now(),
+        user,
+        change,
+    };
+    let body = match serde_json::to_string(&event) {
+        Ok(body) => body,
+        Err(err) => {
+            eprintln!("failed to serialize webhook event {name}: {err}");
+            return;
+        }
+    };
+
+    let mut deliveries = DELIVERIES.lock().await;
+    for webhook in webhooks {
+        let id = loop {
+            let new_id = rand::random::<u32>();
+            if deliveries.iter().all(|delivery| delivery.id != new_id) {
+                break new_id;
+            }
+        };
+        deliveries.truncate(KEEP_DELIVERIES - 1);
+        deliveries.push_front(Delivery {
+            id,
+            webhook: webhook.id,
+            event_id: event.id,
+            event: name.clone(),
+            created: event.time,
+            state: DeliveryState::Pending,
+            attempts: vec![],
+            next_attempt: Some(event.time),
+            body: Some(body.clone()),
+        });
+        rt::spawn(deliver(id, webhook, name.clone(), body.clone(), event.time));
+    }
+    store(&deliveries).await;
+}
+
+/// Checks that the URL of `webhook` can be called.
+fn validate(webhook: &Webhook) -> Result<(), HttpResponse> {
+    match Url::parse(&webhook.url) {
+        Ok(url) if ["http", "https"].contains(&url.scheme()) => Ok(()),
+        _ => Err(HttpResponse::BadRequest().body(format!("invalid URL '{}'", webhook.url))),
+    }
+}
+
+#[get("/api/webhooks")]
+async fn get_webhooks(state: Data<AppState>, _: Admin) -> impl Responder {
+    let data_lock = state.data.lock().await;
+    let mut webhooks: Vec<_> = data_lock.webhooks.values().collect();
+    webhooks.sort_by_key(|webhook| (&webhook.url, webhook.id));
+    HttpResponse::Ok().json(webhooks)
+}
+
+#[derive(Deserialize)]
+struct NewWebhook {
+    url: String,
+    /// Generated if missing
+    secret: Option<String>,
+    #[serde(default)]
+    events: BTreeSet<String>,
+}
+
+#[post("/api/webhooks")]
+async fn post_webhook(
+    state: Data<AppState>,
+    _: Admin,
+    Json(new): Json<NewWebhook>,
+) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    let id = loop {
+        let new_id = rand::random::<u32>();
+        if !data_lock.webhooks.contains_key(&new_id) {
+            break new_id;
+        }
+    };
+    let webhook = Webhook {
+        id,
+        url: new.url,
+        secret: new.secret.unwrap_or_else(new_secret),
+        events: new.events,
+    };
+    if let Err(resp) = validate(&webhook) {
+        return resp;
+    }
+    let resp = HttpResponse::Ok().json(&webhook);
+    data_lock.webhooks.insert(id, webhook);
+    match state.save(&data_lock, Change::Webhook(id)).await {
+        Ok(()) => resp,
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[patch("/api/webhooks")]
+async fn patch_webhook(
+    state: Data<AppState>,
+    _: Admin,
+    Json(webhook): Json<Webhook>,
+) -> impl Responder {
+    if let Err(resp) = validate(&webhook) {
+        return resp;
+    }
+    let mut data_lock = state.data.lock().await;
+    let id = webhook.id;
+    match data_lock.webhooks.get_mut(&id) {
+        Some(existing) => *existing = webhook,
+        None => {
+            return HttpResponse::NotFound().body(format!("webhook with ID {id} does not exist"))
+        }
+    }
+    match state.save(&data_lock, Change::Webhook(id)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[delete("/api/webhooks/{id}")]
+async fn delete_webhook(state: Data<AppState>, _: Admin, id: Path<u32>) -> impl Responder {
+    let mut data_lock = state.data.lock().await;
+    if data_lock.webhooks.remove(&id).is_none() {
+        return HttpResponse::NotFound().body(format!("webhook with ID {id} does not exist"));
+    }
+    match state.save(&data_lock, Change::Webhook(*id)).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().body("Failed to save new data to disk"),
+    }
+}
+
+#[derive(Deserialize)]
+struct DeliveriesQuery {
+    /// Only list the deliveries of the webhook with this id
+    webhook: Option<u32>,
+}
+
+/// Lists the most recent deliveries from newest to oldest.
+#[get("/api/webhooks/deliveries")]
+async fn get_deliveries(
+    _: Admin,
+    Query(DeliveriesQuery { webhook }): Query<DeliveriesQuery>,
+) -> impl Responder {
+    let deliveries = DELIVERIES.lock().await;
+    let deliveries: Vec<_> = deliveries
+        .iter()
+        .filter(|delivery| webhook.is_none_or(|id| delivery.webhook == id))
+        .collect();
+    HttpResponse::Ok().json(deliveries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hmac_signature() {
+        // test case 2 of RFC 4231
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn event_filter() {
+        let mut webhook = Webhook {
+            id: 1,
+            url: "https://example.com/hook".into(),
+            secret: "secret".into(),
+            events: BTreeSet::new(),
+        };
+        // no events means all of them
+        assert!(subscribed(&webhook, "movie.created"));
+        webhook.events = ["movie.created".into(), "tag.deleted".into()].into();
+        assert!(subscribed(&webhook, "movie.created"));
+        assert!(subscribed(&webhook, "tag.deleted"));
+        assert!(!subscribed(&webhook, "movie.updated"));
+        assert!(!subscribed(&webhook, "tag.created"));
+    }
+
+    #[test]
+    fn retries() {
+        let created = Utc::now();
+        let mut delivery = Delivery {
+            id: 1,
+            webhook: 1,
+            event_id: 1,
+            event: "movie.created".into(),
+            created,
+            state: DeliveryState::Pending,
+            attempts: vec![],
+            next_attempt: Some(created),
+            body: Some("{}".into()),
+        };
+        let failed = |time| Attempt {
+            time,
+            status: Some(500),
+            error: Some("unexpected status 500".into()),
+        };
+
+        let mut time = created;
+        for delay in RETRY_DELAYS {
+            delivery.record(failed(time));
+            assert_eq!(delivery.state, DeliveryState::Pending);
+            assert_eq!(delivery.next_attempt, Some(time + delay));
+            assert!(delivery.body.is_some());
+            time += delay;
+        }
+        delivery.record(failed(time));
+        assert_eq!(delivery.state, DeliveryState::Failed);
+        assert_eq!(delivery.next_attempt, None);
+        assert_eq!(delivery.body, None);
+        assert_eq!(delivery.attempts.len(), RETRY_DELAYS.len() + 1);
+
+        delivery.attempts.clear();
+        delivery.body = Some("{}".into());
+        delivery.record(failed(created));
+        delivery.record(Attempt {
+            time: created,
+            status: Some(200),
+            error: None,
+        });
+        assert_eq!(delivery.state, DeliveryState::Succeeded);
+        assert_eq!(delivery.next_attempt, None);
+        assert_eq!(delivery.body, None);
+    }
+}
@@ -9,32 +9,21 @@ export const colorScheme = writable(SchemeKind.System)
 export const darkTheme = writable(false)
 
 export const tags: Writable<{ [index: number]: Tag }> = writable({})
+export const platforms: Writable<{ [index: number]: Platform }> = writable({})
 export const allMovies: Writable<Movie[]> = writable([])
 export const filteredMovies: Writable<Movie[]> = writable([])
 export const allBooks: Writable<Book[]> = writable([])
 export const filteredBooks: Writable<Book[]> = writable([])
 export const fetching = writable(false)
-
-export const PLATFORMS = [
-    'Disney+',
-    'Jellyfin',
-    'Netflix',
-    'Prime Video',
-    'YouTube',
-    'DVD',
-    'BluRay',
-    'Cinema',
-    'TV',
-    'Airplane',
-    'Apple TV',
-    'Stan',
-]
+/** Cleared when the server answers with `401 Unauthorized`, which shows the login page */
+export const loggedIn = writable(true)
 
 export async function fetchApi<T>(
     request: Promise<Response>,
     hasBody: boolean = true,
 ): Promise<string | T> {
     const response = await request
+    if (response.status === 401) loggedIn.set(false)
     if (response.status < 200 || response.status >= 300) {
         const errorText = await response.text()
         return `Server responded with ${response.status} (${response.statusText}): ${errorText}`
@@ -42,6 +31,67 @@ export async function fetchApi<T>(
     return hasBody ? await response.json() : {}
 }
 
+/** The current version of an entity, sent back when replacing it failed because it was changed */
+export class Conflict<T> {
+    constructor(public current: T) {}
+}
+
+/**
+ * Replaces an entity by sending it to `url` with a `PATCH` request. The request is only accepted
+ * if the entity was not changed since its `revision` was loaded. Returns the new revision, the
+ * current version of the entity if it was changed in the meantime, or an error message.
+ */
+export async function patchApi<T extends { revision: number }>(
+    url: string,
+    entity: T,
+): Promise<string | number | Conflict<T>> {
+    const response = await fetch(url, {
+        method: 'PATCH',
+        body: JSON.stringify(entity),
+        headers: {
+            'Content-Type': 'application/json',
+            'If-Match': `"${entity.revision}"`,
+        },
+    })
+    if (response.status === 401) loggedIn.set(false)
+    if (response.status === 412) {
+        return new Conflict<T>(await response.json())
+    }
+    if (response.status < 200 || response.status >= 300) {
+        const errorText = await response.text()
+        return `Server responded with ${response.status} (${response.statusText}): ${errorText}`
+    }
+    const etag = response.headers.get('ETag')
+    return etag !== null ? Number(JSON.parse(etag)) : entity.revision + 1
+}
+
+/**
+ * Creates `tag` if `create` is set and replaces the existing one otherwise. Returns the saved tag
+ * or an error message. If the tag was changed in the meantime, the `tags` store is updated with
+ * its current version instead.
+ */
+export async function saveTag(tag: Tag, create: boolean): Promise<string | Tag> {
+    if (create) {
+        return await fetchApi<Tag>(
+            fetch('/api/tag', {
+                method: 'POST',
+                body: JSON.stringify(tag),
+                headers: {
+                    'Content-Type': 'application/json',
+                },
+            }),
+        )
+    }
+    const res = await patchApi('/api/tag', tag)
+    if (res instanceof Conflict) {
+        const current = res.current
+        tags.update(all => ({ ...all, [current.id]: current }))
+        return 'The tag was changed elsewhere and has been reloaded, please edit it again'
+    }
+    if (typeof res === 'string') return res
+    return { ...tag, revision: res }
+}
+
 export function averageOf(list: number[]): number {
     if (list.length === 0) return 0
     return list.reduce((acc, e) => acc + e, 0) / list.length
@@ -52,6 +102,15 @@ export interface Tag {
     name: string
     color: [number, number, number]
     icon: string | null
+    revision: number
+}
+
+export interface Platform {
+    id: number
+    name: string
+    color: [number, number, number]
+    icon: string | null
+    tmdb_providers: number[]
 }
 
 export interface Movie {
@@ -61,11 +120,12 @@ export interface Movie {
     description: string
     ratings: Rating[]
     tags: number[]
-    platforms: string[]
+    platforms: number[]
     poster: string | null
     release_date: string
     runtime: Duration
     score: number
+    revision: number
 }
 
 export interface MovieStub {
@@ -80,7 +140,7 @@ export interface Rating {
     date: string
     rating: number
     speed: number
-    platform: string | null
+    platform: number | null
     tags: number[]
 }
 
@@ -99,6 +159,7 @@ export interface Book {
     tags: number[],
     release_date: string,
     score: number | null,
+    revision: number,
 }
 
 export interface Reading {
@@ -131,5 +192,7 @@ export interface BookEdition {
     title: string
     description: string | null
     authors: Key[]
+    works: Key[]
     publish_date: string
+    number_of_pages: number | null
 }